@@ -0,0 +1,342 @@
+use ropey::Rope;
+use std::fmt;
+
+// バッファに対する1回の編集 (先頭からの文字オフセット, 文字列)
+#[derive(Clone)]
+pub enum Edit {
+    Insert(usize, String),
+    Delete(usize, String),
+}
+
+impl Edit {
+    fn offset(&self) -> usize {
+        match self {
+            Edit::Insert(at, _) | Edit::Delete(at, _) => *at,
+        }
+    }
+}
+
+// テキスト全体を保持するバッファ
+// 位置はすべて (行, 列) で、列は行頭からの文字数
+#[derive(Clone, Default)]
+pub struct Buffer {
+    text: Rope,
+    edits: Vec<Edit>,       // undo に記録されていない編集
+    version: u64,           // 内容が変わるたびに増える
+    changed: Option<usize>, // 最後に take_changed してから変わった最初の行
+}
+
+impl From<&str> for Buffer {
+    fn from(text: &str) -> Self {
+        Self {
+            text: Rope::from_str(text),
+            edits: Vec::new(),
+            version: 0,
+            changed: None,
+        }
+    }
+}
+
+impl fmt::Display for Buffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for chunk in self.text.chunks() {
+            f.write_str(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+impl Buffer {
+    // 行数 (空のバッファでも1行ある)
+    pub fn len_lines(&self) -> usize {
+        self.text.len_lines()
+    }
+
+    // 改行を含まない行の内容
+    pub fn line(&self, line: usize) -> String {
+        let mut s = self.text.line(line).to_string();
+        if s.ends_with('\n') {
+            s.pop();
+        }
+        s
+    }
+
+    // 改行を含まない行の文字数
+    pub fn line_len(&self, line: usize) -> usize {
+        let slice = self.text.line(line);
+        let len = slice.len_chars();
+        if len > 0 && slice.char(len - 1) == '\n' {
+            len - 1
+        } else {
+            len
+        }
+    }
+
+    pub fn lines(&self) -> impl Iterator<Item = String> + '_ {
+        (0..self.len_lines()).map(|i| self.line(i))
+    }
+
+    // (行, 列) を先頭からの文字オフセットに変換する
+    pub fn to_offset(&self, pos: (usize, usize)) -> usize {
+        let line = pos.0.min(self.len_lines() - 1);
+        self.text.line_to_char(line) + pos.1.min(self.line_len(line))
+    }
+
+    // 先頭からの文字オフセットを (行, 列) に変換する
+    pub fn to_pos(&self, offset: usize) -> (usize, usize) {
+        let offset = offset.min(self.text.len_chars());
+        let line = self.text.char_to_line(offset);
+        (line, offset - self.text.line_to_char(line))
+    }
+
+    // offset から後ろの文字を (オフセット, 文字) で返す (改行も1文字)
+    pub fn chars_after(&self, offset: usize) -> impl Iterator<Item = (usize, char)> + '_ {
+        let offset = offset.min(self.text.len_chars());
+        (offset..).zip(self.text.chars_at(offset))
+    }
+
+    // offset より前の文字を近い順に (オフセット, 文字) で返す
+    pub fn chars_before(&self, offset: usize) -> impl Iterator<Item = (usize, char)> + '_ {
+        let offset = offset.min(self.text.len_chars());
+        let mut chars = self.text.chars_at(offset);
+        (0..offset)
+            .rev()
+            .map_while(move |i| Some((i, chars.prev()?)))
+    }
+
+    pub fn insert(&mut self, pos: (usize, usize), s: &str) {
+        if s.is_empty() {
+            return;
+        }
+        let offset = self.to_offset(pos);
+        self.text.insert(offset, s);
+        self.edits.push(Edit::Insert(offset, s.to_string()));
+        self.note_change(offset);
+    }
+
+    // start から end の直前までを削除して、削除した文字列を返す
+    pub fn delete(&mut self, start: (usize, usize), end: (usize, usize)) -> String {
+        let (from, to) = (self.to_offset(start), self.to_offset(end));
+        let removed = self.text.slice(from..to).to_string();
+        if !removed.is_empty() {
+            self.text.remove(from..to);
+            self.edits.push(Edit::Delete(from, removed.clone()));
+            self.note_change(from);
+        }
+        removed
+    }
+
+    // まだ undo に記録されていない編集があるか
+    pub fn has_edits(&self) -> bool {
+        !self.edits.is_empty()
+    }
+
+    pub fn take_edits(&mut self) -> Vec<Edit> {
+        std::mem::take(&mut self.edits)
+    }
+
+    // 記録済みの編集をやり直す (undo の記録には残さない)
+    pub fn apply(&mut self, edit: &Edit) {
+        match edit {
+            Edit::Insert(at, s) => self.text.insert(*at, s),
+            Edit::Delete(at, s) => self.text.remove(*at..*at + s.chars().count()),
+        }
+        self.note_change(edit.offset());
+    }
+
+    // 記録済みの編集を取り消す (undo の記録には残さない)
+    pub fn revert(&mut self, edit: &Edit) {
+        match edit {
+            Edit::Insert(at, s) => self.text.remove(*at..*at + s.chars().count()),
+            Edit::Delete(at, s) => self.text.insert(*at, s),
+        }
+        self.note_change(edit.offset());
+    }
+
+    // 記録済みの編集をこの内容に対してやり直せるか (削除する文字列が今の内容と一致するか)
+    pub fn can_apply(&self, edit: &Edit) -> bool {
+        match edit {
+            Edit::Insert(at, _) => *at <= self.text.len_chars(),
+            Edit::Delete(at, s) => self.has_text_at(*at, s),
+        }
+    }
+
+    // 記録済みの編集をこの内容に対して取り消せるか (挿入した文字列が今の内容と一致するか)
+    pub fn can_revert(&self, edit: &Edit) -> bool {
+        match edit {
+            Edit::Insert(at, s) => self.has_text_at(*at, s),
+            Edit::Delete(at, _) => *at <= self.text.len_chars(),
+        }
+    }
+
+    fn has_text_at(&self, at: usize, s: &str) -> bool {
+        let end = at + s.chars().count();
+        end <= self.text.len_chars() && self.text.slice(at..end) == s
+    }
+
+    // offset の位置が変わったことを記録する
+    fn note_change(&mut self, offset: usize) {
+        self.version += 1;
+        let line = self.text.char_to_line(offset);
+        self.changed = Some(self.changed.map_or(line, |changed| changed.min(line)));
+    }
+
+    // 前回から変わった最初の行 (シンタックスハイライトを調べ直すのに使う)
+    pub fn take_changed(&mut self) -> Option<usize> {
+        self.changed.take()
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn slice(&self, start: (usize, usize), end: (usize, usize)) -> String {
+        self.text
+            .slice(self.to_offset(start)..self.to_offset(end))
+            .to_string()
+    }
+
+    // line の位置に新しい行を挿入する (line == len_lines() なら末尾に追加)
+    pub fn insert_line(&mut self, line: usize, s: &str) {
+        if line >= self.len_lines() {
+            let last = self.len_lines() - 1;
+            let end = (last, self.line_len(last));
+            self.insert(end, &format!("\n{}", s));
+        } else {
+            self.insert((line, 0), &format!("{}\n", s));
+        }
+    }
+
+    // line から count 行を削除して、改行で連結した内容を返す
+    // 全行を削除した場合は空の1行が残る
+    pub fn remove_lines(&mut self, line: usize, count: usize) -> String {
+        let last = (line + count).min(self.len_lines()) - 1;
+        let removed = self.slice((line, 0), (last, self.line_len(last)));
+        if last + 1 < self.len_lines() {
+            self.delete((line, 0), (last + 1, 0));
+        } else if line > 0 {
+            self.delete(
+                (line - 1, self.line_len(line - 1)),
+                (last, self.line_len(last)),
+            );
+        } else {
+            self.delete((0, 0), (last, self.line_len(last)));
+        }
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // バッファ全体を改行でつないだ内容
+    fn text(buffer: &Buffer) -> String {
+        buffer.lines().collect::<Vec<_>>().join("\n")
+    }
+
+    #[test]
+    fn positions_are_clamped_to_lines() {
+        let buffer = Buffer::from("ab\ncde\n");
+        assert_eq!(buffer.len_lines(), 3);
+        assert_eq!(buffer.line(1), "cde");
+        assert_eq!(buffer.line_len(0), 2);
+        assert_eq!(buffer.line_len(2), 0);
+        assert_eq!(buffer.to_offset((1, 1)), 4);
+        // 行末より右の列は行末、最後の行より下は最後の行にする
+        assert_eq!(buffer.to_offset((0, 10)), 2);
+        assert_eq!(buffer.to_offset((9, 0)), 7);
+    }
+
+    #[test]
+    fn insert_delete_and_slice() {
+        let mut buffer = Buffer::from("hello\nworld");
+        buffer.insert((0, 5), ",\nnew");
+        assert_eq!(text(&buffer), "hello,\nnew\nworld");
+        assert_eq!(buffer.slice((0, 3), (1, 2)), "lo,\nne");
+        assert_eq!(buffer.delete((0, 5), (1, 3)), ",\nnew");
+        assert_eq!(text(&buffer), "hello\nworld");
+        // 複数のバイトからなる文字も1文字に数える
+        let mut buffer = Buffer::from("あいう");
+        buffer.insert((0, 1), "x");
+        assert_eq!(buffer.delete((0, 2), (0, 3)), "い");
+        assert_eq!(text(&buffer), "あxう");
+    }
+
+    #[test]
+    fn insert_and_remove_lines() {
+        let mut buffer = Buffer::from("a\nb\nc");
+        buffer.insert_line(1, "x");
+        buffer.insert_line(4, "end");
+        assert_eq!(text(&buffer), "a\nx\nb\nc\nend");
+        assert_eq!(buffer.remove_lines(1, 2), "x\nb");
+        assert_eq!(text(&buffer), "a\nc\nend");
+        // 最後の行を消すと、前の行の改行も消える
+        assert_eq!(buffer.remove_lines(2, 5), "end");
+        assert_eq!(text(&buffer), "a\nc");
+        // 全部消すと空の1行が残る
+        assert_eq!(buffer.remove_lines(0, 2), "a\nc");
+        assert_eq!(buffer.len_lines(), 1);
+        assert_eq!(text(&buffer), "");
+    }
+
+    #[test]
+    fn edits_are_recorded_and_replayed() {
+        let mut buffer = Buffer::from("abc");
+        buffer.insert((0, 3), "de");
+        buffer.delete((0, 0), (0, 1));
+        // 何も変えない操作は記録しない
+        buffer.insert((0, 0), "");
+        buffer.delete((0, 1), (0, 1));
+        assert!(buffer.has_edits());
+        let edits = buffer.take_edits();
+        assert_eq!(edits.len(), 2);
+        assert!(!buffer.has_edits());
+        assert_eq!(text(&buffer), "bcde");
+        for edit in edits.iter().rev() {
+            buffer.revert(edit);
+        }
+        assert_eq!(text(&buffer), "abc");
+        for edit in &edits {
+            buffer.apply(edit);
+        }
+        assert_eq!(text(&buffer), "bcde");
+        // やり直しと取り消しは記録しない
+        assert!(!buffer.has_edits());
+    }
+
+    #[test]
+    fn offsets_and_positions_convert_both_ways() {
+        let buffer = Buffer::from("ab\nあい\n");
+        for (offset, pos) in [
+            (0, (0, 0)),
+            (2, (0, 2)),
+            (3, (1, 0)),
+            (5, (1, 2)),
+            (6, (2, 0)),
+        ] {
+            assert_eq!(buffer.to_pos(offset), pos);
+            assert_eq!(buffer.to_offset(pos), offset);
+        }
+        // 末尾より後ろは末尾
+        assert_eq!(buffer.to_pos(100), (2, 0));
+    }
+
+    #[test]
+    fn take_changed_returns_the_first_changed_line() {
+        let mut buffer = Buffer::from("a\nb\nc\nd");
+        assert_eq!(buffer.take_changed(), None);
+        let version = buffer.version();
+        buffer.insert((2, 0), "x");
+        buffer.delete((1, 0), (1, 1));
+        buffer.insert((3, 1), "y");
+        // 一番上の行を返して、記録を消す
+        assert_eq!(buffer.take_changed(), Some(1));
+        assert_eq!(buffer.take_changed(), None);
+        assert!(buffer.version() > version);
+        // 元に戻したときも記録する
+        let edits = buffer.take_edits();
+        buffer.revert(&edits[1]);
+        assert_eq!(buffer.take_changed(), Some(1));
+    }
+}
@@ -0,0 +1,240 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use std::fs;
+use std::io;
+use std::mem;
+
+// 覚えておく履歴の数 (古いものから消す)
+const HISTORY_SIZE: usize = 100;
+
+pub enum CommandLineEvent {
+    Cancel,
+    Execute(String),
+}
+
+// 最下行で : のあと (検索なら / か ? のあと) に入力するコマンドライン
+pub struct CommandLine {
+    text: Vec<char>,
+    pub cursor: usize,
+    pub prompt: char,
+    history: Vec<String>,
+    search_history: Vec<String>, // / と ? の履歴 (: の履歴とは別に覚える)
+    history_index: usize,        // 履歴を遡っているときの位置 (history.len() なら入力中の行)
+    typed: String,               // 履歴を遡る前に入力していた内容
+}
+
+impl CommandLine {
+    pub fn new() -> Self {
+        Self {
+            text: Vec::new(),
+            cursor: 0,
+            prompt: ':',
+            history: Vec::new(),
+            search_history: Vec::new(),
+            history_index: 0,
+            typed: String::new(),
+        }
+    }
+
+    pub fn start(&mut self, prompt: char) {
+        self.prompt = prompt;
+        self.text.clear();
+        self.cursor = 0;
+        self.history_index = self.history().len();
+    }
+
+    // text を入力した状態で始める
+    pub fn start_with(&mut self, prompt: char, text: &str) {
+        self.start(prompt);
+        self.set_text(text);
+    }
+
+    // 履歴に加える (* / # の検索のように入力しなかったものも加える)
+    pub fn add_history(&mut self, prompt: char, line: &str) {
+        let current = mem::replace(&mut self.prompt, prompt);
+        let history = self.history_mut();
+        history.retain(|h| h != line);
+        history.push(line.to_string());
+        if history.len() > HISTORY_SIZE {
+            history.drain(..history.len() - HISTORY_SIZE);
+        }
+        self.prompt = current;
+    }
+
+    // 検索の履歴をファイルから読む (1行に1つ、古い順)
+    // ファイルが無ければ何もしない
+    pub fn load_search_history(&mut self, path: &str) -> io::Result<()> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            self.add_history('/', line);
+        }
+        Ok(())
+    }
+
+    // 検索の履歴をファイルに書く (次に起動したときに読む)
+    pub fn save_search_history(&self, path: &str) -> io::Result<()> {
+        let mut text = String::new();
+        for line in &self.search_history {
+            text += line;
+            text += "\n";
+        }
+        fs::write(path, text)
+    }
+
+    pub fn is_search(&self) -> bool {
+        self.prompt != ':'
+    }
+
+    fn history(&self) -> &Vec<String> {
+        if self.is_search() {
+            &self.search_history
+        } else {
+            &self.history
+        }
+    }
+
+    fn history_mut(&mut self) -> &mut Vec<String> {
+        if self.is_search() {
+            &mut self.search_history
+        } else {
+            &mut self.history
+        }
+    }
+
+    pub fn text(&self) -> String {
+        self.text.iter().collect()
+    }
+
+    fn set_text(&mut self, text: &str) {
+        self.text = text.chars().collect();
+        self.cursor = self.text.len();
+    }
+
+    // 入力中の内容で始まる履歴を前 (step = -1) または後ろ (step = 1) に探す
+    fn move_history(&mut self, step: isize) {
+        if self.history_index == self.history().len() {
+            self.typed = self.text();
+        }
+        let mut i = self.history_index as isize;
+        loop {
+            i += step;
+            if i < 0 {
+                return;
+            }
+            if i as usize >= self.history().len() {
+                self.history_index = self.history().len();
+                let typed = self.typed.clone();
+                self.set_text(&typed);
+                return;
+            }
+            if self.history()[i as usize].starts_with(&self.typed) {
+                self.history_index = i as usize;
+                let line = self.history()[i as usize].clone();
+                self.set_text(&line);
+                return;
+            }
+        }
+    }
+
+    pub fn handle_key(&mut self, key: KeyEvent) -> Option<CommandLineEvent> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Esc => return Some(CommandLineEvent::Cancel),
+            KeyCode::Char('c') if ctrl => return Some(CommandLineEvent::Cancel),
+            KeyCode::Enter => {
+                let line = self.text();
+                if !line.trim().is_empty() {
+                    self.add_history(self.prompt, &line);
+                }
+                return Some(CommandLineEvent::Execute(line));
+            }
+            KeyCode::Backspace => {
+                if self.text.is_empty() {
+                    return Some(CommandLineEvent::Cancel);
+                }
+                if self.cursor > 0 {
+                    self.cursor -= 1;
+                    self.text.remove(self.cursor);
+                }
+            }
+            KeyCode::Delete if self.cursor < self.text.len() => {
+                self.text.remove(self.cursor);
+            }
+            KeyCode::Left if self.cursor > 0 => self.cursor -= 1,
+            KeyCode::Right if self.cursor < self.text.len() => self.cursor += 1,
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.text.len(),
+            KeyCode::Up => self.move_history(-1),
+            KeyCode::Down => self.move_history(1),
+            // カーソルより前を消す
+            KeyCode::Char('u') if ctrl => {
+                self.text.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            KeyCode::Char(c) => {
+                self.text.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn search(cmdline: &mut CommandLine, pattern: &str) {
+        cmdline.start('/');
+        for c in pattern.chars() {
+            cmdline.handle_key(KeyEvent::from(KeyCode::Char(c)));
+        }
+        cmdline.handle_key(KeyEvent::from(KeyCode::Enter));
+    }
+
+    #[test]
+    fn search_history_round_trips_through_file() {
+        let path = env::temp_dir().join(format!("kbeditor-history-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut cmdline = CommandLine::new();
+        search(&mut cmdline, "foo");
+        search(&mut cmdline, "b.r");
+        search(&mut cmdline, "foo");
+        // : の履歴は保存しない
+        cmdline.add_history(':', "set ts=4");
+        cmdline.save_search_history(path).unwrap();
+        assert_eq!(fs::read_to_string(path).unwrap(), "b.r\nfoo\n");
+        let mut loaded = CommandLine::new();
+        loaded.load_search_history(path).unwrap();
+        assert_eq!(loaded.search_history, ["b.r", "foo"]);
+        assert!(loaded.history.is_empty());
+        // 読み込んだ履歴を ↑ で呼び出せる
+        loaded.start('?');
+        loaded.handle_key(KeyEvent::from(KeyCode::Up));
+        assert_eq!(loaded.text(), "foo");
+        fs::remove_file(path).unwrap();
+        // ファイルが無ければ空のまま
+        let mut missing = CommandLine::new();
+        missing.load_search_history(path).unwrap();
+        assert!(missing.search_history.is_empty());
+    }
+
+    #[test]
+    fn history_keeps_only_the_newest_entries() {
+        let mut cmdline = CommandLine::new();
+        for i in 0..HISTORY_SIZE + 10 {
+            cmdline.add_history('/', &i.to_string());
+        }
+        assert_eq!(cmdline.search_history.len(), HISTORY_SIZE);
+        assert_eq!(cmdline.search_history[0], "10");
+        assert_eq!(
+            cmdline.search_history.last().unwrap(),
+            &(HISTORY_SIZE + 9).to_string()
+        );
+    }
+}
@@ -0,0 +1,422 @@
+// カラースキーム (強調表示の名前ごとの色と属性) の読み込みと、端末で使える色への変換
+use crate::syntax::Group;
+use crossterm::style::Color;
+use std::env;
+use std::fs;
+
+// 強調表示の名前 (カラースキームのファイルでは HIGHLIGHTS の名前で指定する)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Highlight {
+    Normal,
+    LineNr,
+    Comment,
+    String,
+    Number,
+    Keyword,
+    Type,
+    PreProc,
+    Function,
+    Special,
+    Title,
+    Underlined,
+    Visual,       // ビジュアルモードの選択範囲
+    Search,       // 検索パターンに一致した部分
+    StatusLine,   // 今のウィンドウのステータス行
+    StatusLineNC, // ほかのウィンドウのステータス行
+    VertSplit,    // 左右に並べたウィンドウの区切りの縦線
+    TabLine,      // 今のタブページ以外のタブ
+    TabLineSel,   // 今のタブページのタブ
+    TabLineFill,  // タブページの一覧の残りの部分
+}
+
+const HIGHLIGHTS: [(&str, Highlight); 20] = [
+    ("Normal", Highlight::Normal),
+    ("LineNr", Highlight::LineNr),
+    ("Comment", Highlight::Comment),
+    ("String", Highlight::String),
+    ("Number", Highlight::Number),
+    ("Keyword", Highlight::Keyword),
+    ("Type", Highlight::Type),
+    ("PreProc", Highlight::PreProc),
+    ("Function", Highlight::Function),
+    ("Special", Highlight::Special),
+    ("Title", Highlight::Title),
+    ("Underlined", Highlight::Underlined),
+    ("Visual", Highlight::Visual),
+    ("Search", Highlight::Search),
+    ("StatusLine", Highlight::StatusLine),
+    ("StatusLineNC", Highlight::StatusLineNC),
+    ("VertSplit", Highlight::VertSplit),
+    ("TabLine", Highlight::TabLine),
+    ("TabLineSel", Highlight::TabLineSel),
+    ("TabLineFill", Highlight::TabLineFill),
+];
+
+impl From<Group> for Highlight {
+    fn from(group: Group) -> Self {
+        match group {
+            Group::Normal => Highlight::Normal,
+            Group::Comment => Highlight::Comment,
+            Group::String => Highlight::String,
+            Group::Number => Highlight::Number,
+            Group::Keyword => Highlight::Keyword,
+            Group::Type => Highlight::Type,
+            Group::PreProc => Highlight::PreProc,
+            Group::Function => Highlight::Function,
+            Group::Special => Highlight::Special,
+            Group::Title => Highlight::Title,
+            Group::Underlined => Highlight::Underlined,
+        }
+    }
+}
+
+// 色と属性 (色が None なら端末の標準の色)
+#[derive(Clone, Copy, PartialEq, Default, Debug)]
+pub struct Style {
+    pub fg: Option<Color>,
+    pub bg: Option<Color>,
+    pub bold: bool,
+    pub underline: bool,
+    pub reverse: bool,
+}
+
+impl Style {
+    const fn fg(color: Color) -> Self {
+        Self {
+            fg: Some(color),
+            bg: None,
+            bold: false,
+            underline: false,
+            reverse: false,
+        }
+    }
+}
+
+// 端末で使える色の数
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Depth {
+    TrueColor, // 24bit の RGB
+    Ansi256,
+    Ansi16,
+}
+
+// 環境変数から端末で使える色の数を調べる
+pub fn detect() -> Depth {
+    let colorterm = env::var("COLORTERM").unwrap_or_default();
+    if colorterm == "truecolor" || colorterm == "24bit" {
+        Depth::TrueColor
+    } else if env::var("TERM").unwrap_or_default().contains("256color") {
+        Depth::Ansi256
+    } else {
+        Depth::Ansi16
+    }
+}
+
+// 16色の名前と、xterm での RGB の値 (AnsiValue の 0 〜 15 と同じ順番)
+const ANSI16: [(&str, Color, (u8, u8, u8)); 16] = [
+    ("black", Color::Black, (0, 0, 0)),
+    ("darkred", Color::DarkRed, (128, 0, 0)),
+    ("darkgreen", Color::DarkGreen, (0, 128, 0)),
+    ("darkyellow", Color::DarkYellow, (128, 128, 0)),
+    ("darkblue", Color::DarkBlue, (0, 0, 128)),
+    ("darkmagenta", Color::DarkMagenta, (128, 0, 128)),
+    ("darkcyan", Color::DarkCyan, (0, 128, 128)),
+    ("grey", Color::Grey, (192, 192, 192)),
+    ("darkgrey", Color::DarkGrey, (128, 128, 128)),
+    ("red", Color::Red, (255, 0, 0)),
+    ("green", Color::Green, (0, 255, 0)),
+    ("yellow", Color::Yellow, (255, 255, 0)),
+    ("blue", Color::Blue, (0, 0, 255)),
+    ("magenta", Color::Magenta, (255, 0, 255)),
+    ("cyan", Color::Cyan, (0, 255, 255)),
+    ("white", Color::White, (255, 255, 255)),
+];
+
+// 256色の 16 〜 231 番の色の立方体の、各成分の値
+const CUBE: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+    let d = |x: u8, y: u8| (i32::from(x) - i32::from(y)).pow(2) as u32;
+    d(a.0, b.0) + d(a.1, b.1) + d(a.2, b.2)
+}
+
+// 256色の番号の RGB の値
+fn ansi256_rgb(n: u8) -> (u8, u8, u8) {
+    match n {
+        0..=15 => ANSI16[n as usize].2,
+        16..=231 => {
+            let i = n - 16;
+            (
+                CUBE[(i / 36) as usize],
+                CUBE[(i / 6 % 6) as usize],
+                CUBE[(i % 6) as usize],
+            )
+        }
+        _ => {
+            let level = 8 + (n - 232) * 10;
+            (level, level, level)
+        }
+    }
+}
+
+// RGB に一番近い 256色の番号 (色の立方体と灰色の段階から選ぶ)
+fn nearest256(rgb: (u8, u8, u8)) -> u8 {
+    let level = |x: u8| {
+        (0..CUBE.len())
+            .min_by_key(|&i| (i32::from(CUBE[i]) - i32::from(x)).abs())
+            .unwrap() as u8
+    };
+    let cube = 16 + level(rgb.0) * 36 + level(rgb.1) * 6 + level(rgb.2);
+    let average = (u32::from(rgb.0) + u32::from(rgb.1) + u32::from(rgb.2)) / 3;
+    let grey = 232 + (average.saturating_sub(3) / 10).min(23) as u8;
+    if distance(rgb, ansi256_rgb(grey)) < distance(rgb, ansi256_rgb(cube)) {
+        grey
+    } else {
+        cube
+    }
+}
+
+// RGB に一番近い 16色
+fn nearest16(rgb: (u8, u8, u8)) -> Color {
+    ANSI16
+        .iter()
+        .min_by_key(|(_, _, value)| distance(rgb, *value))
+        .unwrap()
+        .1
+}
+
+// 端末で使える色にする (使えなければ一番近い色にする)
+fn fit(color: Color, depth: Depth) -> Color {
+    match (color, depth) {
+        (Color::Rgb { r, g, b }, Depth::Ansi256) => Color::AnsiValue(nearest256((r, g, b))),
+        (Color::Rgb { r, g, b }, Depth::Ansi16) => nearest16((r, g, b)),
+        (Color::AnsiValue(n), Depth::Ansi16) => nearest16(ansi256_rgb(n)),
+        _ => color,
+    }
+}
+
+// カラースキームに書く色 (#rrggbb, 0 〜 255 の番号, 16色の名前, 標準の色なら NONE)
+fn parse_color(value: &str) -> Result<Option<Color>, String> {
+    let invalid = || format!("E421: Color name or number not recognized: {}", value);
+    let lower = value.to_ascii_lowercase().replace("gray", "grey");
+    if lower == "none" {
+        return Ok(None);
+    }
+    if let Some(hex) = lower.strip_prefix('#') {
+        let channel = |i: usize| {
+            hex.get(i..i + 2)
+                .and_then(|s| u8::from_str_radix(s, 16).ok())
+        };
+        return match (hex.len(), channel(0), channel(2), channel(4)) {
+            (6, Some(r), Some(g), Some(b)) => Ok(Some(Color::Rgb { r, g, b })),
+            _ => Err(invalid()),
+        };
+    }
+    if let Ok(n) = lower.parse::<u8>() {
+        return Ok(Some(Color::AnsiValue(n)));
+    }
+    ANSI16
+        .iter()
+        .find(|(name, _, _)| *name == lower)
+        .map(|(_, color, _)| Some(*color))
+        .ok_or_else(invalid)
+}
+
+pub struct Colorscheme {
+    depth: Depth,
+    styles: Vec<Style>, // HIGHLIGHTS と同じ順番
+}
+
+impl Colorscheme {
+    // 標準のカラースキーム (16色だけを使う)
+    pub fn new(depth: Depth) -> Self {
+        let styles = HIGHLIGHTS
+            .iter()
+            .map(|(_, highlight)| match highlight {
+                Highlight::Normal => Style::fg(Color::Grey),
+                Highlight::LineNr | Highlight::Special => Style::fg(Color::DarkYellow),
+                Highlight::Comment => Style::fg(Color::DarkCyan),
+                Highlight::String | Highlight::Number | Highlight::Title => {
+                    Style::fg(Color::Magenta)
+                }
+                Highlight::Keyword => Style::fg(Color::Yellow),
+                Highlight::Type => Style::fg(Color::Green),
+                Highlight::PreProc => Style::fg(Color::Blue),
+                Highlight::Function => Style::fg(Color::Cyan),
+                Highlight::Underlined => Style {
+                    underline: true,
+                    ..Style::fg(Color::Blue)
+                },
+                Highlight::Visual | Highlight::TabLine | Highlight::TabLineFill => Style {
+                    reverse: true,
+                    ..Style::fg(Color::Grey)
+                },
+                Highlight::Search => Style {
+                    bg: Some(Color::DarkYellow),
+                    ..Style::fg(Color::Black)
+                },
+                Highlight::StatusLine => Style {
+                    bold: true,
+                    reverse: true,
+                    ..Style::fg(Color::Grey)
+                },
+                Highlight::StatusLineNC => Style {
+                    reverse: true,
+                    ..Style::fg(Color::Grey)
+                },
+                Highlight::VertSplit => Style::fg(Color::DarkGrey),
+                Highlight::TabLineSel => Style {
+                    bold: true,
+                    ..Style::fg(Color::Grey)
+                },
+            })
+            .collect();
+        Self { depth, styles }
+    }
+
+    // 強調表示の色と属性 (色は端末で使えるものにしてある)
+    pub fn get(&self, highlight: Highlight) -> Style {
+        self.styles[highlight as usize]
+    }
+
+    // カラースキームのファイルを読み込む
+    pub fn load_file(&mut self, path: &str) -> Result<(), String> {
+        let text =
+            fs::read_to_string(path).map_err(|_| format!("E484: Can't open file {}", path))?;
+        self.load(&text)
+    }
+
+    // vim と同じ `highlight {名前} guifg={色} guibg={色} gui={属性}` の行を順に実行する
+    // (" で始まる行はコメント。間違った行があっても残りの行は読み込み、最初のエラーを返す)
+    pub fn load(&mut self, text: &str) -> Result<(), String> {
+        let mut error = None;
+        for (i, line) in text.lines().enumerate() {
+            if let Err(msg) = self.highlight(line.trim()) {
+                error.get_or_insert(format!("{} (line {})", msg, i + 1));
+            }
+        }
+        error.map_or(Ok(()), Err)
+    }
+
+    fn highlight(&mut self, line: &str) -> Result<(), String> {
+        if line.is_empty() || line.starts_with('"') {
+            return Ok(());
+        }
+        let mut words = line.split_whitespace();
+        if !matches!(words.next(), Some("hi" | "highlight")) {
+            return Err(format!("E492: Not an editor command: {}", line));
+        }
+        let Some(name) = words.next() else {
+            return Ok(());
+        };
+        let Some(&(_, highlight)) = HIGHLIGHTS
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        else {
+            return Err(format!("E411: Highlight group not found: {}", name));
+        };
+        // 指定しなかった色や属性はそのまま残す
+        let mut style = self.get(highlight);
+        for arg in words {
+            let Some((key, value)) = arg.split_once('=') else {
+                return Err(format!("E416: Missing equal sign: {}", arg));
+            };
+            match key.to_ascii_lowercase().as_str() {
+                "guifg" | "ctermfg" => style.fg = parse_color(value)?,
+                "guibg" | "ctermbg" => style.bg = parse_color(value)?,
+                "gui" | "cterm" => {
+                    style.bold = false;
+                    style.underline = false;
+                    style.reverse = false;
+                    for attribute in value.split(',') {
+                        match attribute.to_ascii_lowercase().as_str() {
+                            "bold" => style.bold = true,
+                            "underline" => style.underline = true,
+                            "reverse" | "inverse" => style.reverse = true,
+                            "none" => {}
+                            _ => return Err(format!("E418: Illegal value: {}", attribute)),
+                        }
+                    }
+                }
+                _ => return Err(format!("E423: Illegal argument: {}", arg)),
+            }
+        }
+        style.fg = style.fg.map(|color| fit(color, self.depth));
+        style.bg = style.bg.map(|color| fit(color, self.depth));
+        self.styles[highlight as usize] = style;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THEME: &str =
+        "\" コメント\nhi Comment guifg=#ff0000 gui=bold\nhighlight normal guibg=NONE guifg=236\n";
+
+    #[test]
+    fn colorscheme_loads_and_fits_colors() {
+        let mut colors = Colorscheme::new(Depth::TrueColor);
+        colors.load(THEME).unwrap();
+        let comment = colors.get(Highlight::Comment);
+        assert_eq!(comment.fg, Some(Color::Rgb { r: 255, g: 0, b: 0 }));
+        assert!(comment.bold);
+        assert_eq!(
+            colors.get(Highlight::Normal).fg,
+            Some(Color::AnsiValue(236))
+        );
+        // 指定していない種類は標準のまま
+        assert!(colors.get(Highlight::StatusLine).reverse);
+
+        // 使えない色は一番近い色にする
+        let mut colors = Colorscheme::new(Depth::Ansi256);
+        colors.load(THEME).unwrap();
+        assert_eq!(
+            colors.get(Highlight::Comment).fg,
+            Some(Color::AnsiValue(196))
+        );
+        let mut colors = Colorscheme::new(Depth::Ansi16);
+        colors.load(THEME).unwrap();
+        assert_eq!(colors.get(Highlight::Comment).fg, Some(Color::Red));
+        assert_eq!(colors.get(Highlight::Normal).fg, Some(Color::Black));
+    }
+
+    #[test]
+    fn unknown_groups_are_errors() {
+        let mut colors = Colorscheme::new(Depth::TrueColor);
+        assert_eq!(
+            colors.load("hi Foo guifg=red"),
+            Err("E411: Highlight group not found: Foo (line 1)".to_string())
+        );
+    }
+
+    #[test]
+    fn arguments_need_equal_signs() {
+        let mut colors = Colorscheme::new(Depth::TrueColor);
+        let before = colors.get(Highlight::Comment).fg;
+        assert_eq!(
+            colors.load("\" x\nhi Comment guifg=red bold"),
+            Err("E416: Missing equal sign: bold (line 2)".to_string())
+        );
+        // 間違った行は途中まででも使わない
+        assert_eq!(colors.get(Highlight::Comment).fg, before);
+    }
+
+    #[test]
+    fn bad_lines_do_not_stop_loading() {
+        let mut colors = Colorscheme::new(Depth::TrueColor);
+        // 最初のエラーを返して、残りの行は読み込む
+        assert_eq!(
+            colors
+                .load("hi Foo guifg=red\nhi Type guifg=blue\nhi Title guifg\nhi Comment gui=bold"),
+            Err("E411: Highlight group not found: Foo (line 1)".to_string())
+        );
+        assert_eq!(colors.get(Highlight::Type).fg, Some(Color::Blue));
+        assert!(colors.get(Highlight::Comment).bold);
+        assert_eq!(
+            colors.load("hi Title guifg=nocolor"),
+            Err("E421: Color name or number not recognized: nocolor (line 1)".to_string())
+        );
+    }
+}
@@ -0,0 +1,3073 @@
+// エディタの状態とキー入力の処理 (端末への描画は render.rs)
+use crate::buffer::Buffer;
+use crate::cmdline::{CommandLine, CommandLineEvent};
+use crate::colors::{Colorscheme, Depth};
+use crate::ex;
+use crate::fileio::{is_writable, read_file, write_file, Encoding, FileFormat};
+use crate::grapheme;
+use crate::motion;
+use crate::region::Region;
+use crate::register::{Kind, Register};
+use crate::render::CURSOR_START_POS;
+use crate::search;
+use crate::settings::Settings;
+use crate::substitute::{self, Match, Substitution};
+use crate::swap::{self, Recovery, Swap};
+use crate::syntax::Highlighter;
+use crate::textobject;
+use crate::undo::UndoTree;
+use crate::window::{Layout, Rect, TabPage, Window};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use regex::Regex;
+use std::env;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const SWAP_IDLE: Duration = Duration::from_secs(4);
+const SWAP_KEYS: usize = 200;
+
+const NO_WRITE: &str = "E37: No write since last change (add ! to override)";
+const NO_FILE_NAME: &str = "E32: No file name";
+const NO_PREVIOUS_PATTERN: &str = "E35: No previous regular expression";
+const READONLY: &str = "E45: 'readonly' option is set (add ! to override)";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+    Normal,
+    Insert,
+    Command,
+    Visual(Kind), // v は文字単位, V は行単位, Ctrl-V は矩形
+}
+
+// 矩形選択の I / A で、最初の行に入力した内容をほかの行にも入れる
+struct BlockInsert {
+    last: usize,           // 最後の行
+    x: usize,              // 入れる位置 (表示上の列)
+    append: bool,          // A なら短い行は空白で伸ばしてから入れる
+    start: (usize, usize), // 最初の行で入力を始めた位置
+    len: usize,            // 入力を始めたときの最初の行の長さ
+}
+
+// バッファごとの状態 (今のバッファの分は Editor のフィールドに出していて、ほかのバッファの分をここに置く)
+struct BufferState {
+    number: usize, // :ls や :b で使うバッファの番号
+    buffer: Buffer,
+    format: FileFormat,
+    saved_format: FileFormat, // 最後に読み込んだか保存したときの形式
+    readonly: bool,
+    unreadable: bool, // 読めなかったファイルを開いている
+    filename: String,
+    filepath: PathBuf,
+    recorder: UndoTree,
+    syntax: Highlighter,
+    swap_version: u64,
+    swap_found: bool, // 開いたときに残っていたスワップファイルを、まだ復元も削除もしていない
+    cursor: (usize, usize), // 最後にいた位置 (戻ってきたときはここから始める)
+    upper: usize,
+}
+
+impl BufferState {
+    fn is_modified(&self) -> bool {
+        self.recorder.is_modified() || self.buffer.has_edits() || self.format != self.saved_format
+    }
+
+    fn name(&self) -> &str {
+        if self.filename.is_empty() {
+            "[No Name]"
+        } else {
+            &self.filename
+        }
+    }
+}
+
+// 最後に検索したパターン (n / N で繰り返す)
+struct Search {
+    pattern: String,
+    regex: Regex,
+    forward: bool, // / なら true, ? なら false
+}
+
+// 大文字と小文字を入れ替える
+fn toggle_case(c: char) -> String {
+    if c.is_lowercase() {
+        c.to_uppercase().collect()
+    } else {
+        c.to_lowercase().collect()
+    }
+}
+
+// :earlier / :later の引数 ("3" なら3回分, "10s" "5m" "1h" "1d" なら時間)
+enum TimeTravel {
+    Count(usize),
+    Time(Duration),
+}
+
+fn parse_time_travel(arg: &str) -> Result<TimeTravel, String> {
+    if arg.is_empty() {
+        return Ok(TimeTravel::Count(1));
+    }
+    let invalid = || format!("E475: Invalid argument: {}", arg);
+    let (number, unit) = match arg.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => arg.split_at(i),
+        None => (arg, ""),
+    };
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let seconds = match unit {
+        "" => return Ok(TimeTravel::Count(number as usize)),
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    Ok(TimeTravel::Time(Duration::from_secs(number * seconds)))
+}
+
+// :e の引数の前に付けた ++enc={encoding} と、残りのファイル名
+fn file_options(arg: &str) -> Result<(Option<Encoding>, &str), String> {
+    let mut encoding = None;
+    let mut rest = arg;
+    while let Some(option) = rest.strip_prefix("++") {
+        let (option, after) = option.split_once(' ').unwrap_or((option, ""));
+        let invalid = || format!("E474: Invalid argument: ++{}", option);
+        encoding = match option.split_once('=') {
+            Some(("enc" | "encoding", name)) => {
+                Some(Encoding::from_name(name).ok_or_else(invalid)?)
+            }
+            _ => return Err(invalid()),
+        };
+        rest = after.trim_start();
+    }
+    Ok((encoding, rest))
+}
+
+// 開いたファイルに書き込めるか
+#[derive(Clone, Copy, PartialEq)]
+enum Access {
+    Writable,
+    ReadOnly,
+    Unreadable, // 読めなかった (バッファにファイルの内容が無いので、:w! でも上書きしない)
+}
+
+// ファイルを開いて (バッファ, 形式, 書き込めるか, 表示するメッセージ) を返す
+// encoding を指定しなければ文字コードは内容から判別する
+// 無いファイルは空の新しいバッファとして開き、最初に保存したときに作る
+// 読めないファイルは空のバッファを読み込み専用で開く
+fn open_file(
+    filename: &str,
+    filepath: &Path,
+    encoding: Option<Encoding>,
+) -> (Buffer, FileFormat, Access, String) {
+    match read_file(filepath, encoding) {
+        Ok((buffer, format)) => {
+            let readonly = !is_writable(filepath);
+            let message = format!(
+                "\"{}\" {}{}L",
+                filename,
+                if readonly { "[readonly] " } else { "" },
+                buffer.len_lines()
+            );
+            let access = if readonly {
+                Access::ReadOnly
+            } else {
+                Access::Writable
+            };
+            (buffer, format, access, message)
+        }
+        Err(e) => {
+            let message = match e.kind() {
+                io::ErrorKind::NotFound => {
+                    return (
+                        Buffer::default(),
+                        FileFormat::new(),
+                        Access::Writable,
+                        format!("\"{}\" [New]", filename),
+                    )
+                }
+                io::ErrorKind::PermissionDenied => format!("\"{}\" [Permission Denied]", filename),
+                _ => format!("E484: Can't open file {}: {}", filename, e),
+            };
+            (
+                Buffer::default(),
+                FileFormat::new(),
+                Access::Unreadable,
+                message,
+            )
+        }
+    }
+}
+
+// 作業ディレクトリからのパス
+fn path_of(filename: &str) -> Result<PathBuf, String> {
+    resolve(env::current_dir(), filename)
+}
+
+// dir からのパス (絶対パスならそのまま。作業ディレクトリが消されていて分からなければエラー)
+fn resolve(dir: io::Result<PathBuf>, filename: &str) -> Result<PathBuf, String> {
+    let path = Path::new(filename);
+    if path.is_absolute() {
+        return Ok(path.to_path_buf());
+    }
+    let dir = dir.map_err(|e| format!("E187: Directory unknown: {}", e))?;
+    Ok(dir.join(path))
+}
+
+// 行頭の空白 (自動インデント用)
+fn leading_spaces(line: &str) -> String {
+    line.chars().take_while(|c| *c == ' ').collect()
+}
+
+pub struct Editor {
+    pub buffer: Buffer,             // 入力された文字を保持するバッファ
+    pub format: FileFormat,         // 保存するときの改行コードや文字コード
+    saved_format: FileFormat, // 最後に読み込んだか保存したときの形式 (:set ff などで変えると未保存の変更になる)
+    pub readonly: bool,       // 読めない・書き込めないファイルを開いている
+    unreadable: bool,         // ファイルを読めなかった (:w! でも上書きしない)
+    pub filename: String,     // 空なら名前の無いバッファ
+    pub filepath: PathBuf,    // 作業ディレクトリからのパス
+    pub recorder: UndoTree,   // 変更履歴
+    pub syntax: Highlighter,  // シンタックスハイライト
+    pub number: usize,        // 今のバッファの番号
+    pub settings: Settings,   // :set で変更できるオプション
+    pub colors: Colorscheme,  // 強調表示の色
+    pub register: Register,   // d / y で保存した内容
+    pub cursor: (usize, usize), // バッファ上のカーソルの位置 (行, 列)
+    pub upper: usize,         // 画面の一番上に表示している行
+    pub leftcol: usize,       // 今のウィンドウの左端に表示している表示上の列
+    pub width: usize,         // 今のウィンドウの幅 (行番号を含む)
+    pub height: usize,        // 今のウィンドウのテキストを表示する行数
+    pub screen: (usize, usize), // 端末の (幅, 高さ) (最下行はコマンドラインとメッセージに使う)
+    pub windows: Vec<Window>, // すべてのウィンドウ (上から、左から順)
+    pub window: usize,        // 今のウィンドウの id
+    pub tabs: Vec<Option<TabPage>>, // すべてのタブページ (今のタブページは None にして、中身は上のフィールドに出している)
+    pub tab: usize,                 // 今のタブページの位置
+    pub mode: Mode,
+    pub cmdline: CommandLine,
+    pub message: String,                        // 最下行に表示するメッセージ
+    pub quit_prompt: bool,                      // 未保存のまま終了しようとして確認している
+    pub quit: bool,                             // 終了する
+    count: usize,                               // コマンドの前に入力した数
+    pending_g: Option<((usize, usize), usize)>, // 直前に g が押されたときの (カーソル, upper) (g- / gt 用)
+    operator: Option<(char, usize)>,            // 入力中の演算子 (d / c / y) とその前に入力した数
+    object: Option<char>, // 演算子の後に入力した i か a (テキストオブジェクト用)
+    change_start: (usize, usize), // 記録前の編集が始まったときのカーソル位置
+    visual_start: (usize, usize), // ビジュアルモードで選択を始めた位置
+    block_insert: Option<BlockInsert>,
+    search: Option<Search>,
+    hlsearch: bool,         // 最後に検索したパターンを強調するか (:noh で消す)
+    preview: Option<Regex>, // 入力中の検索パターン
+    search_origin: ((usize, usize), usize), // 検索を始めたときのカーソルの位置と表示の先頭行
+    substitution: Option<(Substitution, Match)>, // :s の c フラグで置き換えるかを尋ねている一致
+    hidden: Vec<BufferState>, // 今のバッファ以外のバッファ (番号順)
+    layout: Layout,         // ウィンドウの並べ方
+    last_window: usize,     // 最後に付けたウィンドウの id
+    pending_window: bool,   // 直前に Ctrl-W が押されたか (ウィンドウの操作用)
+    last_number: usize,     // 最後に付けたバッファの番号
+    // スワップファイルは、変更してから一定時間入力が無いときか、一定回数の入力ごとに書く
+    // 開いたときに残っていたスワップファイルは、復元するか削除するまで書き換えも削除もしない
+    swap_version: u64,
+    swap_found: bool,
+    swap_prompt: bool, // 残っていたスワップファイルをどうするか尋ねている
+    last_input: Instant,
+    keys_since_swap: usize,
+}
+
+impl Editor {
+    // 名前の無い空のバッファで始める
+    pub fn new(settings: Settings) -> Self {
+        let buffer = Buffer::default();
+        Self {
+            swap_version: buffer.version(),
+            swap_found: false,
+            swap_prompt: false,
+            buffer,
+            format: FileFormat::new(),
+            saved_format: FileFormat::new(),
+            readonly: false,
+            unreadable: false,
+            filename: String::new(),
+            filepath: PathBuf::new(),
+            recorder: UndoTree::new(),
+            syntax: Highlighter::default(),
+            number: 1,
+            settings,
+            colors: Colorscheme::new(Depth::Ansi16),
+            register: Register::new(),
+            cursor: (0, 0),
+            upper: 0,
+            leftcol: 0,
+            width: 80,
+            height: 24,
+            screen: (80, 25),
+            windows: vec![Window {
+                id: 1,
+                number: 1,
+                cursor: (0, 0),
+                upper: 0,
+                leftcol: 0,
+                rect: Rect {
+                    x: 0,
+                    y: 0,
+                    width: 80,
+                    height: 24,
+                },
+            }],
+            window: 1,
+            tabs: vec![None],
+            tab: 0,
+            mode: Mode::Normal,
+            cmdline: CommandLine::new(),
+            message: String::new(),
+            quit_prompt: false,
+            quit: false,
+            count: 0,
+            pending_g: None,
+            operator: None,
+            object: None,
+            change_start: (0, 0),
+            visual_start: (0, 0),
+            block_insert: None,
+            search: None,
+            hlsearch: false,
+            preview: None,
+            search_origin: ((0, 0), 0),
+            substitution: None,
+            hidden: Vec::new(),
+            last_number: 1,
+            layout: Layout::Window(1),
+            last_window: 1,
+            pending_window: false,
+            last_input: Instant::now(),
+            keys_since_swap: 0,
+        }
+    }
+
+    // ファイルを開いて始める
+    pub fn open(filename: &str, settings: Settings) -> Self {
+        let mut editor = Self::new(settings);
+        editor.load(filename, None);
+        editor
+    }
+
+    // 残りのファイルを、裏のバッファとして開く (起動時に複数のファイルを指定したとき)
+    pub fn add_buffers(&mut self, filenames: &[String]) {
+        for filename in filenames {
+            self.last_number += 1;
+            let (state, _) = self.read_buffer(self.last_number, filename, None);
+            self.hidden.push(state);
+        }
+    }
+
+    // ファイルを読み込んだバッファ (undo の履歴があれば引き継ぐ) と、表示するメッセージ
+    fn read_buffer(
+        &self,
+        number: usize,
+        filename: &str,
+        encoding: Option<Encoding>,
+    ) -> (BufferState, String) {
+        let filepath = path_of(filename);
+        let (buffer, format, access, message) = match &filepath {
+            Ok(filepath) => open_file(filename, filepath, encoding),
+            // 作業ディレクトリが分からなければ、読めなかったファイルと同じように開く
+            Err(msg) => (
+                Buffer::default(),
+                FileFormat::new(),
+                Access::Unreadable,
+                msg.clone(),
+            ),
+        };
+        let filepath = filepath.unwrap_or_else(|_| PathBuf::from(filename));
+        let recorder = if self.settings.undofile {
+            UndoTree::load(&filepath, &buffer).unwrap_or_else(UndoTree::new)
+        } else {
+            UndoTree::new()
+        };
+        // 前に落ちたときのスワップファイルが残っているか (内容がファイルと同じなら、保存していない変更は無かったので消す)
+        let swap_found = match swap::read(&filepath) {
+            Some(swap) if !swap.is_running() && swap.text == buffer.to_string() => {
+                swap::remove(&filepath);
+                false
+            }
+            found => found.is_some(),
+        };
+        let state = BufferState {
+            number,
+            swap_version: buffer.version(),
+            swap_found,
+            syntax: Highlighter::new(filename, &buffer),
+            buffer,
+            format,
+            saved_format: format,
+            readonly: access != Access::Writable,
+            unreadable: access == Access::Unreadable,
+            filename: filename.to_string(),
+            filepath,
+            recorder,
+            cursor: (0, 0),
+            upper: 0,
+        };
+        (state, message)
+    }
+
+    // ファイルを読み込んで今のバッファにする
+    fn load(&mut self, filename: &str, encoding: Option<Encoding>) {
+        let (state, message) = self.read_buffer(self.number, filename, encoding);
+        self.unpark(state);
+        self.message = message;
+    }
+
+    // 今のバッファの状態を取り出す (フィールドには空のバッファが残る)
+    fn park(&mut self) -> BufferState {
+        self.recorder.commit(&mut self.buffer, self.change_start);
+        self.sync_syntax();
+        BufferState {
+            number: self.number,
+            buffer: mem::take(&mut self.buffer),
+            format: self.format,
+            saved_format: self.saved_format,
+            readonly: self.readonly,
+            unreadable: self.unreadable,
+            filename: mem::take(&mut self.filename),
+            filepath: mem::take(&mut self.filepath),
+            recorder: mem::replace(&mut self.recorder, UndoTree::new()),
+            syntax: mem::take(&mut self.syntax),
+            swap_version: self.swap_version,
+            swap_found: self.swap_found,
+            cursor: self.cursor,
+            upper: self.upper,
+        }
+    }
+
+    // state を今のバッファにする
+    fn unpark(&mut self, state: BufferState) {
+        self.number = state.number;
+        self.buffer = state.buffer;
+        self.format = state.format;
+        self.saved_format = state.saved_format;
+        self.readonly = state.readonly;
+        self.unreadable = state.unreadable;
+        self.filename = state.filename;
+        self.filepath = state.filepath;
+        self.recorder = state.recorder;
+        self.syntax = state.syntax;
+        self.swap_version = state.swap_version;
+        self.swap_found = state.swap_found;
+        self.cursor = state.cursor;
+        self.upper = state.upper;
+        self.clamp_cursor();
+    }
+
+    // 番号が number のバッファに切り替える (今のバッファは変更があっても裏に回す)
+    fn switch_to(&mut self, number: usize) -> Result<(), String> {
+        if number == self.number {
+            return Ok(());
+        }
+        let Some(i) = self.hidden.iter().position(|state| state.number == number) else {
+            return Err(format!("E86: Buffer {} does not exist", number));
+        };
+        let target = self.hidden.remove(i);
+        let current = self.park();
+        self.hidden.push(current);
+        self.hidden.sort_by_key(|state| state.number);
+        self.unpark(target);
+        self.message = format!("\"{}\" line {}", self.name(), self.cursor.0 + 1);
+        Ok(())
+    }
+
+    // 番号が number のバッファの内容 (今のバッファか裏のバッファ)
+    pub fn buffer_of(&self, number: usize) -> &Buffer {
+        match self.hidden.iter().find(|state| state.number == number) {
+            Some(state) => &state.buffer,
+            None => &self.buffer,
+        }
+    }
+
+    // 番号が number のバッファのシンタックスハイライト
+    pub fn syntax_of(&self, number: usize) -> &Highlighter {
+        match self.hidden.iter().find(|state| state.number == number) {
+            Some(state) => &state.syntax,
+            None => &self.syntax,
+        }
+    }
+
+    // 編集した行から後のシンタックスハイライトを調べ直すようにする
+    fn sync_syntax(&mut self) {
+        if let Some(line) = self.buffer.take_changed() {
+            self.syntax.invalidate(line);
+        }
+    }
+
+    // 番号が number のバッファの名前と、未保存の変更があるか
+    pub fn buffer_status(&self, number: usize) -> (&str, bool) {
+        match self.hidden.iter().find(|state| state.number == number) {
+            Some(state) => (state.name(), state.is_modified()),
+            None => (self.name(), self.is_modified()),
+        }
+    }
+
+    fn current_window(&self) -> &Window {
+        self.windows.iter().find(|w| w.id == self.window).unwrap()
+    }
+
+    // 端末の大きさに合わせてウィンドウを並べ直す
+    fn arrange(&mut self) {
+        // タブページが複数あれば、一番上の行をタブの一覧に使う
+        let top = usize::from(self.tabs.len() > 1);
+        let area = Rect {
+            x: 0,
+            y: top,
+            width: self.screen.0,
+            height: self.screen.1.saturating_sub(1 + top),
+        };
+        let mut rects = Vec::new();
+        self.layout.arrange(area, &mut rects);
+        // ウィンドウが複数あれば、それぞれの最下行をステータス行にする
+        let status = usize::from(rects.len() > 1);
+        let mut windows = mem::take(&mut self.windows);
+        for (id, rect) in rects {
+            let i = windows.iter().position(|w| w.id == id).unwrap();
+            let mut window = windows.swap_remove(i);
+            window.rect = Rect {
+                height: rect.height.saturating_sub(status).max(1),
+                ..rect
+            };
+            self.windows.push(window);
+        }
+        let rect = self.current_window().rect;
+        (self.width, self.height) = (rect.width, rect.height);
+        self.scroll();
+    }
+
+    // id のウィンドウに移る (今のウィンドウのカーソルの位置などは残しておく)
+    fn enter_window(&mut self, id: usize) {
+        self.leave_window();
+        self.window = id;
+        self.load_window();
+    }
+
+    // 今のウィンドウのカーソルの位置などを残しておく
+    fn leave_window(&mut self) {
+        let (cursor, upper, leftcol, number) = (self.cursor, self.upper, self.leftcol, self.number);
+        if let Some(current) = self.windows.iter_mut().find(|w| w.id == self.window) {
+            (
+                current.cursor,
+                current.upper,
+                current.leftcol,
+                current.number,
+            ) = (cursor, upper, leftcol, number);
+        }
+    }
+
+    // 今のウィンドウに残しておいたバッファとカーソルの位置に戻す
+    fn load_window(&mut self) {
+        let target = self.current_window().clone();
+        if target.number != self.number {
+            let i = self
+                .hidden
+                .iter()
+                .position(|state| state.number == target.number);
+            let state = self.hidden.remove(i.unwrap());
+            let current = self.park();
+            self.hidden.push(current);
+            self.hidden.sort_by_key(|state| state.number);
+            self.unpark(state);
+        }
+        (self.cursor, self.upper, self.leftcol) = (target.cursor, target.upper, target.leftcol);
+        (self.width, self.height) = (target.rect.width, target.rect.height);
+        self.clamp_cursor();
+        self.scroll();
+    }
+
+    // 今のウィンドウを上下 (vertical なら左右) に分けて、新しいウィンドウに移る
+    // 新しいウィンドウは同じバッファの同じ位置を表示する
+    fn split_window(&mut self, vertical: bool) {
+        self.last_window += 1;
+        let id = self.last_window;
+        self.layout.split(self.window, id, vertical);
+        self.windows.push(Window {
+            id,
+            number: self.number,
+            cursor: self.cursor,
+            upper: self.upper,
+            leftcol: self.leftcol,
+            rect: Rect::default(),
+        });
+        self.arrange();
+        self.enter_window(id);
+    }
+
+    // 今のウィンドウを閉じて、前 (無ければ次) のウィンドウに移る (バッファは閉じない)
+    // タブページの最後のウィンドウなら、タブページを閉じる
+    fn close_window(&mut self) -> Result<(), String> {
+        if self.windows.len() == 1 && self.tabs.len() > 1 {
+            return self.close_tab();
+        }
+        if self.windows.len() == 1 {
+            return Err("E444: Cannot close last window".to_string());
+        }
+        let i = self
+            .windows
+            .iter()
+            .position(|w| w.id == self.window)
+            .unwrap();
+        self.windows.remove(i);
+        self.layout.remove(self.window);
+        self.enter_window(self.windows[i.saturating_sub(1)].id);
+        self.arrange();
+        Ok(())
+    }
+
+    // 今のタブページの後ろに、filename (空なら新しい空のバッファ) を表示するウィンドウが1つのタブページを作る
+    fn new_tab(&mut self, filename: &str) -> Result<(), String> {
+        self.leave_tab();
+        self.last_window += 1;
+        self.window = self.last_window;
+        self.windows = vec![Window {
+            id: self.window,
+            number: self.number,
+            cursor: self.cursor,
+            upper: self.upper,
+            leftcol: self.leftcol,
+            rect: Rect::default(),
+        }];
+        self.layout = Layout::Window(self.window);
+        self.tab += 1;
+        self.tabs.insert(self.tab, None);
+        self.arrange();
+        if filename.is_empty() {
+            let current = self.park();
+            self.hidden.push(current);
+            let state = self.empty_buffer();
+            self.unpark(state);
+            Ok(())
+        } else {
+            self.edit(filename, false)
+        }
+    }
+
+    // 今のタブページの並べ方とウィンドウを tabs に戻す
+    fn leave_tab(&mut self) {
+        self.leave_window();
+        self.tabs[self.tab] = Some(TabPage {
+            layout: mem::replace(&mut self.layout, Layout::Window(0)),
+            windows: mem::take(&mut self.windows),
+            window: self.window,
+        });
+    }
+
+    // index 番目のタブページに移る
+    fn enter_tab(&mut self, index: usize) {
+        if index == self.tab {
+            return;
+        }
+        self.leave_tab();
+        self.tab = index;
+        self.load_tab();
+    }
+
+    // 今のタブページの並べ方とウィンドウを tabs から取り出す
+    fn load_tab(&mut self) {
+        let tab = self.tabs[self.tab].take().unwrap();
+        (self.layout, self.windows, self.window) = (tab.layout, tab.windows, tab.window);
+        // 別のタブページにいる間に端末の大きさが変わっているかもしれない
+        self.arrange();
+        self.load_window();
+    }
+
+    // 今のタブページを閉じて、次 (無ければ前) のタブページに移る
+    fn close_tab(&mut self) -> Result<(), String> {
+        if self.tabs.len() == 1 {
+            return Err("E784: Cannot close last tab page".to_string());
+        }
+        self.tabs.remove(self.tab);
+        self.tab = self.tab.min(self.tabs.len() - 1);
+        self.load_tab();
+        Ok(())
+    }
+
+    // 番号が number のバッファを表示しているウィンドウの数 (ほかのタブページも含む)
+    fn windows_showing(&self, number: usize) -> usize {
+        let others = self.tabs.iter().flatten().flat_map(|tab| &tab.windows);
+        self.windows
+            .iter()
+            .map(|w| {
+                if w.id == self.window {
+                    self.number
+                } else {
+                    w.number
+                }
+            })
+            .chain(others.map(|w| w.number))
+            .filter(|n| *n == number)
+            .count()
+    }
+
+    // 今のウィンドウ以外を閉じる
+    fn only_window(&mut self) {
+        self.windows.retain(|w| w.id == self.window);
+        self.layout = Layout::Window(self.window);
+        self.arrange();
+    }
+
+    // 今のウィンドウの dir (h j k l) の向きの隣にあるウィンドウ
+    // 左右はカーソルの行に、上下はウィンドウの左端の列に重なるものを選ぶ
+    fn neighbor(&self, dir: char) -> Option<usize> {
+        let status = usize::from(self.windows.len() > 1);
+        // 右の区切りの縦線と、下のステータス行を含めた範囲
+        let outer = |r: Rect| (r.x, r.y, r.x + r.width + 1, r.y + r.height + status);
+        let (x0, y0, x1, y1) = outer(self.current_window().rect);
+        let row = y0 + self.cursor.0.saturating_sub(self.upper);
+        self.windows
+            .iter()
+            .find(|w| {
+                let (a0, b0, a1, b1) = outer(w.rect);
+                match dir {
+                    'h' => a1 == x0 && b0 <= row && row < b1,
+                    'l' => a0 == x1 && b0 <= row && row < b1,
+                    'k' => b1 == y0 && a0 <= x0 && x0 < a1,
+                    _ => b0 == y1 && a0 <= x0 && x0 < a1,
+                }
+            })
+            .map(|w| w.id)
+    }
+
+    // Ctrl-W の後のキー (ウィンドウの分割、移動、閉じる、大きさを変える)
+    fn window_command(&mut self, key: KeyEvent) {
+        let count = self.take_count() as isize;
+        let i = self
+            .windows
+            .iter()
+            .position(|w| w.id == self.window)
+            .unwrap();
+        let len = self.windows.len();
+        // 矢印キーは h j k l と同じ
+        let c = match key.code {
+            KeyCode::Left => 'h',
+            KeyCode::Down => 'j',
+            KeyCode::Up => 'k',
+            KeyCode::Right => 'l',
+            KeyCode::Char(c) => c,
+            _ => return,
+        };
+        match c {
+            's' | 'S' => self.split_window(false),
+            'v' => self.split_window(true),
+            'w' => self.enter_window(self.windows[(i + 1) % len].id),
+            'W' => self.enter_window(self.windows[(i + len - 1) % len].id),
+            'h' | 'j' | 'k' | 'l' => {
+                if let Some(id) = self.neighbor(c) {
+                    self.enter_window(id);
+                }
+            }
+            'c' | 'q' => {
+                let result = if c == 'c' {
+                    self.close_window()
+                } else {
+                    self.execute("quit")
+                };
+                if let Err(msg) = result {
+                    self.message = msg;
+                }
+            }
+            'o' => self.only_window(),
+            '+' | '-' | '>' | '<' => {
+                let delta = if c == '+' || c == '>' { count } else { -count };
+                self.layout.resize(self.window, c == '>' || c == '<', delta);
+                self.arrange();
+            }
+            '=' => {
+                self.layout.equalize();
+                self.arrange();
+            }
+            _ => {}
+        }
+    }
+
+    // number の次 (step = 1) か前 (step = -1) の番号のバッファ (端を越えたら反対の端に戻る)
+    fn cycle_buffer_from(&self, number: usize, step: isize) -> usize {
+        let mut numbers: Vec<usize> = self.hidden.iter().map(|state| state.number).collect();
+        numbers.push(self.number);
+        numbers.sort();
+        let i = numbers.iter().position(|n| *n == number).unwrap() as isize;
+        let len = numbers.len() as isize;
+        numbers[((i + step) % len + len) as usize % len as usize]
+    }
+
+    // :b の引数 (番号か、ファイル名の一部) のバッファの番号
+    fn find_buffer(&self, arg: &str) -> Result<usize, String> {
+        if let Ok(number) = arg.parse::<usize>() {
+            return Ok(number);
+        }
+        let mut found = self
+            .hidden
+            .iter()
+            .map(|state| (state.number, state.filename.as_str()))
+            .chain([(self.number, self.filename.as_str())])
+            .filter(|(_, name)| name.contains(arg));
+        match (found.next(), found.next()) {
+            (Some((number, _)), None) => Ok(number),
+            (Some(_), Some(_)) => Err(format!("E93: More than one match for {}", arg)),
+            (None, _) => Err(format!("E94: No matching buffer for {}", arg)),
+        }
+    }
+
+    // :bd でバッファを閉じる (今のバッファなら次のバッファに、無ければ空のバッファに切り替える)
+    fn delete_buffer(&mut self, number: usize, force: bool) -> Result<(), String> {
+        let modified = if number == self.number {
+            self.is_modified()
+        } else {
+            let state = self.hidden.iter().find(|state| state.number == number);
+            state
+                .ok_or(format!("E516: No buffers were deleted: bd {}", number))?
+                .is_modified()
+        };
+        if modified && !force {
+            return Err(format!(
+                "E89: No write since last change for buffer {} (add ! to override)",
+                number
+            ));
+        }
+        if number != self.number {
+            let i = self
+                .hidden
+                .iter()
+                .position(|state| state.number == number)
+                .unwrap();
+            let state = self.hidden.remove(i);
+            if !state.filename.is_empty() && !state.swap_found {
+                swap::remove(&state.filepath);
+            }
+            return Ok(());
+        }
+        if !self.filename.is_empty() && !self.swap_found {
+            swap::remove(&self.filepath);
+        }
+        let next = self
+            .hidden
+            .iter()
+            .position(|state| state.number > number)
+            .or_else(|| self.hidden.len().checked_sub(1));
+        match next {
+            Some(i) => {
+                let state = self.hidden.remove(i);
+                self.unpark(state);
+            }
+            None => {
+                let state = self.empty_buffer();
+                self.unpark(state);
+            }
+        }
+        Ok(())
+    }
+
+    // 新しい番号の、名前の無い空のバッファ
+    fn empty_buffer(&mut self) -> BufferState {
+        self.last_number += 1;
+        BufferState {
+            number: self.last_number,
+            buffer: Buffer::default(),
+            format: FileFormat::new(),
+            saved_format: FileFormat::new(),
+            readonly: false,
+            unreadable: false,
+            filename: String::new(),
+            filepath: PathBuf::new(),
+            recorder: UndoTree::new(),
+            syntax: Highlighter::default(),
+            swap_version: 0,
+            swap_found: false,
+            cursor: (0, 0),
+            upper: 0,
+        }
+    }
+
+    // :ls の一覧 (% は今のバッファ、+ は未保存の変更があるバッファ)
+    fn list_buffers(&self) -> String {
+        let mut lines: Vec<(usize, String)> = self
+            .hidden
+            .iter()
+            .map(|state| {
+                let line = format!(
+                    "{:>3}  h {} \"{}\" line {}",
+                    state.number,
+                    if state.is_modified() { "+" } else { " " },
+                    state.name(),
+                    state.cursor.0 + 1
+                );
+                (state.number, line)
+            })
+            .collect();
+        lines.push((
+            self.number,
+            format!(
+                "{:>3} %a {} \"{}\" line {}",
+                self.number,
+                if self.is_modified() { "+" } else { " " },
+                self.name(),
+                self.cursor.0 + 1
+            ),
+        ));
+        lines.sort_by_key(|(number, _)| *number);
+        lines
+            .into_iter()
+            .map(|(_, line)| line)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    // 今のバッファの名前 (名前が無ければ [No Name])
+    fn name(&self) -> &str {
+        if self.filename.is_empty() {
+            "[No Name]"
+        } else {
+            &self.filename
+        }
+    }
+
+    // 未保存の変更がある裏のバッファがあれば、終了しない理由のメッセージ
+    fn hidden_modified(&self) -> Option<String> {
+        self.hidden
+            .iter()
+            .find(|state| state.is_modified())
+            .map(|state| {
+                format!(
+                    "E162: No write since last change for buffer \"{}\"",
+                    state.name()
+                )
+            })
+    }
+
+    // ほかのバッファに未保存の変更が無ければ終了する (あれば警告する)
+    fn quit_if_saved(&mut self) {
+        match self.hidden_modified() {
+            Some(msg) => self.message = msg,
+            None => self.quit = true,
+        }
+    }
+
+    // スワップファイルの内容に置き換える
+    // 復元は1回分の変更として記録するので、u でファイルの内容に戻せる
+    pub fn recover(&mut self, swap: Swap) {
+        let last = self.buffer.len_lines() - 1;
+        self.buffer
+            .delete((0, 0), (last, self.buffer.line_len(last)));
+        self.buffer.insert((0, 0), &swap.text);
+        self.recorder.commit(&mut self.buffer, (0, 0));
+        self.jump_to(swap.cursor);
+        self.swap_found = false;
+    }
+
+    // 起動時に開いたすべてのバッファについて、残っていたスワップファイルをどうするかを ask で尋ねる
+    // (終了するなら false。尋ねた後は最初のバッファに戻る)
+    pub fn check_swaps(
+        &mut self,
+        mut ask: impl FnMut(&Path, &str) -> io::Result<Recovery>,
+    ) -> io::Result<bool> {
+        let (first, message) = (self.number, self.message.clone());
+        let found: Vec<usize> = (self.swap_found.then_some(self.number).into_iter())
+            .chain(
+                self.hidden
+                    .iter()
+                    .filter(|s| s.swap_found)
+                    .map(|s| s.number),
+            )
+            .collect();
+        for number in found {
+            self.switch_to(number).unwrap();
+            match ask(&self.filepath, &self.buffer.to_string())? {
+                Recovery::Recover(swap) => self.recover(swap),
+                // 削除していなければ、そのまま残しておく
+                Recovery::Ignore => self.swap_found = swap::read(&self.filepath).is_some(),
+                Recovery::Quit => return Ok(false),
+            }
+        }
+        self.switch_to(first).unwrap();
+        self.message = message;
+        Ok(true)
+    }
+
+    // 端末の大きさが変わったとき
+    pub fn resize(&mut self, width: u16, height: u16) {
+        self.screen = (width as usize, height as usize);
+        self.arrange();
+    }
+
+    // 未保存の変更があるか (記録前の編集と、形式の変更も含む)
+    pub fn is_modified(&self) -> bool {
+        self.recorder.is_modified() || self.buffer.has_edits() || self.format != self.saved_format
+    }
+
+    // カーソルを画面内に収める (はみ出した分だけずらす)
+    fn scroll(&mut self) {
+        let (line, col) = self.cursor;
+        if line < self.upper {
+            self.upper = line;
+        } else if line >= self.upper + self.height {
+            self.upper = line + 1 - self.height;
+        }
+        // 左右も、カーソルの文字が全部見えるようにずらす
+        let text = self.buffer.line(line);
+        let tabstop = self.settings.tabstop;
+        let x = grapheme::width_to(&text, col, tabstop);
+        let end =
+            grapheme::width_to(&text, grapheme::next_boundary(&text, col), tabstop).max(x + 1);
+        let text_width = self.width.saturating_sub(CURSOR_START_POS).max(1);
+        if x < self.leftcol {
+            self.leftcol = x;
+        } else if end > self.leftcol + text_width {
+            self.leftcol = end.saturating_sub(text_width).min(x);
+        }
+    }
+
+    // 離れた位置に移動する (画面外なら移動先が画面の中央になるようにずらす)
+    fn jump_to(&mut self, pos: (usize, usize)) {
+        self.cursor = pos;
+        self.clamp_cursor();
+        if self.cursor.0 < self.upper || self.cursor.0 >= self.upper + self.height {
+            self.upper = self.cursor.0.saturating_sub(self.height / 2);
+        }
+    }
+
+    // カーソルがバッファの外に出ないようにする
+    fn clamp_cursor(&mut self) {
+        let line = self.cursor.0.min(self.buffer.len_lines() - 1);
+        let text = self.buffer.line(line);
+        let col = grapheme::snap(&text, self.cursor.1.min(text.chars().count()));
+        self.cursor = (line, col);
+    }
+
+    // line 行目に上下に移動するときの移動先 (表示上の列がなるべく変わらないようにする)
+    fn vertical_target(&self, line: usize) -> (usize, usize) {
+        let tabstop = self.settings.tabstop;
+        let x = grapheme::width_to(&self.buffer.line(self.cursor.0), self.cursor.1, tabstop);
+        let line = line.min(self.buffer.len_lines() - 1);
+        (
+            line,
+            grapheme::col_at_width(&self.buffer.line(line), x, tabstop),
+        )
+    }
+
+    fn move_vertically(&mut self, line: usize) {
+        self.cursor = self.vertical_target(line);
+        self.scroll();
+    }
+
+    // 移動コマンドの移動先 (移動コマンドでなければ None)
+    // count は入力した数 (入力していなければ 0)
+    fn motion(&self, c: char, count: usize) -> Option<(usize, usize)> {
+        let (line, col) = self.cursor;
+        let n = count.max(1);
+        let text = self.buffer.line(line);
+        let last = self.buffer.len_lines() - 1;
+        Some(match c {
+            'h' => (
+                line,
+                (0..n).fold(col, |col, _| grapheme::prev_boundary(&text, col)),
+            ),
+            'l' => (
+                line,
+                (0..n).fold(col, |col, _| grapheme::next_boundary(&text, col)),
+            ),
+            'j' => self.vertical_target(line + n),
+            'k' => self.vertical_target(line.saturating_sub(n)),
+            'w' => (0..n).fold(self.cursor, |pos, _| {
+                motion::next_word_start(&self.buffer, pos)
+            }),
+            'b' => (0..n).fold(self.cursor, |pos, _| {
+                motion::prev_word_start(&self.buffer, pos)
+            }),
+            '$' => (line, text.chars().count()),
+            '^' => (line, 0),
+            'g' => (0, motion::first_non_blank(&self.buffer.line(0))),
+            // G は最後の行に、数を入力した後ならその行に移動する
+            'G' => {
+                let target = if count == 0 {
+                    last
+                } else {
+                    (count - 1).min(last)
+                };
+                (target, motion::first_non_blank(&self.buffer.line(target)))
+            }
+            _ => return None,
+        })
+    }
+
+    fn move_left(&mut self) {
+        self.cursor = self.motion('h', 1).unwrap();
+    }
+
+    fn move_right(&mut self) {
+        self.cursor = self.motion('l', 1).unwrap();
+    }
+
+    // 数の指定 (無ければ 1)
+    fn take_count(&mut self) -> usize {
+        mem::take(&mut self.count).max(1)
+    }
+
+    // バッファを target に保存して、表示するメッセージを返す
+    // 名前の無いバッファか編集中のファイルへの保存なら、保存済みの印を付けて undo の履歴も保存する
+    fn save(&mut self, target: &str) -> Result<String, String> {
+        // 名前の無いバッファに付けるパス (作業ディレクトリが分からなければ保存しない)
+        let filepath = if self.filename.is_empty() {
+            Some(path_of(target)?)
+        } else {
+            None
+        };
+        write_file(target, &self.buffer, &self.format, self.settings.backup).map_err(
+            |e| match e.kind() {
+                io::ErrorKind::InvalidData => {
+                    format!("E513: write error, conversion failed: {}", e)
+                }
+                _ => format!("E212: Can't open file for writing: {}: {}", target, e),
+            },
+        )?;
+        // 名前の無いバッファは、保存できたらそのファイル名になる
+        if let Some(filepath) = filepath {
+            self.filename = target.to_string();
+            self.filepath = filepath;
+            self.syntax = Highlighter::new(target, &self.buffer);
+        }
+        if target == self.filename {
+            self.recorder.mark_saved();
+            self.saved_format = self.format;
+            if self.settings.undofile {
+                self.recorder
+                    .save(&self.filepath, &self.buffer)
+                    .map_err(|_| "E828: Cannot open undo file for writing".to_string())?;
+            }
+        }
+        let described = self.format.describe();
+        Ok(format!(
+            "\"{}\" {}{}{}L written",
+            target,
+            described,
+            if described.is_empty() { "" } else { " " },
+            self.buffer.len_lines()
+        ))
+    }
+
+    // 変更を書く必要があればスワップファイルを書く (入力が無いときも定期的に呼ぶ)
+    pub fn tick(&mut self) {
+        if self.buffer.version() != self.swap_version
+            && !self.filename.is_empty()
+            && !self.swap_found
+            && (self.last_input.elapsed() >= SWAP_IDLE || self.keys_since_swap >= SWAP_KEYS)
+        {
+            match swap::write(&self.filepath, &self.buffer, self.cursor) {
+                Ok(_) => {
+                    self.swap_version = self.buffer.version();
+                    self.keys_since_swap = 0;
+                }
+                Err(e) => self.message = format!("E297: Write error in swap file: {}", e),
+            }
+        }
+        // 裏のバッファはもう変わらないので、書いていない変更があればすぐに書く
+        for state in &mut self.hidden {
+            if state.buffer.version() != state.swap_version
+                && !state.filename.is_empty()
+                && !state.swap_found
+            {
+                match swap::write(&state.filepath, &state.buffer, state.cursor) {
+                    Ok(_) => state.swap_version = state.buffer.version(),
+                    Err(e) => self.message = format!("E297: Write error in swap file: {}", e),
+                }
+            }
+        }
+    }
+
+    // 終了するときの後片付け (残っていたものを除いて、すべてのバッファのスワップファイルを消す)
+    pub fn close(&mut self) {
+        if !self.filename.is_empty() && !self.swap_found {
+            swap::remove(&self.filepath);
+        }
+        for state in &self.hidden {
+            if !state.filename.is_empty() && !state.swap_found {
+                swap::remove(&state.filepath);
+            }
+        }
+    }
+
+    pub fn handle_key(&mut self, key: KeyEvent) {
+        self.last_input = Instant::now();
+        self.keys_since_swap += 1;
+        if !self.buffer.has_edits() {
+            self.change_start = self.cursor;
+        }
+        let after_g = mem::take(&mut self.pending_g);
+        self.message.clear();
+        if self.quit_prompt {
+            self.answer_quit_prompt(key.code);
+        } else if self.swap_prompt {
+            self.answer_swap_prompt(key.code);
+        } else if self.substitution.is_some() {
+            self.answer_substitution(key.code);
+        } else if mem::take(&mut self.pending_window) {
+            self.window_command(key);
+        } else if let Mode::Command = self.mode {
+            let searching = self.cmdline.is_search();
+            match self.cmdline.handle_key(key) {
+                Some(CommandLineEvent::Cancel) => {
+                    self.mode = Mode::Normal;
+                    if searching {
+                        self.preview = None;
+                        (self.cursor, self.upper) = self.search_origin;
+                    }
+                }
+                Some(CommandLineEvent::Execute(line)) => {
+                    self.mode = Mode::Normal;
+                    let result = if searching {
+                        self.search(&line)
+                    } else {
+                        self.execute(&line)
+                    };
+                    if let Err(msg) = result {
+                        self.message = msg;
+                    }
+                }
+                None if searching => self.preview_search(),
+                None => {}
+            }
+        } else {
+            self.edit_key(key, after_g);
+        }
+        // ノーマルモードのコマンドは1回ごとに、インサートモードに入ってから
+        // 抜けるまでの入力はまとめて1回分の変更として記録する
+        // :s で確認しながら置き換えた内容も、全部でまとめて1回分にする
+        if self.mode == Mode::Normal && self.substitution.is_none() {
+            self.recorder.commit(&mut self.buffer, self.change_start);
+        }
+        self.clamp_cursor();
+        self.scroll();
+        self.sync_syntax();
+    }
+
+    // 次に一致する部分にカーソルを移して、置き換えるかを尋ねる (無ければ終わる)
+    fn ask_substitution(&mut self, mut run: Substitution) -> Result<(), String> {
+        match run.next(&self.buffer) {
+            Some(m) => {
+                self.jump_to((m.line, m.start));
+                self.message = format!("replace with {} (y/n/a/q/l)?", m.text);
+                self.substitution = Some((run, m));
+                Ok(())
+            }
+            None => self.finish_substitution(run),
+        }
+    }
+
+    // y: 置き換える, n: 飛ばす, a: 残りをすべて置き換える, q / Esc: やめる, l: 置き換えてやめる
+    fn answer_substitution(&mut self, code: KeyCode) {
+        let Some((mut run, m)) = self.substitution.take() else {
+            return;
+        };
+        let result = match code {
+            KeyCode::Char('y') => {
+                run.replace(&mut self.buffer, &m);
+                self.ask_substitution(run)
+            }
+            KeyCode::Char('n') => {
+                run.skip(&m);
+                self.ask_substitution(run)
+            }
+            KeyCode::Char('a') => {
+                run.replace(&mut self.buffer, &m);
+                while let Some(m) = run.next(&self.buffer) {
+                    run.replace(&mut self.buffer, &m);
+                }
+                self.finish_substitution(run)
+            }
+            KeyCode::Char('l') => {
+                run.replace(&mut self.buffer, &m);
+                self.finish_substitution(run)
+            }
+            KeyCode::Char('q') | KeyCode::Esc => self.finish_substitution(run),
+            _ => {
+                self.message = format!("replace with {} (y/n/a/q/l)?", m.text);
+                self.substitution = Some((run, m));
+                Ok(())
+            }
+        };
+        if let Err(msg) = result {
+            self.message = msg;
+        }
+    }
+
+    // 置き換えた数を表示して、最後に置き換えた行に移動する
+    fn finish_substitution(&mut self, run: Substitution) -> Result<(), String> {
+        if run.matched == 0 {
+            return Err(format!(
+                "E486: Pattern not found: {}",
+                run.substitute.pattern
+            ));
+        }
+        if let Some(line) = run.changed {
+            self.jump_to((line, motion::first_non_blank(&self.buffer.line(line))));
+            let plural = |n: usize| if n == 1 { "" } else { "s" };
+            self.message = format!(
+                "{} substitution{} on {} line{}",
+                run.count,
+                plural(run.count),
+                run.lines,
+                plural(run.lines)
+            );
+        }
+        Ok(())
+    }
+
+    // 未保存の変更があるときの終了の確認
+    fn ask_quit(&mut self) {
+        self.quit_prompt = true;
+        self.message = format!("Save changes to \"{}\"? (y)es/(n)o/(c)ancel", self.filename);
+    }
+
+    fn answer_quit_prompt(&mut self, code: KeyCode) {
+        self.quit_prompt = false;
+        match code {
+            KeyCode::Char('y') if self.filename.is_empty() => {
+                self.message = NO_FILE_NAME.to_string()
+            }
+            KeyCode::Char('y') if self.readonly => self.message = READONLY.to_string(),
+            KeyCode::Char('y') => match self.save(&self.filename.clone()) {
+                Ok(_) => self.quit_if_saved(),
+                Err(msg) => self.message = msg,
+            },
+            KeyCode::Char('n') => self.quit_if_saved(),
+            _ => {}
+        }
+    }
+
+    // :e で開いたファイルにスワップファイルが残っていたときの確認
+    fn ask_swap(&mut self) {
+        self.swap_prompt = true;
+        self.message = format!(
+            "E325: ATTENTION: Found a swap file \"{}\" (r)ecover/(d)elete it/(e)dit anyway",
+            swap::swap_file_path(&self.filepath).display()
+        );
+    }
+
+    fn answer_swap_prompt(&mut self, code: KeyCode) {
+        self.swap_prompt = false;
+        match code {
+            KeyCode::Char('r') => match swap::read(&self.filepath) {
+                Some(swap) => self.recover(swap),
+                None => self.message = format!("E305: No swap file found for {}", self.filename),
+            },
+            KeyCode::Char('d') => {
+                swap::remove(&self.filepath);
+                self.swap_found = false;
+            }
+            // そのまま編集する (スワップファイルは残して、書き換えない)
+            _ => {}
+        }
+    }
+
+    // ノーマルモードとインサートモードのキー
+    fn edit_key(&mut self, key: KeyEvent, after_g: Option<((usize, usize), usize)>) {
+        // Ctrl との組み合わせは割り当てのあるものだけを実行して、ほかは文字として扱わずに無視する
+        if let (true, KeyCode::Char(c)) = (key.modifiers.contains(KeyModifiers::CONTROL), key.code)
+        {
+            match (c, self.mode) {
+                // ウィンドウの操作 (次のキーで何をするかが決まる)
+                ('w', Mode::Normal) => self.pending_window = true,
+                // 矩形選択を始める
+                ('v', Mode::Normal | Mode::Visual(_)) => self.toggle_visual(Kind::Block),
+                // インサートモードでカーソルの前の単語、行頭まで、1文字を消す
+                ('w' | 'u' | 'h', Mode::Insert) => self.delete_before_cursor(c),
+                _ => {}
+            }
+            return;
+        }
+        let (line, col) = self.cursor;
+        match key.code {
+            KeyCode::Enter => {
+                // Enterキーが押された場合、新しい行に移動
+                self.open_line();
+            }
+            KeyCode::Esc => {
+                if let Mode::Insert = self.mode {
+                    self.leave_insert();
+                }
+                self.mode = Mode::Normal;
+                self.count = 0;
+                self.operator = None;
+                self.object = None;
+            }
+            KeyCode::Tab => {
+                self.buffer
+                    .insert(self.cursor, &" ".repeat(self.settings.tabstop));
+                self.cursor.1 += self.settings.tabstop;
+            }
+            // 矢印キーなどはノーマルモードでもインサートモードでも使える
+            KeyCode::Left => self.move_left(),
+            KeyCode::Right => self.move_right(),
+            KeyCode::Up => self.move_vertically(line.saturating_sub(1)),
+            KeyCode::Down => self.move_vertically(line + 1),
+            // 1画面分スクロールする
+            KeyCode::PageUp => {
+                let row = line - self.upper;
+                self.move_vertically(line.saturating_sub(self.height));
+                self.upper = self.cursor.0.saturating_sub(row);
+            }
+            KeyCode::PageDown => {
+                let row = line - self.upper;
+                self.move_vertically(line + self.height);
+                self.upper = self.cursor.0 - row.min(self.cursor.0);
+            }
+            KeyCode::Home => self.cursor.1 = 0,
+            KeyCode::End => self.cursor.1 = self.buffer.line_len(line),
+            // インサートモードでの削除 (行末での Delete は次の行とつなげる)
+            KeyCode::Backspace if matches!(self.mode, Mode::Insert) => {
+                self.delete_before_cursor('h')
+            }
+            KeyCode::Delete if matches!(self.mode, Mode::Insert) => {
+                if col < self.buffer.line_len(line) {
+                    let end = grapheme::next_boundary(&self.buffer.line(line), col);
+                    self.buffer.delete((line, col), (line, end));
+                } else if line + 1 < self.buffer.len_lines() {
+                    self.buffer.delete((line, col), (line + 1, 0));
+                }
+            }
+            KeyCode::Char(c) => match self.mode {
+                Mode::Normal => self.normal_command(c, after_g),
+                Mode::Visual(_) => self.visual_command(c),
+                Mode::Insert => {
+                    if c == '`' {
+                        self.leave_insert();
+                    } else {
+                        // 文字が入力された場合、それをバッファに追加
+                        let mut s = [0; 4];
+                        self.buffer.insert(self.cursor, c.encode_utf8(&mut s));
+                        self.cursor.1 += 1; // カーソル位置を右に移動
+                    }
+                }
+                // コマンドラインの入力は先に処理している
+                Mode::Command => {}
+            },
+            _ => {}
+        }
+    }
+
+    // インサートモードでカーソルより前を消す
+    // Ctrl-W は単語、Ctrl-U は行頭まで、Ctrl-H (Backspace) は1文字で、行頭では前の行とつなげる
+    fn delete_before_cursor(&mut self, key: char) {
+        let (line, col) = self.cursor;
+        if col > 0 {
+            let text = self.buffer.line(line);
+            let start = match key {
+                'w' => motion::word_start_before(&text, col),
+                'u' => 0,
+                _ => grapheme::prev_boundary(&text, col),
+            };
+            self.buffer.delete((line, start), (line, col));
+            self.cursor.1 = start;
+        } else if line > 0 {
+            let len = self.buffer.line_len(line - 1);
+            self.buffer.delete((line - 1, len), (line, 0));
+            self.cursor = (line - 1, len);
+        }
+    }
+
+    // 下に行を開けてインサートモードにする (インデントは今の行に合わせる)
+    fn open_line(&mut self) {
+        self.mode = Mode::Insert;
+        let spaces = leading_spaces(&self.buffer.line(self.cursor.0));
+        self.buffer.insert_line(self.cursor.0 + 1, &spaces);
+        self.cursor = (self.cursor.0 + 1, spaces.len());
+    }
+
+    fn normal_command(&mut self, c: char, after_g: Option<((usize, usize), usize)>) {
+        let (line, col) = self.cursor;
+        if let (Some((op, before)), false) = (self.operator, c.is_ascii_digit()) {
+            // i / a の後はテキストオブジェクトを入力する
+            if (c == 'i' || c == 'a') && self.object.is_none() {
+                self.object = Some(c);
+                return;
+            }
+            self.operator = None;
+            let object = self.object.take();
+            // 演算子の前と後の数はかけ合わせる (2d3w は 6 単語)
+            let count = match (before, mem::take(&mut self.count)) {
+                (0, 0) => 0,
+                (a, b) => a.max(1) * b.max(1),
+            };
+            let region = match object {
+                Some(object) => self.text_object(object == 'a', c, count),
+                // dd / cc / yy は数の分の行を操作する
+                None if c == op => {
+                    let last = (line + count.max(1)).min(self.buffer.len_lines()) - 1;
+                    Some(Region::Line(line, last))
+                }
+                None => self.operator_region(op, c, count),
+            };
+            if let Some(region) = region {
+                self.apply_operator(op, region);
+            }
+            return;
+        }
+        if !c.is_ascii_digit() {
+            if let Some(pos) = self.motion(c, self.count) {
+                self.count = 0;
+                match c {
+                    'G' => self.jump_to(pos),
+                    _ => self.cursor = pos,
+                }
+                if c == 'g' {
+                    self.pending_g = Some(((line, col), self.upper));
+                }
+                return;
+            }
+        }
+        match c {
+            // manage numeric
+            '0'..='9' => {
+                self.count = self.count * 10 + c.to_digit(10).unwrap() as usize;
+            }
+            // redo undo
+            'u' => {
+                if let Some(pos) = self.recorder.undo(&mut self.buffer) {
+                    self.jump_to(pos);
+                }
+            }
+            'r' => {
+                if let Some(pos) = self.recorder.redo(&mut self.buffer) {
+                    self.jump_to(pos);
+                }
+            }
+            // 変更履歴を時間順にたどる
+            '-' | '+' if after_g.is_some() => {
+                let count = self.take_count();
+                let pos = if c == '-' {
+                    self.recorder.earlier(count, &mut self.buffer)
+                } else {
+                    self.recorder.later(count, &mut self.buffer)
+                };
+                if let Some(pos) = pos {
+                    self.jump_to(pos);
+                }
+            }
+            // 次/前のタブページに移る (g で動かしたカーソルは元に戻す)
+            't' | 'T' if after_g.is_some() => {
+                (self.cursor, self.upper) = after_g.unwrap();
+                let len = self.tabs.len();
+                let step = if c == 't' { 1 } else { len - 1 };
+                self.enter_tab((self.tab + step) % len);
+            }
+            // quit
+            'q' => {
+                if self.is_modified() {
+                    self.ask_quit();
+                } else {
+                    self.quit_if_saved();
+                }
+            }
+            // コマンドラインに入力する
+            ':' => {
+                self.mode = Mode::Command;
+                self.cmdline.start(':');
+            }
+            // 検索する
+            '/' | '?' => {
+                self.search_origin = (self.cursor, self.upper);
+                self.mode = Mode::Command;
+                self.cmdline.start(c);
+            }
+            'n' | 'N' => {
+                if let Err(msg) = self.search_next(c == 'N') {
+                    self.message = msg;
+                }
+            }
+            '*' | '#' => {
+                if let Err(msg) = self.search_word(c == '*') {
+                    self.message = msg;
+                }
+            }
+            // change mode to insert
+            'i' => {
+                self.mode = Mode::Insert;
+            }
+            'o' => self.open_line(),
+            // 選択を始める
+            'v' => self.toggle_visual(Kind::Char),
+            'V' => self.toggle_visual(Kind::Line),
+            // remove char
+            'x' if col > 0 => {
+                let start = grapheme::prev_boundary(&self.buffer.line(line), col);
+                self.buffer.delete((line, start), (line, col));
+                self.cursor.1 = start;
+            }
+            'X' if col < self.buffer.line_len(line) => {
+                let end = grapheme::next_boundary(&self.buffer.line(line), col);
+                self.buffer.delete((line, col), (line, end));
+            }
+            // 演算子 (続けて入力した移動コマンドの範囲か、同じキーなら行を操作する)
+            'd' | 'c' | 'y' => {
+                self.operator = Some((c, mem::take(&mut self.count)));
+            }
+            // paste clipboard (行単位なら今の行の上に、文字単位や矩形ならカーソルの位置に)
+            'p' => {
+                let (str, kind) = self.register.get();
+                self.put(&str, kind);
+            }
+            _ => {}
+        }
+    }
+
+    // 入力中のパターンに一致する位置にカーソルを動かして見せる
+    fn preview_search(&mut self) {
+        let pattern = self.cmdline.text();
+        (self.cursor, self.upper) = self.search_origin;
+        self.preview = search::compile(&pattern)
+            .ok()
+            .filter(|_| !pattern.is_empty());
+        if let Some(regex) = &self.preview {
+            let forward = self.cmdline.prompt == '/';
+            if let Some((pos, _)) = search::find(&self.buffer, regex, self.cursor, forward) {
+                self.jump_to(pos);
+            }
+        }
+    }
+
+    // / か ? で入力したパターンを探す (空なら前回のパターンで探す)
+    fn search(&mut self, pattern: &str) -> Result<(), String> {
+        self.preview = None;
+        (self.cursor, self.upper) = self.search_origin;
+        let pattern = match (pattern, &self.search) {
+            ("", Some(search)) => search.pattern.clone(),
+            ("", None) => return Err(NO_PREVIOUS_PATTERN.to_string()),
+            _ => pattern.to_string(),
+        };
+        self.search = Some(Search {
+            regex: search::compile(&pattern)?,
+            pattern,
+            forward: self.cmdline.prompt == '/',
+        });
+        self.search_next(false)
+    }
+
+    // 前回のパターンを同じ向き (reverse なら逆向き) に数の分だけ探す
+    fn search_next(&mut self, reverse: bool) -> Result<(), String> {
+        let count = self.take_count();
+        let Some(search) = &self.search else {
+            return Err(NO_PREVIOUS_PATTERN.to_string());
+        };
+        let forward = search.forward != reverse;
+        let mut pos = self.cursor;
+        let mut wrapped = false;
+        for _ in 0..count {
+            match search::find(&self.buffer, &search.regex, pos, forward) {
+                Some((found, w)) => {
+                    pos = found;
+                    wrapped |= w;
+                }
+                None => return Err(format!("E486: Pattern not found: {}", search.pattern)),
+            }
+        }
+        self.message = match (wrapped, forward) {
+            (true, true) => "search hit BOTTOM, continuing at TOP".to_string(),
+            (true, false) => "search hit TOP, continuing at BOTTOM".to_string(),
+            (false, true) => format!("/{}", search.pattern),
+            (false, false) => format!("?{}", search.pattern),
+        };
+        self.hlsearch = true;
+        self.jump_to(pos);
+        Ok(())
+    }
+
+    // カーソルの位置の単語を、単語全体として一致するように探す (* は後ろ、# は前)
+    fn search_word(&mut self, forward: bool) -> Result<(), String> {
+        let (line, col) = self.cursor;
+        let Some((start, word)) = search::word_at(&self.buffer.line(line), col) else {
+            return Err("E348: No string under cursor".to_string());
+        };
+        let pattern = format!(r"\b{}\b", regex::quote(&word));
+        self.cmdline
+            .add_history(if forward { '/' } else { '?' }, &pattern);
+        self.search = Some(Search {
+            regex: search::compile(&pattern)?,
+            pattern,
+            forward,
+        });
+        // 単語の先頭から探して、今の単語には止まらないようにする
+        self.cursor = (line, start);
+        self.search_next(false)
+    }
+
+    // 画面で強調する検索パターン (検索の入力中なら入力中のパターン)
+    pub fn highlight(&self) -> Option<&Regex> {
+        match self.mode {
+            Mode::Command if self.cmdline.is_search() => self.preview.as_ref(),
+            _ if self.hlsearch => self.search.as_ref().map(|search| &search.regex),
+            _ => None,
+        }
+    }
+
+    // 演算子の後に入力した移動コマンドが示す範囲
+    // j / k / g / G は行単位、それ以外はカーソルと移動先の間の文字単位 (移動先の文字は含まない)
+    fn operator_region(&self, op: char, c: char, count: usize) -> Option<Region> {
+        let cursor = self.cursor;
+        let mut target = self.motion(c, count)?;
+        match c {
+            'j' | 'k' if target.0 == cursor.0 => None,
+            'j' | 'k' | 'g' | 'G' => {
+                Some(Region::Line(cursor.0.min(target.0), cursor.0.max(target.0)))
+            }
+            _ => {
+                if c == 'w' {
+                    // 最後に越えた単語が行末にあれば、次の行には進まずにその行末までにする
+                    if target.0 > cursor.0 {
+                        let line = target.0 - 1;
+                        target = (line, self.buffer.line_len(line));
+                    }
+                    // cw は単語の後の空白を含めない
+                    let chars: Vec<char> = self.buffer.line(target.0).chars().collect();
+                    let on_word = self
+                        .buffer
+                        .line(cursor.0)
+                        .chars()
+                        .nth(cursor.1)
+                        .is_some_and(|c| !c.is_whitespace());
+                    if op == 'c' && on_word {
+                        while target > cursor && target.1 > 0 && chars[target.1 - 1].is_whitespace()
+                        {
+                            target.1 -= 1;
+                        }
+                    }
+                }
+                let (start, end) = if cursor <= target {
+                    (cursor, target)
+                } else {
+                    (target, cursor)
+                };
+                (start != end).then_some(Region::Char(start, end))
+            }
+        }
+    }
+
+    // テキストオブジェクトの範囲 (around なら a、そうでなければ i)
+    fn text_object(&self, around: bool, c: char, count: usize) -> Option<Region> {
+        let (buffer, pos) = (&self.buffer, self.cursor);
+        match c {
+            'w' => textobject::word(buffer, pos, around, count.max(1)),
+            '(' | ')' | 'b' => textobject::bracket(buffer, pos, '(', ')', around, count),
+            '[' | ']' => textobject::bracket(buffer, pos, '[', ']', around, count),
+            '{' | '}' | 'B' => textobject::bracket(buffer, pos, '{', '}', around, count),
+            '<' | '>' => textobject::bracket(buffer, pos, '<', '>', around, count),
+            '"' | '\'' | '`' => textobject::quote(buffer, pos, c, around),
+            't' => textobject::tag(buffer, pos, around, count),
+            'p' => textobject::paragraph(buffer, pos.0, around, count),
+            _ => None,
+        }
+    }
+
+    fn apply_operator(&mut self, op: char, region: Region) {
+        match op {
+            'd' => self.delete_region(region),
+            'c' => self.change_region(region),
+            _ => {
+                self.yank_region(region);
+                // 行単位なら列はそのままで最初の行に、文字単位なら範囲の始まりに移動する
+                match region {
+                    Region::Line(first, _) => self.cursor = self.vertical_target(first),
+                    _ => self.cursor = region.start(&self.buffer, self.settings.tabstop),
+                }
+            }
+        }
+    }
+
+    // ビジュアルモードを始める (同じ種類なら終わる、違う種類なら切り替える)
+    fn toggle_visual(&mut self, kind: Kind) {
+        self.mode = match self.mode {
+            Mode::Visual(current) if current == kind => Mode::Normal,
+            Mode::Visual(_) => Mode::Visual(kind),
+            _ => {
+                self.visual_start = self.cursor;
+                Mode::Visual(kind)
+            }
+        };
+    }
+
+    // ビジュアルモードで選択している範囲
+    pub fn selection(&self) -> Option<Region> {
+        match self.mode {
+            Mode::Visual(kind) => Some(Region::between(
+                kind,
+                self.visual_start,
+                self.cursor,
+                &self.buffer,
+                self.settings.tabstop,
+            )),
+            _ => None,
+        }
+    }
+
+    fn visual_command(&mut self, c: char) {
+        let region = self.selection().unwrap();
+        match c {
+            '0'..='9' if c != '0' || self.count > 0 => {
+                self.count = self.count * 10 + c.to_digit(10).unwrap() as usize;
+                return;
+            }
+            // 選択の反対側の端に移動する
+            'o' => {
+                mem::swap(&mut self.cursor, &mut self.visual_start);
+                return;
+            }
+            'v' => return self.toggle_visual(Kind::Char),
+            'V' => return self.toggle_visual(Kind::Line),
+            // 選択した行をコマンドの範囲にする
+            ':' => {
+                let (first, last) = region.lines();
+                self.mode = Mode::Command;
+                self.cmdline
+                    .start_with(':', &format!("{},{}", first + 1, last + 1));
+                return;
+            }
+            'd' | 'x' => self.delete_region(region),
+            'y' => {
+                self.yank_region(region);
+                self.cursor = region.start(&self.buffer, self.settings.tabstop);
+            }
+            'c' | 's' => return self.change_region(region),
+            '>' | '<' => {
+                let (first, last) = region.lines();
+                self.shift_lines(first, last, c == '>');
+            }
+            '~' => self.toggle_case(region),
+            'p' => self.put_region(region),
+            // 矩形の左 (I) か右 (A) の、選択したすべての行に入力する
+            'I' | 'A' if region.kind() == Kind::Block => {
+                let Region::Block(first, last, left, right) = region else {
+                    unreachable!()
+                };
+                let append = c == 'A';
+                let text = self.buffer.line(first);
+                let x = if append { right } else { left };
+                let col = if append {
+                    grapheme::col_at_width_ceil(&text, x, self.settings.tabstop)
+                } else {
+                    grapheme::col_at_width(&text, x, self.settings.tabstop)
+                };
+                self.start_block_insert(first, last, x, append, col);
+                return;
+            }
+            _ => {
+                if let Some(pos) = self.motion(c, self.count) {
+                    self.cursor = pos;
+                    self.count = 0;
+                }
+                return;
+            }
+        }
+        self.mode = Mode::Normal;
+        self.count = 0;
+    }
+
+    // 範囲を削除して保存する
+    fn delete_region(&mut self, region: Region) {
+        let tabstop = self.settings.tabstop;
+        let start = region.start(&self.buffer, tabstop);
+        let text = region.delete(&mut self.buffer, tabstop);
+        self.register.set(text, region.kind());
+        self.cursor = start;
+        self.clamp_cursor();
+        if let Region::Line(..) = region {
+            let line = self.cursor.0;
+            self.cursor.1 = motion::first_non_blank(&self.buffer.line(line));
+        }
+    }
+
+    fn yank_region(&mut self, region: Region) {
+        let text = region.text(&self.buffer, self.settings.tabstop);
+        self.register.set(text, region.kind());
+    }
+
+    // 範囲を削除してインサートモードにする
+    // 行単位なら1行の空行 (インデントは残す) に、矩形なら選択したすべての行に入力する
+    fn change_region(&mut self, region: Region) {
+        let tabstop = self.settings.tabstop;
+        match region {
+            Region::Line(first, last) => {
+                self.yank_region(region);
+                let indent = leading_spaces(&self.buffer.line(first)).chars().count();
+                let end = (last, self.buffer.line_len(last));
+                self.buffer.delete((first, indent), end);
+                self.cursor = (first, indent);
+                self.mode = Mode::Insert;
+            }
+            Region::Block(first, last, left, _) => {
+                self.delete_region(region);
+                let col = grapheme::col_at_width(&self.buffer.line(first), left, tabstop);
+                self.start_block_insert(first, last, left, false, col);
+            }
+            Region::Char(..) => {
+                self.delete_region(region);
+                self.mode = Mode::Insert;
+            }
+        }
+        self.count = 0;
+    }
+
+    fn start_block_insert(
+        &mut self,
+        first: usize,
+        last: usize,
+        x: usize,
+        append: bool,
+        col: usize,
+    ) {
+        self.cursor = (first, col);
+        self.block_insert = Some(BlockInsert {
+            last,
+            x,
+            append,
+            start: self.cursor,
+            len: self.buffer.line_len(first),
+        });
+        self.mode = Mode::Insert;
+        self.count = 0;
+    }
+
+    // インサートモードを終わる (矩形選択からの入力なら、ほかの行にも同じ内容を入れる)
+    fn leave_insert(&mut self) {
+        self.mode = Mode::Normal;
+        let Some(block) = self.block_insert.take() else {
+            return;
+        };
+        let (line, col) = block.start;
+        let grown = self.buffer.line_len(line).saturating_sub(block.len);
+        // 改行した場合や、入力を始めた位置より前を消した場合は何もしない
+        if grown == 0 || self.cursor.0 != line || self.cursor.1 < col {
+            return;
+        }
+        let text = self.buffer.slice((line, col), (line, col + grown));
+        let tabstop = self.settings.tabstop;
+        for l in line + 1..=block.last.min(self.buffer.len_lines() - 1) {
+            let current = self.buffer.line(l);
+            let len = current.chars().count();
+            let width = grapheme::width_to(&current, len, tabstop);
+            if width < block.x {
+                // 短い行は I なら飛ばし、A なら空白で伸ばす
+                if block.append {
+                    let pad = " ".repeat(block.x - width);
+                    self.buffer.insert((l, len), &format!("{}{}", pad, text));
+                }
+            } else {
+                let col = if block.append {
+                    grapheme::col_at_width_ceil(&current, block.x, tabstop)
+                } else {
+                    grapheme::col_at_width(&current, block.x, tabstop)
+                };
+                self.buffer.insert((l, col), &text);
+            }
+        }
+    }
+
+    // first から last の行を右 (>) か左 (<) に tabstop 分ずらす
+    fn shift_lines(&mut self, first: usize, last: usize, right: bool) {
+        let width = self.settings.tabstop;
+        for line in first..=last {
+            let text = self.buffer.line(line);
+            if right {
+                if !text.is_empty() {
+                    self.buffer.insert((line, 0), &" ".repeat(width));
+                }
+            } else {
+                let spaces = leading_spaces(&text).chars().count().min(width);
+                self.buffer.delete((line, 0), (line, spaces));
+            }
+        }
+        self.cursor = (first, motion::first_non_blank(&self.buffer.line(first)));
+    }
+
+    fn toggle_case(&mut self, region: Region) {
+        let tabstop = self.settings.tabstop;
+        for (start, end) in region.spans(&self.buffer, tabstop) {
+            let text = self.buffer.slice(start, end);
+            let toggled: String = text.chars().map(toggle_case).collect();
+            if toggled != text {
+                self.buffer.delete(start, end);
+                self.buffer.insert(start, &toggled);
+            }
+        }
+        self.cursor = region.start(&self.buffer, tabstop);
+    }
+
+    // カーソルの位置に貼り付ける (行単位なら今の行の上に入れる)
+    fn put(&mut self, text: &str, kind: Kind) {
+        let (line, col) = self.cursor;
+        match kind {
+            Kind::Line => {
+                for (i, text) in text.split('\n').enumerate() {
+                    self.buffer.insert_line(line + i, text);
+                }
+            }
+            Kind::Char => self.buffer.insert(self.cursor, text),
+            // 矩形は各行の同じ表示上の列に入れる (行が足りなければ追加し、短い行は空白で伸ばす)
+            Kind::Block => {
+                let tabstop = self.settings.tabstop;
+                let x = grapheme::width_to(&self.buffer.line(line), col, tabstop);
+                for (i, text) in text.split('\n').enumerate() {
+                    let l = line + i;
+                    if l >= self.buffer.len_lines() {
+                        self.buffer.insert_line(l, "");
+                    }
+                    let current = self.buffer.line(l);
+                    let len = current.chars().count();
+                    let width = grapheme::width_to(&current, len, tabstop);
+                    if width < x {
+                        let pad = " ".repeat(x - width);
+                        self.buffer.insert((l, len), &format!("{}{}", pad, text));
+                    } else {
+                        let col = grapheme::col_at_width(&current, x, tabstop);
+                        self.buffer.insert((l, col), text);
+                    }
+                }
+            }
+        }
+    }
+
+    // 選択範囲を貼り付ける内容で置き換える (置き換えた内容を保存する)
+    fn put_region(&mut self, region: Region) {
+        let (text, kind) = self.register.get();
+        self.delete_region(region);
+        let start = region.start(&self.buffer, self.settings.tabstop);
+        self.cursor = start;
+        self.clamp_cursor();
+        match (region, kind) {
+            // 消した行の位置に空の行を作って入れる (全部の行を消したときは残った空の1行に入れる)
+            (Region::Line(first, _), _) => {
+                if self.buffer.len_lines() > 1 || self.buffer.line_len(0) > 0 {
+                    self.buffer.insert_line(first, "");
+                }
+                self.cursor = (first, 0);
+                match kind {
+                    Kind::Block => self.put(&text, kind),
+                    _ => self.put(&text, Kind::Char),
+                }
+            }
+            // 文字単位の位置に行を貼り付けるときは、行を分けて間に入れる
+            (_, Kind::Line) => {
+                let pos = self.cursor;
+                self.buffer.insert(pos, &format!("\n{}\n", text));
+            }
+            _ => self.put(&text, kind),
+        }
+        self.cursor = start;
+    }
+
+    // ex コマンドを1つ実行する (起動オプションの --exec 用、先頭の : は省略できる)
+    pub fn run_command(&mut self, line: &str) -> Result<(), String> {
+        self.message.clear();
+        let result = self.execute(line.strip_prefix(':').unwrap_or(line));
+        self.recorder.commit(&mut self.buffer, self.cursor);
+        self.clamp_cursor();
+        self.sync_syntax();
+        result
+    }
+
+    // :e で arg のファイルを開く (arg が空か今のファイルなら読み込み直す)
+    // ++enc={encoding} を付けると、その文字コードとして読む
+    fn edit(&mut self, arg: &str, bang: bool) -> Result<(), String> {
+        let (encoding, arg) = file_options(arg)?;
+        let reload = arg.is_empty() || arg == self.filename;
+        if reload && self.is_modified() && !bang {
+            return Err(NO_WRITE.to_string());
+        }
+        if reload && self.filename.is_empty() {
+            return Err(NO_FILE_NAME.to_string());
+        }
+        if reload {
+            if !self.filename.is_empty() && !self.swap_found {
+                swap::remove(&self.filepath);
+            }
+            self.load(&self.filename.clone(), encoding);
+        } else if let Some(state) = self.hidden.iter().find(|s| s.filename == arg) {
+            // 開いているファイルならそのバッファに切り替える
+            return self.switch_to(state.number);
+        } else if self.filename.is_empty()
+            && !self.is_modified()
+            && self.windows_showing(self.number) == 1
+        {
+            // ほかのウィンドウに表示していない、何も入っていない名前の無いバッファはそのまま使う
+            self.load(arg, encoding);
+        } else {
+            let current = self.park();
+            self.hidden.push(current);
+            self.last_number += 1;
+            let (state, message) = self.read_buffer(self.last_number, arg, encoding);
+            self.unpark(state);
+            self.message = message;
+        }
+        if self.swap_found {
+            self.ask_swap();
+        }
+        Ok(())
+    }
+
+    // : で入力したコマンドを実行する
+    fn execute(&mut self, line: &str) -> Result<(), String> {
+        let cmd = ex::parse(line)?;
+        let (start, end) = cmd.line_range(self.cursor.0, self.buffer.len_lines())?;
+        if cmd.range.is_some() && !matches!(cmd.name.as_str(), "" | "delete" | "substitute") {
+            return Err("E481: No range allowed".to_string());
+        }
+        match cmd.name.as_str() {
+            // :{n} その行に移動
+            "" => self.jump_to((end, 0)),
+            "write" | "wq" => {
+                let target = if cmd.arg.is_empty() {
+                    self.filename.clone()
+                } else {
+                    cmd.arg.clone()
+                };
+                if target.is_empty() {
+                    return Err(NO_FILE_NAME.to_string());
+                }
+                // 別のファイルを上書きしてしまわないようにする
+                if target != self.filename && path_of(&target)?.exists() && !cmd.bang {
+                    return Err("E13: File exists (add ! to override)".to_string());
+                }
+                if self.readonly && target == self.filename && !cmd.bang {
+                    return Err(READONLY.to_string());
+                }
+                // 読めなかったファイルを空のバッファで上書きしないように、! があっても保存しない
+                if self.unreadable && target == self.filename {
+                    return Err(format!(
+                        "E212: Can't open file for writing: {}: the file could not be read",
+                        target
+                    ));
+                }
+                self.message = self.save(&target)?;
+                if cmd.name == "wq" && (self.windows.len() > 1 || self.tabs.len() > 1) {
+                    self.close_window()?;
+                } else if cmd.name == "wq" {
+                    self.quit_if_saved();
+                }
+            }
+            // ウィンドウかタブページが複数あれば、今のウィンドウだけを閉じる
+            "quit" if self.windows.len() > 1 || self.tabs.len() > 1 => self.close_window()?,
+            "quit" => {
+                if self.is_modified() && !cmd.bang {
+                    return Err(NO_WRITE.to_string());
+                }
+                if let Some(msg) = self.hidden_modified().filter(|_| !cmd.bang) {
+                    return Err(msg);
+                }
+                self.quit = true;
+            }
+            "edit" => self.edit(&cmd.arg, cmd.bang)?,
+            "split" | "vsplit" => {
+                self.split_window(cmd.name == "vsplit");
+                if !cmd.arg.is_empty() && cmd.arg != self.filename {
+                    self.edit(&cmd.arg, cmd.bang)?;
+                }
+            }
+            "close" => self.close_window()?,
+            "tabnew" => self.new_tab(&cmd.arg)?,
+            "tabclose" => self.close_tab()?,
+            "tabnext" | "tabprevious" => {
+                let len = self.tabs.len();
+                let n = cmd.arg.parse::<usize>().ok();
+                let index = match (cmd.name.as_str(), n) {
+                    // :tabnext {n} は n 番目のタブページに移る
+                    ("tabnext", Some(n)) if (1..=len).contains(&n) => n - 1,
+                    ("tabnext", Some(n)) => return Err(format!("E475: Invalid argument: {}", n)),
+                    ("tabnext", None) => (self.tab + 1) % len,
+                    // :tabprevious {n} は n 個前に移る
+                    (_, n) => (self.tab + len - n.unwrap_or(1) % len) % len,
+                };
+                self.enter_tab(index);
+            }
+            "only" => self.only_window(),
+            "resize" => {
+                let n = cmd.arg.trim_start_matches(['+', '-']);
+                let n = if n.is_empty() {
+                    // 数が無ければできるだけ大きくする
+                    self.screen.1 as isize
+                } else {
+                    n.parse::<isize>()
+                        .map_err(|_| format!("E475: Invalid argument: {}", cmd.arg))?
+                };
+                let delta = match cmd.arg.chars().next() {
+                    Some('+') => n,
+                    Some('-') => -n,
+                    _ => n - self.height as isize,
+                };
+                self.layout.resize(self.window, false, delta);
+                self.arrange();
+            }
+            "set" => {
+                if let Some(shown) = self.settings.set(&mut self.format, &cmd.arg)? {
+                    self.message = shown;
+                }
+            }
+            "delete" => {
+                let str = self.buffer.remove_lines(start, end - start + 1);
+                self.register.set(str, Kind::Line);
+                self.jump_to((start, 0));
+            }
+            "nohlsearch" => self.hlsearch = false,
+            "bnext" | "bprevious" => {
+                let step = if cmd.name == "bnext" { 1 } else { -1 };
+                let count = cmd.arg.parse::<usize>().unwrap_or(1);
+                let mut number = self.number;
+                for _ in 0..count {
+                    number = self.cycle_buffer_from(number, step);
+                }
+                self.switch_to(number)?;
+            }
+            "buffer" => {
+                if !cmd.arg.is_empty() {
+                    let number = self.find_buffer(&cmd.arg)?;
+                    self.switch_to(number)?;
+                }
+            }
+            "ls" => self.message = self.list_buffers(),
+            "bdelete" => {
+                let number = if cmd.arg.is_empty() {
+                    self.number
+                } else {
+                    self.find_buffer(&cmd.arg)?
+                };
+                self.delete_buffer(number, cmd.bang)?;
+                // 閉じたバッファを表示していたウィンドウには今のバッファを表示する
+                let others = self
+                    .tabs
+                    .iter_mut()
+                    .flatten()
+                    .flat_map(|tab| &mut tab.windows);
+                for window in self.windows.iter_mut().chain(others) {
+                    if window.number == number {
+                        (window.number, window.cursor, window.upper, window.leftcol) =
+                            (self.number, (0, 0), 0, 0);
+                    }
+                }
+            }
+            "substitute" => {
+                let last = self.search.as_ref().map(|search| search.pattern.clone());
+                let substitute = substitute::parse(&cmd.arg, last.as_deref())?;
+                // 置換したパターンは n で探せるようにする
+                self.search = Some(Search {
+                    pattern: substitute.pattern.clone(),
+                    regex: substitute.regex.clone(),
+                    forward: true,
+                });
+                self.hlsearch = true;
+                let confirm = substitute.confirm;
+                let mut run = Substitution::new(substitute, start, end);
+                if confirm {
+                    return self.ask_substitution(run);
+                }
+                while let Some(m) = run.next(&self.buffer) {
+                    run.replace(&mut self.buffer, &m);
+                }
+                return self.finish_substitution(run);
+            }
+            "earlier" | "later" => {
+                let earlier = cmd.name == "earlier";
+                let pos = match parse_time_travel(&cmd.arg)? {
+                    TimeTravel::Count(n) if earlier => self.recorder.earlier(n, &mut self.buffer),
+                    TimeTravel::Count(n) => self.recorder.later(n, &mut self.buffer),
+                    TimeTravel::Time(d) if earlier => self.recorder.earlier_by(d, &mut self.buffer),
+                    TimeTravel::Time(d) => self.recorder.later_by(d, &mut self.buffer),
+                };
+                if let Some(pos) = pos {
+                    self.jump_to(pos);
+                }
+            }
+            // 解析できても実行する処理の無いコマンド
+            _ => return Err(format!("E492: Not an editor command: {}", line)),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys;
+
+    // テスト用の空のディレクトリ
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("kbeditor-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // text を開いた状態から keys を入力したエディタ
+    fn run(text: &str, input: &str) -> Editor {
+        let mut editor = Editor::new(Settings::new());
+        editor.buffer = Buffer::from(text);
+        editor.recorder = UndoTree::new();
+        for key in keys::parse(input) {
+            editor.handle_key(key);
+        }
+        editor
+    }
+
+    #[test]
+    fn w_moves_to_next_word() {
+        assert_eq!(run("foo bar baz", "w").cursor, (0, 4));
+        assert_eq!(run("foo bar baz", "2w").cursor, (0, 8));
+        assert_eq!(run("foo.bar", "w").cursor, (0, 3));
+        assert_eq!(run("foo.bar", "ww").cursor, (0, 4));
+    }
+
+    #[test]
+    fn w_crosses_lines() {
+        assert_eq!(run("foo\n  bar", "w").cursor, (1, 2));
+        assert_eq!(run("foo\n\nbar", "w").cursor, (1, 0));
+        // 最後の単語の後は行末で止まる
+        assert_eq!(run("foo bar", "www").cursor, (0, 7));
+    }
+
+    #[test]
+    fn b_moves_to_previous_word() {
+        assert_eq!(run("foo bar baz", "$b").cursor, (0, 8));
+        assert_eq!(run("foo bar baz", "$bb").cursor, (0, 4));
+        assert_eq!(run("foo bar", "$b").cursor, (0, 4));
+        assert_eq!(run("foo  bar", "wb").cursor, (0, 0));
+        assert_eq!(run("foo bar\nbaz", "jb").cursor, (0, 4));
+    }
+
+    #[test]
+    fn word_motions_handle_multibyte_text() {
+        assert_eq!(run("日本語 テキスト", "w").cursor, (0, 4));
+        assert_eq!(run("日本語 テキスト", "$b").cursor, (0, 4));
+        assert_eq!(run("cafe\u{301} bar", "w").cursor, (0, 6));
+    }
+
+    #[test]
+    fn g_moves_to_last_or_given_line() {
+        let text = (1..=100)
+            .map(|i| format!("  line {}", i))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let editor = run(&text, "G");
+        assert_eq!(editor.cursor, (99, 2));
+        assert!(editor.upper <= 99 && 99 < editor.upper + editor.height);
+        assert_eq!(run(&text, "42G").cursor, (41, 2));
+        assert_eq!(run(&text, "1000G").cursor, (99, 2));
+        assert_eq!(run(&text, "Gg").cursor, (0, 2));
+    }
+
+    #[test]
+    fn x_and_x_delete_graphemes() {
+        let editor = run("aé\u{301}b", "$hx");
+        assert_eq!(editor.buffer.to_string(), "ab");
+        assert_eq!(editor.cursor, (0, 1));
+        assert_eq!(run("日本語", "X").buffer.to_string(), "本語");
+    }
+
+    #[test]
+    fn insert_and_undo() {
+        let editor = run("foo", "$ibar<Esc>");
+        assert_eq!(editor.buffer.to_string(), "foobar");
+        assert_eq!(editor.cursor, (0, 6));
+        assert_eq!(run("foo", "$ibar<Esc>u").buffer.to_string(), "foo");
+    }
+
+    #[test]
+    fn backspace_joins_lines() {
+        let editor = run("foo\nbar", "ji<BS>");
+        assert_eq!(editor.buffer.to_string(), "foobar");
+        assert_eq!(editor.cursor, (0, 3));
+    }
+
+    #[test]
+    fn ctrl_w_and_ctrl_u_delete_before_cursor() {
+        assert_eq!(run("foo bar  ", "$i<C-w>").buffer.to_string(), "foo ");
+        assert_eq!(run("foo bar", "$hi<C-u>").buffer.to_string(), "r");
+    }
+
+    #[test]
+    fn unbound_ctrl_keys_are_ignored() {
+        // 割り当ての無い Ctrl の組み合わせは文字として入力しない
+        assert_eq!(run("", "i<C-v>x<Esc>").buffer.to_string(), "x");
+        // ノーマルモードでも u (undo) などのコマンドにならない
+        let editor = run("foo bar", "dw<C-u>");
+        assert_eq!(editor.buffer.to_string(), "bar");
+        assert!(editor.mode == Mode::Normal);
+        assert_eq!(run("foo", "<C-x>").buffer.to_string(), "foo");
+    }
+
+    #[test]
+    fn delete_and_paste_lines() {
+        let editor = run("a\nb\nc", "2ddp");
+        assert_eq!(editor.buffer.to_string(), "a\nb\nc");
+        let mut editor = run("a\nb\nc", ":2,3d<CR>");
+        assert_eq!(editor.buffer.to_string(), "a");
+        assert_eq!(editor.register.get(), ("b\nc".to_string(), Kind::Line));
+    }
+
+    #[test]
+    fn operators_take_motions() {
+        assert_eq!(run("foo bar baz", "dw").buffer.to_string(), "bar baz");
+        assert_eq!(run("foo bar baz", "wd$").buffer.to_string(), "foo ");
+        assert_eq!(run("foo bar", "$d^").buffer.to_string(), "");
+        assert_eq!(run("abc", "$dh").buffer.to_string(), "ab");
+        assert_eq!(run("foo bar baz", "$db").buffer.to_string(), "foo bar ");
+        assert_eq!(run("a b c d e f g", "2d3w").buffer.to_string(), "g");
+        // 行末の単語を越えても次の行までは消さない
+        assert_eq!(run("foo\n  bar", "dw").buffer.to_string(), "\n  bar");
+        assert_eq!(run("foo bar", "cwxx<Esc>").buffer.to_string(), "xx bar");
+        assert_eq!(run("foo bar", "cwxx<Esc>u").buffer.to_string(), "foo bar");
+    }
+
+    #[test]
+    fn linewise_operators() {
+        assert_eq!(run("a\nb\nc", "jdG").buffer.to_string(), "a");
+        assert_eq!(run("a\nb\nc", "jdg").buffer.to_string(), "c");
+        assert_eq!(run("a\nb\nc", "jdd").buffer.to_string(), "a\nc");
+        assert_eq!(run("a\nb\nc", "dk").buffer.to_string(), "a\nb\nc");
+        assert_eq!(run("a\nb\nc", "dj").buffer.to_string(), "c");
+        assert_eq!(run("  a\nb", "ccx<Esc>").buffer.to_string(), "  x\nb");
+        let mut editor = run("1\n2\n3\n4\n5", "jy3j");
+        assert_eq!(
+            editor.register.get(),
+            ("2\n3\n4\n5".to_string(), Kind::Line)
+        );
+        assert_eq!(editor.cursor.0, 1);
+        let mut editor = run("1\n2\n3", "2yy");
+        assert_eq!(editor.register.get(), ("1\n2".to_string(), Kind::Line));
+        let mut editor = run("foo bar", "wy$");
+        assert_eq!(editor.register.get(), ("bar".to_string(), Kind::Char));
+    }
+
+    #[test]
+    fn word_objects() {
+        assert_eq!(
+            run("foo bar baz", "wciwx<Esc>").buffer.to_string(),
+            "foo x baz"
+        );
+        assert_eq!(run("foo bar baz", "wdaw").buffer.to_string(), "foo baz");
+        // 後ろに空白が無ければ前の空白を消す
+        assert_eq!(run("foo bar", "$daw").buffer.to_string(), "foo");
+        assert_eq!(run("foo bar baz", "d2aw").buffer.to_string(), "baz");
+    }
+
+    #[test]
+    fn bracket_objects_handle_nesting_and_strings() {
+        let text = "(set i (+ i 1))";
+        assert_eq!(run(text, "di(").buffer.to_string(), "()");
+        assert_eq!(run(text, "wwwdi(").buffer.to_string(), "(set i ())");
+        assert_eq!(run(text, "wwwda(").buffer.to_string(), "(set i )");
+        assert_eq!(run(text, "www2di(").buffer.to_string(), "()");
+        let text = "(print \"a) b\" [1 2])";
+        assert_eq!(run(text, "wdi(").buffer.to_string(), "()");
+        assert_eq!(
+            run(text, "$hhhdi[").buffer.to_string(),
+            "(print \"a) b\" [])"
+        );
+        let text = "(loop\n  (a)\n  (b)\n)";
+        assert_eq!(run(text, "di(").buffer.to_string(), "()");
+        assert_eq!(
+            run(text, "jjwdi(").buffer.to_string(),
+            "(loop\n  (a)\n  ()\n)"
+        );
+        assert_eq!(run("[\n  a\n  b\n]", "jdi[").buffer.to_string(), "[\n]");
+        // カーソルが文字列の中なら、まずその文字列の中の括弧を探す
+        let text = "(f \"(a b)\" x)";
+        assert_eq!(run(text, "wwldi(").buffer.to_string(), "(f \"()\" x)");
+        assert_eq!(run(text, "wdi(").buffer.to_string(), "()");
+        // 閉じていない括弧は飛ばす
+        assert_eq!(run("(a (b c)", "$hdi(").buffer.to_string(), "(a ()");
+    }
+
+    #[test]
+    fn text_objects_scan_long_nesting_once() {
+        // 閉じていない括弧やタグがたくさんあっても、1つずつ対応を探し直さない
+        let n = 20000;
+        let text = format!("{}\nx", "(".repeat(n));
+        assert_eq!(run(&text, "jdi(").buffer.to_string(), text);
+        let text = format!("{}\nx", "<a>".repeat(n));
+        assert_eq!(run(&text, "jdit").buffer.to_string(), text);
+        let text = format!("{}\nx\n{}", "(".repeat(n), ")".repeat(n - 1));
+        let editor = run(&text, &format!("j{}di(", n - 1));
+        assert_eq!(editor.buffer.to_string(), "(()");
+        let text = format!("<b>{}\nx\n{}", "<a>".repeat(n), "</a>".repeat(n));
+        let editor = run(&text, &format!("j{}dat", n));
+        assert_eq!(editor.buffer.to_string(), "<b>");
+    }
+
+    #[test]
+    fn quote_tag_and_paragraph_objects() {
+        assert_eq!(
+            run("say \"hi there\" now", "di\"").buffer.to_string(),
+            "say \"\" now"
+        );
+        assert_eq!(
+            run("say \"hi there\" now", "wwda\"").buffer.to_string(),
+            "say now"
+        );
+        let mut editor = run("x = 'a\\'b' + 1", "yi'");
+        assert_eq!(editor.register.get(), ("a\\'b".to_string(), Kind::Char));
+        assert_eq!(run("x = 'a' + 1", "da'").buffer.to_string(), "x = + 1");
+        let mut editor = run("run `ls -l` now", "wwyi`");
+        assert_eq!(editor.register.get(), ("ls -l".to_string(), Kind::Char));
+        let text = "<div><b>bold</b> text</div>";
+        assert_eq!(run(text, "$hhhhhhhdit").buffer.to_string(), "<div></div>");
+        assert_eq!(run(text, "wwwwdat").buffer.to_string(), "<div> text</div>");
+        // 閉じタグの上でも、入れ子の同じ名前のタグでも組を正しく選ぶ
+        assert_eq!(run(text, "$hhdit").buffer.to_string(), "<div></div>");
+        let text = "<a><a>x</a>y</a>";
+        assert_eq!(run(text, "$hhhhhdit").buffer.to_string(), "<a></a>");
+        let inner = "$hhhhhhhhhh";
+        assert_eq!(
+            run(text, &format!("{}dit", inner)).buffer.to_string(),
+            "<a><a></a>y</a>"
+        );
+        assert_eq!(
+            run(text, &format!("{}2dit", inner)).buffer.to_string(),
+            "<a></a>"
+        );
+        let text = "a\nb\n\nc\n\nd";
+        assert_eq!(run(text, "dip").buffer.to_string(), "\nc\n\nd");
+        assert_eq!(run(text, "dap").buffer.to_string(), "c\n\nd");
+        assert_eq!(run(text, "Gdap").buffer.to_string(), "a\nb\n\nc");
+    }
+
+    #[test]
+    fn search_forward_and_backward() {
+        assert_eq!(run("foo bar foo", "/foo<CR>").cursor, (0, 8));
+        let editor = run("foo bar foo", "/foo<CR>n");
+        assert_eq!(editor.cursor, (0, 0));
+        assert_eq!(editor.message, "search hit BOTTOM, continuing at TOP");
+        assert_eq!(run("a\nfoo\nb\nfoo", "G?foo<CR>").cursor, (1, 0));
+        assert_eq!(run("a\nfoo\nb\nfoo", "/foo<CR>N").cursor, (3, 0));
+        assert_eq!(run("x1 x2 x3 x4", "/x\\d<CR>2n").cursor, (0, 9));
+        assert_eq!(run("bar baz", "/<CR>").message, NO_PREVIOUS_PATTERN);
+    }
+
+    #[test]
+    fn search_errors_keep_cursor() {
+        let editor = run("foo\nbar", "j/zzz<CR>");
+        assert_eq!(editor.cursor, (1, 0));
+        assert_eq!(editor.message, "E486: Pattern not found: zzz");
+        assert!(run("foo", "/(<CR>").message.starts_with("E383"));
+    }
+
+    #[test]
+    fn incremental_search_previews_and_restores() {
+        let editor = run("foo\nbar\nbaz", "/baz");
+        assert_eq!(editor.cursor, (2, 0));
+        assert!(editor.highlight().is_some());
+        let editor = run("foo\nbar\nbaz", "/baz<Esc>");
+        assert_eq!(editor.cursor, (0, 0));
+        assert!(editor.highlight().is_none());
+    }
+
+    #[test]
+    fn star_and_hash_search_whole_words() {
+        assert_eq!(run("foo bar\nfoobar foo", "*").cursor, (1, 7));
+        assert_eq!(run("foo bar\nfoobar foo", "#").cursor, (1, 7));
+        assert_eq!(run("  foo x foo", "*").cursor, (0, 8));
+        assert_eq!(run("", "*").message, "E348: No string under cursor");
+    }
+
+    #[test]
+    fn search_history_and_highlight() {
+        let editor = run("foo", "/foo<CR>:<Up>");
+        assert_eq!(editor.cmdline.text(), "");
+        let editor = run("foo", "/foo<CR>:<Esc>/<Up>");
+        assert_eq!(editor.cmdline.text(), "foo");
+        assert!(run("foo", "/foo<CR>").highlight().is_some());
+        assert!(run("foo", "/foo<CR>:noh<CR>").highlight().is_none());
+        assert!(run("foo", "/foo<CR>:noh<CR>n").highlight().is_some());
+    }
+
+    #[test]
+    fn substitute_with_flags() {
+        let editor = run("foo foo\nfoo", ":%s/foo/bar/<CR>");
+        assert_eq!(editor.buffer.to_string(), "bar foo\nbar");
+        assert_eq!(editor.message, "2 substitutions on 2 lines");
+        assert_eq!(
+            run("foo foo", ":s/foo/bar/g<CR>").buffer.to_string(),
+            "bar bar"
+        );
+        assert_eq!(run("FOO foo", ":s/foo/x/gi<CR>").buffer.to_string(), "x x");
+        assert_eq!(
+            run("a\nx\nx\nx", ":2,3s/x/y/<CR>").buffer.to_string(),
+            "a\ny\ny\nx"
+        );
+        assert_eq!(run("a/b/c", ":s#/#-#g<CR>").buffer.to_string(), "a-b-c");
+        assert_eq!(run("abc", ":s/x*/-/g<CR>").buffer.to_string(), "-a-b-c-");
+        assert_eq!(run("a,b,c", ":s/,/\\r/g<CR>").buffer.to_string(), "a\nb\nc");
+        assert_eq!(run("foo", "/o<CR>:s//0/g<CR>").buffer.to_string(), "f00");
+        assert_eq!(
+            run("foo", ":s/x/y/<CR>").message,
+            "E486: Pattern not found: x"
+        );
+    }
+
+    #[test]
+    fn substitute_with_captures() {
+        assert_eq!(
+            run("hello world", ":s/(\\w+) (\\w+)/\\2 \\1/<CR>")
+                .buffer
+                .to_string(),
+            "world hello"
+        );
+        assert_eq!(run("ab", ":s/(a)(b)/$2$1/<CR>").buffer.to_string(), "ba");
+        assert_eq!(
+            run("foo", ":s/o/<lt>&>/g<CR>").buffer.to_string(),
+            "f<o><o>"
+        );
+        assert_eq!(run("a", ":s/a/\\&$$/<CR>").buffer.to_string(), "&$");
+    }
+
+    #[test]
+    fn substitute_undoes_as_one_step() {
+        assert_eq!(run("a a\na", ":%s/a/b/g<CR>u").buffer.to_string(), "a a\na");
+        assert_eq!(
+            run("a a a", ":s/a/b/gc<CR>yyyu").buffer.to_string(),
+            "a a a"
+        );
+    }
+
+    #[test]
+    fn buffers_keep_their_own_state() {
+        let mut editor = run("one\ntwo", "jix<Esc>:e no_such_file_b<CR>iab<Esc>");
+        assert_eq!(
+            (editor.number, editor.buffer.to_string()),
+            (2, "ab".to_string())
+        );
+        editor.run_command(":bp").unwrap();
+        assert_eq!(editor.number, 1);
+        assert_eq!(editor.buffer.to_string(), "one\nxtwo");
+        assert_eq!(editor.cursor.0, 1);
+        for key in keys::parse("u") {
+            editor.handle_key(key);
+        }
+        assert_eq!(editor.buffer.to_string(), "one\ntwo");
+        editor.run_command(":b no_such").unwrap();
+        assert_eq!(editor.buffer.to_string(), "ab");
+        editor.run_command(":bn").unwrap();
+        assert_eq!(editor.number, 1);
+        assert_eq!(
+            editor.list_buffers(),
+            "  1 %a   \"[No Name]\" line 2\n  2  h + \"no_such_file_b\" line 1"
+        );
+        assert!(editor.run_command(":b 3").unwrap_err().starts_with("E86"));
+    }
+
+    #[test]
+    fn modified_buffers_block_quit_and_delete() {
+        let mut editor = run("one", "ix<Esc>:e no_such_file_c<CR>");
+        assert_eq!(
+            editor.run_command(":q").unwrap_err(),
+            "E162: No write since last change for buffer \"[No Name]\""
+        );
+        assert!(editor.run_command(":bd 1").unwrap_err().starts_with("E89"));
+        editor.run_command(":bd! 1").unwrap();
+        editor.run_command(":bd").unwrap();
+        assert_eq!(editor.number, 3);
+        assert_eq!(editor.buffer.to_string(), "");
+        editor.run_command(":q").unwrap();
+        assert!(editor.quit);
+    }
+
+    #[test]
+    fn split_windows_share_buffers() {
+        let mut editor = run("one\ntwo\nthree", ":sp<CR>jdd");
+        assert_eq!((editor.windows.len(), editor.window), (2, 2));
+        assert_eq!((editor.windows[0].id, editor.height), (2, 11));
+        for key in keys::parse("<C-w>j") {
+            editor.handle_key(key);
+        }
+        assert_eq!((editor.window, editor.cursor), (1, (0, 0)));
+        assert_eq!(editor.buffer.to_string(), "one\nthree");
+        editor.run_command(":e no_such_file_w").unwrap();
+        for key in keys::parse("<C-w>k") {
+            editor.handle_key(key);
+        }
+        assert_eq!((editor.number, editor.cursor), (1, (1, 0)));
+        editor.run_command(":q").unwrap();
+        assert!(!editor.quit);
+        assert_eq!((editor.windows.len(), editor.number), (1, 2));
+        assert!(editor
+            .run_command(":close")
+            .unwrap_err()
+            .starts_with("E444"));
+    }
+
+    #[test]
+    fn vertical_split_and_resize() {
+        let mut editor = run("a", ":vs<CR>3<C-w>>");
+        let widths: Vec<usize> = editor.windows.iter().map(|w| w.rect.width).collect();
+        assert_eq!(widths, [43, 36]);
+        assert_eq!(editor.windows[1].rect.x, 44);
+        for key in keys::parse("<C-w>=<C-w>l") {
+            editor.handle_key(key);
+        }
+        assert_eq!((editor.window, editor.width), (1, 39));
+        editor.run_command(":sp").unwrap();
+        editor.run_command(":res 5").unwrap();
+        assert_eq!(editor.height, 5);
+        assert_eq!(editor.windows[2].rect.y, 6);
+        editor.run_command(":only").unwrap();
+        assert_eq!(
+            (editor.windows.len(), editor.width, editor.height),
+            (1, 80, 24)
+        );
+    }
+
+    #[test]
+    fn tab_pages_hold_layouts() {
+        let mut editor = run("one\ntwo", ":vs<CR>j:tabnew<CR>ixyz<Esc>");
+        assert_eq!(
+            (editor.tabs.len(), editor.tab, editor.windows.len()),
+            (2, 1, 1)
+        );
+        assert_eq!((editor.number, editor.height), (2, 23));
+        assert_eq!(editor.windows[0].rect.y, 1);
+        for key in keys::parse("gT") {
+            editor.handle_key(key);
+        }
+        assert_eq!((editor.tab, editor.windows.len()), (0, 2));
+        assert_eq!((editor.number, editor.cursor), (1, (1, 0)));
+        for key in keys::parse("gt") {
+            editor.handle_key(key);
+        }
+        assert_eq!(editor.buffer.to_string(), "xyz");
+        editor.run_command(":q").unwrap();
+        assert_eq!((editor.tabs.len(), editor.tab, editor.height), (1, 0, 23));
+        assert_eq!(editor.windows[0].rect.y, 0);
+        assert!(!editor.quit);
+        assert!(editor
+            .run_command(":tabclose")
+            .unwrap_err()
+            .starts_with("E784"));
+    }
+
+    #[test]
+    fn substitute_asks_for_confirmation() {
+        let editor = run("a a a", ":s/a/b/gc<CR>");
+        assert_eq!(editor.message, "replace with b (y/n/a/q/l)?");
+        assert_eq!(run("a a a", ":s/a/b/gc<CR>yny").buffer.to_string(), "b a b");
+        assert_eq!(run("a a a", ":s/a/b/gc<CR>na").buffer.to_string(), "a b b");
+        assert_eq!(run("a a a", ":s/a/b/gc<CR>yq").buffer.to_string(), "b a a");
+        assert_eq!(run("a a a", ":s/a/b/gc<CR>l").buffer.to_string(), "b a a");
+        let editor = run("a\na", ":%s/a/b/c<CR>ny");
+        assert_eq!(editor.buffer.to_string(), "a\nb");
+        assert_eq!(editor.message, "1 substitution on 1 line");
+    }
+
+    #[test]
+    fn visual_delete_and_yank() {
+        let mut editor = run("hello world", "vlld");
+        assert_eq!(editor.buffer.to_string(), "lo world");
+        assert_eq!(editor.register.get(), ("hel".to_string(), Kind::Char));
+        assert_eq!(run("ab\ncd", "vjd").buffer.to_string(), "d");
+        let mut editor = run("a\nb\nc", "jVd");
+        assert_eq!(editor.buffer.to_string(), "a\nc");
+        assert_eq!(editor.register.get(), ("b".to_string(), Kind::Line));
+        assert_eq!(run("foo bar", "vlly$p").buffer.to_string(), "foo barfoo");
+        assert_eq!(run("a\nb\nc", "Vj:d<CR>").buffer.to_string(), "c");
+    }
+
+    #[test]
+    fn visual_put_replaces_selection() {
+        let mut editor = run("foo bar", "vllywvllp");
+        assert_eq!(editor.buffer.to_string(), "foo foo");
+        assert_eq!(editor.register.get(), ("bar".to_string(), Kind::Char));
+        assert_eq!(run("a\nb\nc", "yyjVp").buffer.to_string(), "a\na\nc");
+    }
+
+    #[test]
+    fn visual_block_operations() {
+        assert_eq!(
+            run("abc\ndef\nghi", "l<C-v>jjd").buffer.to_string(),
+            "ac\ndf\ngi"
+        );
+        assert_eq!(
+            run("abc\ndef\nghi", "<C-v>jjI# <Esc>").buffer.to_string(),
+            "# abc\n# def\n# ghi"
+        );
+        assert_eq!(
+            run("ab\na\nabc", "l<C-v>jjA!<Esc>").buffer.to_string(),
+            "ab!\na !\nab!c"
+        );
+        assert_eq!(
+            run("abc\ndef", "<C-v>jlcX<Esc>").buffer.to_string(),
+            "Xc\nXf"
+        );
+        // 入力した全部の行が1回で元に戻る
+        assert_eq!(
+            run("abc\ndef\nghi", "<C-v>jjI# <Esc>u").buffer.to_string(),
+            "abc\ndef\nghi"
+        );
+    }
+
+    #[test]
+    fn visual_case_and_shift() {
+        assert_eq!(run("Hello", "v$~").buffer.to_string(), "hELLO");
+        let editor = run("a\n\nb", "Vjj>");
+        let indent = " ".repeat(editor.settings.tabstop);
+        assert_eq!(
+            editor.buffer.to_string(),
+            format!("{}a\n\n{}b", indent, indent)
+        );
+        assert_eq!(run("a\n\nb", "Vjj>Vjj<").buffer.to_string(), "a\n\nb");
+    }
+
+    #[test]
+    fn leftover_swap_survives_edit_and_quit() {
+        let dir = temp_dir("swap");
+        let file = dir.join("a.txt");
+        std::fs::write(&file, "saved\n").unwrap();
+        swap::write(&file, &Buffer::from("unsaved"), (0, 0)).unwrap();
+        let open = format!(":e {}<CR>", file.display());
+        let mut editor = run("", &open);
+        assert!(editor.message.starts_with("E325"));
+        // そのまま編集して終了しても、スワップファイルは書き換えも削除もしない
+        for key in keys::parse("eix<Esc>") {
+            editor.handle_key(key);
+        }
+        assert_eq!(editor.buffer.to_string(), "xsaved");
+        editor.keys_since_swap = SWAP_KEYS;
+        editor.tick();
+        editor.run_command("q!").unwrap();
+        editor.close();
+        assert_eq!(swap::read(&file).unwrap().text, "unsaved");
+        // r で復元する (u でファイルの内容に戻せる)
+        let editor = run("", &format!("{}r", open));
+        assert_eq!(editor.buffer.to_string(), "unsaved");
+        assert!(editor.is_modified());
+        // d で削除する
+        run("", &format!("{}d", open));
+        assert!(swap::read(&file).is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn swaps_of_every_argument_are_checked() {
+        let dir = temp_dir("swaps");
+        let (a, b) = (dir.join("a.txt"), dir.join("b.txt"));
+        std::fs::write(&a, "a\n").unwrap();
+        std::fs::write(&b, "b\n").unwrap();
+        swap::write(&b, &Buffer::from("b2"), (0, 0)).unwrap();
+        let open = || {
+            let mut editor = Editor::open(a.to_str().unwrap(), Settings::new());
+            editor.add_buffers(&[b.to_str().unwrap().to_string()]);
+            editor
+        };
+        // 裏のバッファのスワップファイルも尋ねる (そのまま開けば残しておく)
+        let mut editor = open();
+        let mut asked = Vec::new();
+        let go_on = editor.check_swaps(|path, text| {
+            asked.push((path.to_path_buf(), text.to_string()));
+            Ok(Recovery::Ignore)
+        });
+        assert!(go_on.unwrap());
+        assert_eq!(asked, [(b.clone(), "b".to_string())]);
+        assert_eq!(editor.filepath, a);
+        editor.run_command("bn").unwrap();
+        for key in keys::parse("ix<Esc>") {
+            editor.handle_key(key);
+        }
+        editor.keys_since_swap = SWAP_KEYS;
+        editor.tick();
+        editor.close();
+        assert_eq!(swap::read(&b).unwrap().text, "b2");
+        // 裏のバッファにも復元できる
+        let mut editor = open();
+        let go_on = editor.check_swaps(|path, _| Ok(Recovery::Recover(swap::read(path).unwrap())));
+        assert!(go_on.unwrap());
+        editor.run_command("bn").unwrap();
+        assert_eq!(editor.buffer.to_string(), "b2");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn changing_file_format_is_a_modification() {
+        let editor = run("a", ":set ff=dos<CR>:q<CR>");
+        assert!(editor.is_modified());
+        assert!(!editor.quit);
+        assert_eq!(editor.message, NO_WRITE);
+        assert!(run("a", ":set noeol<CR>").is_modified());
+        // 元の形式に戻せば変更は無い
+        assert!(!run("a", ":set fenc=latin1<CR>:set fenc=utf-8<CR>").is_modified());
+    }
+
+    #[test]
+    fn unreadable_files_are_never_overwritten() {
+        let dir = temp_dir("unreadable");
+        let file = dir.join("a.txt");
+        std::fs::write(&file, "secret\n").unwrap();
+        let mut editor = run("", &format!(":e {}<CR>", file.display()));
+        // 読めなかったときと同じ状態にする (root では読めないファイルを作れない)
+        editor.buffer = Buffer::default();
+        editor.readonly = true;
+        editor.unreadable = true;
+        assert!(editor.run_command("w!").unwrap_err().starts_with("E212"));
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "secret\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_write_keeps_buffer_unnamed() {
+        let mut editor = run("text", "");
+        assert!(editor.run_command("w /nonexistent-dir/a.txt").is_err());
+        assert!(editor.filename.is_empty());
+        // 保存できたときはそのファイル名になる
+        let dir = temp_dir("unnamed");
+        let file = dir.join("a.txt");
+        editor
+            .run_command(&format!("w {}", file.display()))
+            .unwrap();
+        assert_eq!(editor.filename, file.display().to_string());
+        assert!(!editor.is_modified());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn long_lines_scroll_sideways() {
+        use crate::render;
+        use crate::screen::Screen;
+        let line = format!("{}END", "x".repeat(197));
+        let cursor = |editor: &Editor| {
+            let mut screen = Screen::new(0, 0, Default::default());
+            let mut out = Vec::new();
+            render::render(&mut out, editor, &mut screen).unwrap();
+            (screen.cursor, String::from_utf8(out).unwrap())
+        };
+        // 80 列のウィンドウで行末 (最後の文字の後) に移ると、そこが見えるようにずらす
+        let editor = run(&line, "$");
+        let text_width = editor.width - CURSOR_START_POS;
+        assert_eq!(editor.leftcol, 201 - text_width);
+        let ((x, y), out) = cursor(&editor);
+        assert_eq!((x, y), (79, 0));
+        assert!(out.contains("END"));
+        // 行頭に戻れば元に戻る
+        assert_eq!(run(&line, "$^").leftcol, 0);
+        // 左右に分けたウィンドウでも、カーソルはそのウィンドウの中にある
+        let editor = run(&line, ":vsp<CR>$");
+        let rect = editor.current_window().rect;
+        let ((x, _), _) = cursor(&editor);
+        assert!(rect.x <= x && x < rect.x + rect.width);
+        // ほかのウィンドウは左端のまま
+        assert!(editor
+            .windows
+            .iter()
+            .any(|w| w.id != editor.window && w.leftcol == 0));
+    }
+
+    #[test]
+    fn vertical_moves_keep_the_display_column() {
+        let mut editor = run("あいう\nabcdef\n\tx\nlast", "ll");
+        editor.height = 2;
+        for (key, cursor, upper) in [
+            ('j', (1, 4), 0),
+            // タブの途中にはならない
+            ('j', (2, 1), 1),
+            ('j', (3, 4), 2),
+            // 最終行より先には行かない
+            ('j', (3, 4), 2),
+            ('k', (2, 1), 2),
+            ('k', (1, 4), 1),
+            ('k', (0, 2), 0),
+        ] {
+            editor.handle_key(KeyEvent::from(KeyCode::Char(key)));
+            assert_eq!((editor.cursor, editor.upper), (cursor, upper));
+        }
+    }
+
+    #[test]
+    fn ctrl_w_deletes_the_previous_word() {
+        let line = "let foo_bar =  (x";
+        assert_eq!(motion::word_start_before(line, 17), 16);
+        assert_eq!(motion::word_start_before(line, 16), 15);
+        // 空白は前の単語と一緒に消す
+        assert_eq!(motion::word_start_before(line, 15), 12);
+        assert_eq!(motion::word_start_before(line, 13), 12);
+        assert_eq!(motion::word_start_before(line, 11), 4);
+        assert_eq!(motion::word_start_before(line, 4), 0);
+        assert_eq!(motion::word_start_before(line, 0), 0);
+    }
+
+    #[test]
+    fn missing_files_open_as_new_buffers() {
+        use std::fs;
+        let dir = env::temp_dir().join(format!("kbeditor-open-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("new.txt");
+        let (buffer, _, access, message) = open_file("new.txt", &file, None);
+        assert_eq!(buffer.to_string(), "");
+        assert!(access == Access::Writable);
+        assert_eq!(message, "\"new.txt\" [New]");
+        // 開いただけではファイルを作らない
+        assert!(!file.exists());
+        fs::write(&file, "a\nb\n").unwrap();
+        let (buffer, _, access, message) = open_file("new.txt", &file, None);
+        assert_eq!(buffer.to_string(), "a\nb");
+        assert!(access == Access::Writable);
+        assert_eq!(message, "\"new.txt\" 2L");
+        // ディレクトリは読めないので、上書きもしない
+        let (_, _, access, message) = open_file("dir", &dir, None);
+        assert!(access == Access::Unreadable);
+        assert!(message.starts_with("E484: "));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn e_rereads_with_given_encoding() {
+        let dir = temp_dir("enc");
+        let file = dir.join("a.txt");
+        std::fs::write(&file, "caf\u{e9}\n").unwrap();
+        let mut editor = Editor::open(file.to_str().unwrap(), Settings::new());
+        assert_eq!(editor.buffer.to_string(), "caf\u{e9}");
+        editor.run_command("e ++enc=latin1").unwrap();
+        assert_eq!(editor.buffer.to_string(), "caf\u{c3}\u{a9}");
+        assert!(editor.format.encoding == Encoding::Latin1);
+        assert!(!editor.is_modified());
+        // 指定した文字コードで保存する
+        editor.run_command("w").unwrap();
+        assert_eq!(std::fs::read(&file).unwrap(), "caf\u{e9}\n".as_bytes());
+        assert_eq!(
+            editor.run_command("e ++enc=ebcdic").unwrap_err(),
+            "E474: Invalid argument: ++enc=ebcdic"
+        );
+        assert!(editor.run_command("e ++ff=dos").is_err());
+        // その文字コードとして読めなければ、上書きしないように読み込み専用で開く
+        std::fs::write(&file, b"\x82").unwrap();
+        editor.run_command("e ++enc=sjis").unwrap();
+        assert!(editor.message.starts_with("E484: "));
+        assert!(editor.readonly);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn non_utf8_paths_can_be_opened() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let dir = temp_dir("utf8").join(OsStr::from_bytes(b"dir\xff"));
+        std::fs::create_dir(&dir).unwrap();
+        let file = dir.join("a.txt");
+        std::fs::write(&file, "text\n").unwrap();
+        let (buffer, _, access, message) = open_file("a.txt", &file, None);
+        assert_eq!(buffer.to_string(), "text");
+        assert!(access == Access::Writable);
+        assert_eq!(message, "\"a.txt\" 1L");
+        std::fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn unknown_working_directory_is_an_error() {
+        let gone = || Err(io::Error::from(io::ErrorKind::NotFound));
+        assert!(resolve(gone(), "a.txt").unwrap_err().starts_with("E187: "));
+        // 絶対パスなら作業ディレクトリは要らない
+        let absolute = env::temp_dir().join("a.txt");
+        let name = absolute.to_string_lossy();
+        assert_eq!(resolve(gone(), &name), Ok(absolute.clone()));
+        assert_eq!(
+            resolve(Ok(PathBuf::from("/work")), "b/c.txt"),
+            Ok(PathBuf::from("/work/b/c.txt"))
+        );
+    }
+}
@@ -0,0 +1,279 @@
+// : で入力するコマンド (ex コマンド) の解析
+
+// 行の指定 (1 始まりの行番号, 現在行 ".", 最終行 "$") と、それに足す行数
+#[derive(Clone, Copy)]
+pub enum Base {
+    Number(usize),
+    Current,
+    Last,
+}
+
+#[derive(Clone, Copy)]
+pub struct Address {
+    base: Base,
+    offset: isize,
+}
+
+impl Address {
+    // 0 始まりの行番号に変換する (current は現在行, len は行数)
+    pub fn resolve(&self, current: usize, len: usize) -> Result<usize, String> {
+        let line = match self.base {
+            Base::Number(n) => n as isize,
+            Base::Current => current as isize + 1,
+            Base::Last => len as isize,
+        } + self.offset;
+        if line < 0 || line > len as isize {
+            return Err("E16: Invalid range".to_string());
+        }
+        // :0 は1行目として扱う
+        Ok((line.max(1) - 1) as usize)
+    }
+}
+
+pub struct ExCommand {
+    pub range: Option<(Address, Address)>,
+    pub name: String, // 省略形を展開した正式な名前 (範囲だけなら空)
+    pub bang: bool,
+    pub arg: String,
+}
+
+impl ExCommand {
+    // 範囲を 0 始まりの (最初の行, 最後の行) にする (範囲が無ければ現在行)
+    pub fn line_range(&self, current: usize, len: usize) -> Result<(usize, usize), String> {
+        match self.range {
+            Some((start, end)) => {
+                let (start, end) = (start.resolve(current, len)?, end.resolve(current, len)?);
+                if start > end {
+                    return Err("E493: Backwards range given".to_string());
+                }
+                Ok((start, end))
+            }
+            None => Ok((current, current)),
+        }
+    }
+}
+
+// (最短の省略形, 正式な名前)
+const COMMANDS: [(&str, &str); 24] = [
+    ("w", "write"),
+    ("q", "quit"),
+    ("wq", "wq"),
+    ("e", "edit"),
+    ("se", "set"),
+    ("d", "delete"),
+    ("ea", "earlier"),
+    ("lat", "later"),
+    ("noh", "nohlsearch"),
+    ("s", "substitute"),
+    ("bn", "bnext"),
+    ("bp", "bprevious"),
+    ("b", "buffer"),
+    ("ls", "ls"),
+    ("bd", "bdelete"),
+    ("sp", "split"),
+    ("vs", "vsplit"),
+    ("clo", "close"),
+    ("on", "only"),
+    ("res", "resize"),
+    ("tabnew", "tabnew"),
+    ("tabc", "tabclose"),
+    ("tabn", "tabnext"),
+    ("tabp", "tabprevious"),
+];
+
+fn command_name(name: &str) -> Option<&'static str> {
+    COMMANDS
+        .iter()
+        .find(|(short, full)| name.starts_with(short) && full.starts_with(name))
+        .map(|(_, full)| *full)
+}
+
+fn parse_number(chars: &[char], i: &mut usize) -> Option<usize> {
+    let start = *i;
+    while *i < chars.len() && chars[*i].is_ascii_digit() {
+        *i += 1;
+    }
+    chars[start..*i].iter().collect::<String>().parse().ok()
+}
+
+fn parse_address(chars: &[char], i: &mut usize) -> Option<Address> {
+    let base = match chars.get(*i) {
+        Some('.') => {
+            *i += 1;
+            Some(Base::Current)
+        }
+        Some('$') => {
+            *i += 1;
+            Some(Base::Last)
+        }
+        Some(c) if c.is_ascii_digit() => Some(Base::Number(parse_number(chars, i)?)),
+        _ => None,
+    };
+    // .+3 や $-1 のような相対指定 (+ だけなら現在行からの相対)
+    let mut offset = 0;
+    while let Some(sign @ ('+' | '-')) = chars.get(*i) {
+        let sign = if *sign == '+' { 1 } else { -1 };
+        *i += 1;
+        let n = parse_number(chars, i).unwrap_or(1);
+        offset += sign * n as isize;
+    }
+    if base.is_none() && offset == 0 {
+        return None;
+    }
+    Some(Address {
+        base: base.unwrap_or(Base::Current),
+        offset,
+    })
+}
+
+pub fn parse(line: &str) -> Result<ExCommand, String> {
+    let chars: Vec<char> = line.trim_start().chars().collect();
+    let mut i = 0;
+
+    let range = if chars.first() == Some(&'%') {
+        i += 1;
+        Some((
+            Address {
+                base: Base::Number(1),
+                offset: 0,
+            },
+            Address {
+                base: Base::Last,
+                offset: 0,
+            },
+        ))
+    } else {
+        match parse_address(&chars, &mut i) {
+            Some(start) => {
+                if chars.get(i) == Some(&',') {
+                    i += 1;
+                    match parse_address(&chars, &mut i) {
+                        Some(end) => Some((start, end)),
+                        None => return Err("E14: Invalid address".to_string()),
+                    }
+                } else {
+                    Some((start, start))
+                }
+            }
+            None => None,
+        }
+    };
+
+    let start = i;
+    while i < chars.len() && chars[i].is_ascii_alphabetic() {
+        i += 1;
+    }
+    let name: String = chars[start..i].iter().collect();
+    let bang = chars.get(i) == Some(&'!');
+    if bang {
+        i += 1;
+    }
+    let arg = chars[i..].iter().collect::<String>().trim().to_string();
+
+    let name = if name.is_empty() {
+        if !arg.is_empty() || bang {
+            return Err(format!("E492: Not an editor command: {}", line));
+        }
+        String::new()
+    } else {
+        match command_name(&name) {
+            Some(full) => full.to_string(),
+            None => return Err(format!("E492: Not an editor command: {}", line)),
+        }
+    };
+    Ok(ExCommand {
+        range,
+        name,
+        bang,
+        arg,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 10 行あって 5 行目 (0 始まりで 4) にいるときの範囲
+    fn range(line: &str) -> Result<(usize, usize), String> {
+        parse(line)?.line_range(4, 10)
+    }
+
+    #[test]
+    fn addresses_resolve_to_lines() {
+        assert_eq!(range("3"), Ok((2, 2)));
+        assert_eq!(range("."), Ok((4, 4)));
+        assert_eq!(range("$"), Ok((9, 9)));
+        assert_eq!(range("%"), Ok((0, 9)));
+        assert_eq!(range("2,4"), Ok((1, 3)));
+        assert_eq!(range(".,$"), Ok((4, 9)));
+        // + だけなら現在行からの相対、数が無ければ 1
+        assert_eq!(range("+2"), Ok((6, 6)));
+        assert_eq!(range("-"), Ok((3, 3)));
+        assert_eq!(range(".+1,$-2"), Ok((5, 7)));
+        assert_eq!(range("1++"), Ok((2, 2)));
+        // :0 は1行目
+        assert_eq!(range("0"), Ok((0, 0)));
+        // 範囲が無ければ現在行
+        assert_eq!(range("d"), Ok((4, 4)));
+        assert!(parse("d").unwrap().range.is_none());
+    }
+
+    #[test]
+    fn invalid_ranges_are_errors() {
+        assert_eq!(range("11"), Err("E16: Invalid range".to_string()));
+        assert_eq!(range("$+1"), Err("E16: Invalid range".to_string()));
+        assert_eq!(range("1-2"), Err("E16: Invalid range".to_string()));
+        assert_eq!(range("4,2"), Err("E493: Backwards range given".to_string()));
+        assert_eq!(range("$,."), Err("E493: Backwards range given".to_string()));
+        assert!(matches!(parse("1,d"), Err(e) if e == "E14: Invalid address"));
+        assert!(matches!(parse("%,3d"), Err(e) if e.starts_with("E492")));
+    }
+
+    #[test]
+    fn names_are_expanded_from_abbreviations() {
+        for (line, name) in [
+            ("w", "write"),
+            ("wri", "write"),
+            ("write", "write"),
+            ("q", "quit"),
+            ("wq", "wq"),
+            ("se", "set"),
+            ("b", "buffer"),
+            ("bn", "bnext"),
+            ("bd", "bdelete"),
+            ("noh", "nohlsearch"),
+            ("tabc", "tabclose"),
+            ("s", "substitute"),
+        ] {
+            assert_eq!(parse(line).unwrap().name, name, "{}", line);
+        }
+        // 最短の省略形より短いものや、正式な名前より長いものは知らないコマンド
+        for line in ["t", "writes", "ta", "no", "foo"] {
+            assert_eq!(
+                parse(line).err(),
+                Some(format!("E492: Not an editor command: {}", line))
+            );
+        }
+        // 短い省略形を持つほかのコマンドの名前には化けない
+        assert_eq!(parse("bdelete").unwrap().name, "bdelete");
+    }
+
+    #[test]
+    fn bang_and_argument_follow_the_name() {
+        let cmd = parse("  w! foo.txt ").unwrap();
+        assert_eq!(
+            (cmd.name.as_str(), cmd.bang, cmd.arg.as_str()),
+            ("write", true, "foo.txt")
+        );
+        let cmd = parse("%s/a/b/g").unwrap();
+        assert_eq!(
+            (cmd.name.as_str(), cmd.arg.as_str()),
+            ("substitute", "/a/b/g")
+        );
+        let cmd = parse("5").unwrap();
+        assert!(cmd.name.is_empty() && !cmd.bang && cmd.arg.is_empty());
+        // 名前が無いのに ! や引数があるのはエラー
+        assert!(matches!(parse("3!"), Err(e) if e.starts_with("E492")));
+        assert!(matches!(parse("3 x"), Err(e) if e.starts_with("E492")));
+    }
+}
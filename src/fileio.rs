@@ -0,0 +1,443 @@
+use crate::buffer::Buffer;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+// 改行コード (vim の fileformat)
+#[derive(Clone, Copy, PartialEq)]
+pub enum LineEnding {
+    Unix, // LF
+    Dos,  // CRLF
+    Mac,  // CR
+}
+
+impl LineEnding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Unix => "\n",
+            LineEnding::Dos => "\r\n",
+            LineEnding::Mac => "\r",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LineEnding::Unix => "unix",
+            LineEnding::Dos => "dos",
+            LineEnding::Mac => "mac",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "unix" => Some(LineEnding::Unix),
+            "dos" => Some(LineEnding::Dos),
+            "mac" => Some(LineEnding::Mac),
+            _ => None,
+        }
+    }
+}
+
+// 文字コード (vim の fileencoding)
+#[derive(Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Utf8,
+    ShiftJis,
+    Latin1,
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "utf-8",
+            Encoding::ShiftJis => "shift_jis",
+            Encoding::Latin1 => "latin1",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "utf-8" | "utf8" => Some(Encoding::Utf8),
+            "shift_jis" | "sjis" | "cp932" => Some(Encoding::ShiftJis),
+            "latin1" | "iso-8859-1" => Some(Encoding::Latin1),
+            _ => None,
+        }
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Option<String> {
+        match self {
+            Encoding::Utf8 => String::from_utf8(bytes.to_vec()).ok(),
+            Encoding::ShiftJis => encoding_rs::SHIFT_JIS
+                .decode_without_bom_handling_and_without_replacement(bytes)
+                .map(|s| s.into_owned()),
+            Encoding::Latin1 => Some(bytes.iter().map(|b| *b as char).collect()),
+        }
+    }
+
+    // 変換できない文字があれば None
+    fn encode(&self, text: &str) -> Option<Vec<u8>> {
+        match self {
+            Encoding::Utf8 => Some(text.as_bytes().to_vec()),
+            Encoding::ShiftJis => {
+                let (bytes, _, had_errors) = encoding_rs::SHIFT_JIS.encode(text);
+                (!had_errors).then(|| bytes.into_owned())
+            }
+            Encoding::Latin1 => text.chars().map(|c| u8::try_from(c).ok()).collect(),
+        }
+    }
+}
+
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+
+// 読み込んだときのファイルの形式 (保存するときも同じ形式で書く)
+#[derive(Clone, Copy, PartialEq)]
+pub struct FileFormat {
+    pub line_ending: LineEnding,
+    pub encoding: Encoding,
+    pub bom: bool,           // UTF-8 の BOM が付いている
+    pub final_newline: bool, // 最後の行が改行で終わっている
+}
+
+impl FileFormat {
+    pub fn new() -> Self {
+        Self {
+            line_ending: LineEnding::Unix,
+            encoding: Encoding::Utf8,
+            bom: false,
+            final_newline: true,
+        }
+    }
+
+    // 標準と違うところだけを [dos][shift_jis] のように表示する
+    pub fn describe(&self) -> String {
+        let mut s = String::new();
+        if self.line_ending != LineEnding::Unix {
+            s += &format!("[{}]", self.line_ending.name());
+        }
+        if self.encoding != Encoding::Utf8 {
+            s += &format!("[{}]", self.encoding.name());
+        }
+        if self.bom {
+            s += "[BOM]";
+        }
+        if !self.final_newline {
+            s += "[noeol]";
+        }
+        s
+    }
+}
+
+// encoding を指定しなければ内容から判別する (指定した文字コードで読めなければエラー)
+pub fn read_file(path: &Path, encoding: Option<Encoding>) -> io::Result<(Buffer, FileFormat)> {
+    let bytes = fs::read(path)?;
+    match encoding {
+        None => Ok(decode(&bytes)),
+        Some(encoding) => decode_as(&bytes, encoding).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("conversion from {} failed", encoding.name()),
+            )
+        }),
+    }
+}
+
+// ファイルを書き込み用に開けるか (中身は変更しない)
+pub fn is_writable(path: &Path) -> bool {
+    OpenOptions::new().write(true).open(path).is_ok()
+}
+
+// ファイルの内容から形式を判別して、改行を \n にそろえたバッファにする
+pub fn decode(bytes: &[u8]) -> (Buffer, FileFormat) {
+    // UTF-8 でなければ Shift_JIS、それも違えば Latin-1 として読む
+    let body = bytes.strip_prefix(UTF8_BOM).unwrap_or(bytes);
+    let encoding = if Encoding::Utf8.decode(body).is_some() {
+        Encoding::Utf8
+    } else if is_shift_jis(bytes) {
+        Encoding::ShiftJis
+    } else {
+        Encoding::Latin1
+    };
+    decode_as(bytes, encoding).unwrap()
+}
+
+// Shift_JIS として読めて、2バイト文字 (先頭バイトと2バイト目の組) を含んでいるか
+// 0xA1..=0xDF だけなら半角カナとして読めてしまうので、Latin-1 のファイルと区別できない
+fn is_shift_jis(bytes: &[u8]) -> bool {
+    Encoding::ShiftJis.decode(bytes).is_some()
+        && bytes.iter().any(|b| matches!(b, 0x81..=0x9f | 0xe0..=0xfc))
+}
+
+// encoding の文字コードとして読む (読めなければ None)
+pub fn decode_as(bytes: &[u8], encoding: Encoding) -> Option<(Buffer, FileFormat)> {
+    let mut format = FileFormat::new();
+    // BOM は UTF-8 のときだけ取り除く
+    let bytes = match bytes.strip_prefix(UTF8_BOM) {
+        Some(rest) if encoding == Encoding::Utf8 => {
+            format.bom = true;
+            rest
+        }
+        _ => bytes,
+    };
+    let text = encoding.decode(bytes)?;
+    format.encoding = encoding;
+    // すべての LF の前に CR があるときだけ dos にする (混ざっていれば unix にして、CR は文字として残す)
+    let lf = text.matches('\n').count();
+    format.line_ending = if lf > 0 && text.matches("\r\n").count() == lf {
+        LineEnding::Dos
+    } else if lf == 0 && text.contains('\r') {
+        LineEnding::Mac
+    } else {
+        LineEnding::Unix
+    };
+    let ending = format.line_ending.as_str();
+    // 空のファイルは改行を付けずに保存する (改行だけのファイルとは区別する)
+    let text = match text.strip_suffix(ending) {
+        Some(rest) => rest,
+        None => {
+            format.final_newline = false;
+            &text
+        }
+    };
+    let text = match format.line_ending {
+        LineEnding::Unix => text.to_string(),
+        _ => text.replace(ending, "\n"),
+    };
+    Some((Buffer::from(text.as_str()), format))
+}
+
+// バッファを format の形式のバイト列にする
+pub fn encode(buf: &Buffer, format: &FileFormat) -> io::Result<Vec<u8>> {
+    let mut text = buf.to_string();
+    if format.line_ending != LineEnding::Unix {
+        text = text.replace('\n', format.line_ending.as_str());
+    }
+    // 空の1行だけのバッファも、eol なら改行を書く
+    if format.final_newline {
+        text += format.line_ending.as_str();
+    }
+    let mut bytes = Vec::new();
+    if format.bom && format.encoding == Encoding::Utf8 {
+        bytes.extend_from_slice(UTF8_BOM);
+    }
+    match format.encoding.encode(&text) {
+        Some(encoded) => bytes.extend(encoded),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("conversion to {} failed", format.encoding.name()),
+            ))
+        }
+    }
+    Ok(bytes)
+}
+
+// 同じディレクトリに隠しファイルとして作るファイルのパス (.名前.suffix)
+pub fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.{}", name, suffix))
+}
+
+// 途中で失敗しても元のファイルが壊れないように、同じディレクトリの一時ファイルに
+// 書き込んでから置き換える
+// backup なら元のファイルを 名前~ に残す
+pub fn write_file(
+    filename: &str,
+    buf: &Buffer,
+    format: &FileFormat,
+    backup: bool,
+) -> io::Result<()> {
+    let bytes = encode(buf, format)?;
+    // シンボリックリンクはリンク先のファイルを置き換える
+    let path = fs::canonicalize(filename).unwrap_or_else(|_| PathBuf::from(filename));
+    let original = fs::metadata(&path).ok();
+    let tmp = sibling_path(&path, &format!("{:08x}.tmp", rand::random::<u32>()));
+
+    let result = (|| {
+        let file = OpenOptions::new().write(true).create_new(true).open(&tmp)?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&bytes)?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        if let Some(original) = &original {
+            file.set_permissions(original.permissions())?;
+        }
+        file.sync_all()?;
+        if backup && original.is_some() {
+            let mut backup_path = path.clone().into_os_string();
+            backup_path.push("~");
+            fs::copy(&path, backup_path)?;
+        }
+        fs::rename(&tmp, &path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+        return result;
+    }
+    // 置き換えたことをディレクトリにも反映させる
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        if let Ok(dir) = File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("kbeditor-fileio-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // dir の中のファイル名 (並べ替え済み)
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn write(path: &Path, text: &str, backup: bool) -> io::Result<()> {
+        write_file(
+            path.to_str().unwrap(),
+            &Buffer::from(text),
+            &FileFormat::new(),
+            backup,
+        )
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn writes_through_a_temporary_file() {
+        use std::os::unix::fs::MetadataExt;
+        let dir = temp_dir("rename");
+        let file = dir.join("a.txt");
+        write(&file, "new file", false).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "new file\n");
+        // 元のファイルを書き換えずに、一時ファイルで置き換える
+        let inode = fs::metadata(&file).unwrap().ino();
+        write(&file, "changed", false).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "changed\n");
+        assert_ne!(fs::metadata(&file).unwrap().ino(), inode);
+        // 一時ファイルは残らない
+        assert_eq!(names(&dir), ["a.txt"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn keeps_permissions_and_symlinks() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+        let dir = temp_dir("permissions");
+        let file = dir.join("a.sh");
+        fs::write(&file, "old\n").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o750)).unwrap();
+        write(&file, "new", false).unwrap();
+        let mode = fs::metadata(&file).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o750);
+        // シンボリックリンクはリンクのまま、リンク先を書き換える
+        let link = dir.join("link");
+        symlink(&file, &link).unwrap();
+        write(&link, "via link", false).unwrap();
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "via link\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn backup_keeps_the_previous_contents() {
+        let dir = temp_dir("backup");
+        let file = dir.join("a.txt");
+        // 新しいファイルにはバックアップを作らない
+        write(&file, "first", true).unwrap();
+        assert_eq!(names(&dir), ["a.txt"]);
+        write(&file, "second", true).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("a.txt~")).unwrap(), "first\n");
+        assert_eq!(fs::read_to_string(&file).unwrap(), "second\n");
+        // backup でなければ前のバックアップもそのまま
+        write(&file, "third", false).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("a.txt~")).unwrap(), "first\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_writes_leave_nothing_behind() {
+        let dir = temp_dir("failed");
+        // 置き換えに失敗したら一時ファイルを消す (ディレクトリはファイルで置き換えられない)
+        let target = dir.join("sub");
+        fs::create_dir(&target).unwrap();
+        fs::write(target.join("keep.txt"), "keep").unwrap();
+        assert!(write(&target, "text", false).is_err());
+        assert_eq!(names(&dir), ["sub"]);
+        assert_eq!(names(&target), ["keep.txt"]);
+        // 変換できない文字があれば何も書かない
+        let file = dir.join("a.txt");
+        fs::write(&file, "old\n").unwrap();
+        let mut format = FileFormat::new();
+        format.encoding = Encoding::ShiftJis;
+        let error = write_file(file.to_str().unwrap(), &Buffer::from("😀"), &format, true);
+        assert_eq!(error.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "old\n");
+        assert_eq!(names(&dir), ["a.txt", "sub"]);
+        // ディレクトリが無ければ一時ファイルも作れない
+        assert!(write(&dir.join("none").join("a.txt"), "text", false).is_err());
+        assert_eq!(names(&dir), ["a.txt", "sub"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn files_round_trip_byte_for_byte() {
+        let cases: [(&[u8], &str); 11] = [
+            (b"a\nb\n", "a\nb"),
+            (b"a\r\nb\r\n", "a\nb"),
+            (b"a\rb\r", "a\nb"),
+            (b"\xef\xbb\xbfa\n", "a"),
+            (b"a\nb", "a\nb"),
+            (b"\n", ""),
+            (b"", ""),
+            // 改行コードが混ざっていれば unix として読み、CR は残す
+            (b"a\r\nb\nc\n", "a\r\nb\nc"),
+            // Shift_JIS の "あ\r\n"
+            (b"\x82\xa0\r\n", "あ"),
+            // Latin-1 の "À la carte" と "café"
+            (b"\xc0 la carte\n", "À la carte"),
+            (b"caf\xe9\n", "café"),
+        ];
+        for (bytes, text) in cases {
+            let (buffer, format) = decode(bytes);
+            assert_eq!(buffer.to_string(), text);
+            assert_eq!(encode(&buffer, &format).unwrap(), bytes);
+        }
+        let (_, format) = decode(b"a\r\nb\nc\n");
+        assert!(format.line_ending == LineEnding::Unix);
+        let (_, format) = decode(b"\x82\xa0\r\n");
+        assert!(format.encoding == Encoding::ShiftJis && format.line_ending == LineEnding::Dos);
+        assert_eq!(format.describe(), "[dos][shift_jis]");
+    }
+
+    #[test]
+    fn shift_jis_needs_double_byte_characters() {
+        let encoding = |bytes: &[u8]| decode(bytes).1.encoding;
+        // 半角カナの範囲のバイトだけなら Latin-1
+        assert!(encoding(b"\xc0\xe0\n") == Encoding::Latin1);
+        assert!(encoding(b"\xb1\xb2\n") == Encoding::Latin1);
+        // 2バイト文字があれば、半角カナも Shift_JIS として読む
+        assert!(encoding(b"\xb1\x82\xa0\n") == Encoding::ShiftJis);
+        assert_eq!(decode(b"\xb1\x82\xa0\n").0.to_string(), "ｱあ");
+        // 指定した文字コードで読む (UTF-8 でなければ BOM も文字として読む)
+        let (buffer, format) = decode_as(b"\xef\xbb\xbfa\n", Encoding::Latin1).unwrap();
+        assert_eq!(buffer.to_string(), "ï»¿a");
+        assert!(!format.bom);
+        assert!(decode_as(b"\x82\n", Encoding::ShiftJis).is_none());
+        assert!(decode_as(b"\xe9\n", Encoding::Utf8).is_none());
+    }
+}
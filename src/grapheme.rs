@@ -0,0 +1,150 @@
+// 書記素クラスタ (結合文字や絵文字の組み合わせも含めた、見た目の1文字) と表示幅の計算
+// 列はバッファと同じく文字 (char) 単位で数える
+use std::iter;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+// x 列目から始まる書記素クラスタの表示幅 (タブは次のタブ位置まで、全角文字は2)
+fn grapheme_width(g: &str, x: usize, tabstop: usize) -> usize {
+    if g == "\t" {
+        tabstop - x % tabstop
+    } else {
+        g.width()
+    }
+}
+
+// 書記素クラスタの境界の列 (行頭の 0 と行末を含む)
+fn boundaries(line: &str) -> impl Iterator<Item = usize> + '_ {
+    let mut col = 0;
+    iter::once(0).chain(line.graphemes(true).map(move |g| {
+        col += g.chars().count();
+        col
+    }))
+}
+
+// col の1つ前の境界
+pub fn prev_boundary(line: &str, col: usize) -> usize {
+    boundaries(line)
+        .take_while(|b| *b < col)
+        .last()
+        .unwrap_or(0)
+}
+
+// col の1つ後の境界 (行末なら col のまま)
+pub fn next_boundary(line: &str, col: usize) -> usize {
+    boundaries(line).find(|b| *b > col).unwrap_or(col)
+}
+
+// col が書記素クラスタの途中なら、そのクラスタの後ろに合わせる
+pub fn snap(line: &str, col: usize) -> usize {
+    boundaries(line).find(|b| *b >= col).unwrap_or(col)
+}
+
+// 行頭から col までの表示幅
+pub fn width_to(line: &str, col: usize, tabstop: usize) -> usize {
+    let mut x = 0;
+    let mut c = 0;
+    for g in line.graphemes(true) {
+        if c >= col {
+            break;
+        }
+        x += grapheme_width(g, x, tabstop);
+        c += g.chars().count();
+    }
+    x
+}
+
+// 表示幅が width を超えない一番右の境界 (上下に移動したときに表示上の列を保つ)
+pub fn col_at_width(line: &str, width: usize, tabstop: usize) -> usize {
+    let mut x = 0;
+    let mut col = 0;
+    for g in line.graphemes(true) {
+        x += grapheme_width(g, x, tabstop);
+        if x > width {
+            break;
+        }
+        col += g.chars().count();
+    }
+    col
+}
+
+// x 列目から表示する文字列 (タブは空白にする)
+pub fn expand_tabs(text: &str, x: usize, tabstop: usize) -> String {
+    if !text.contains('\t') {
+        return text.to_string();
+    }
+    let mut s = String::new();
+    let mut x = x;
+    for g in text.graphemes(true) {
+        let w = grapheme_width(g, x, tabstop);
+        if g == "\t" {
+            s.push_str(&" ".repeat(w));
+        } else {
+            s.push_str(g);
+        }
+        x += w;
+    }
+    s
+}
+
+// 表示幅が width 以上になる最初の境界 (行の幅が足りなければ行末)
+pub fn col_at_width_ceil(line: &str, width: usize, tabstop: usize) -> usize {
+    let mut x = 0;
+    let mut col = 0;
+    for g in line.graphemes(true) {
+        if x >= width {
+            break;
+        }
+        x += grapheme_width(g, x, tabstop);
+        col += g.chars().count();
+    }
+    col
+}
+
+// col から始まる書記素クラスタの表示幅 (行末なら 1)
+pub fn width_at(line: &str, col: usize, tabstop: usize) -> usize {
+    let x = width_to(line, col, tabstop);
+    let end = next_boundary(line, col);
+    if end == col {
+        1
+    } else {
+        width_to(line, end, tabstop) - x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moves_by_grapheme_cluster() {
+        // "e" + 結合アクセント、国旗 (2つの regional indicator)、全角文字
+        let line = "ae\u{301}\u{1f1ef}\u{1f1f5}あ";
+        assert_eq!(next_boundary(line, 0), 1);
+        assert_eq!(next_boundary(line, 1), 3);
+        assert_eq!(next_boundary(line, 3), 5);
+        assert_eq!(next_boundary(line, 6), 6);
+        assert_eq!(prev_boundary(line, 5), 3);
+        assert_eq!(prev_boundary(line, 3), 1);
+        assert_eq!(prev_boundary(line, 0), 0);
+        assert_eq!(snap(line, 2), 3);
+        assert_eq!(snap(line, 4), 5);
+    }
+
+    #[test]
+    fn columns_follow_display_width() {
+        let line = "aあ\tb";
+        assert_eq!(width_to(line, 1, 4), 1);
+        assert_eq!(width_to(line, 2, 4), 3);
+        // タブは次のタブ位置まで
+        assert_eq!(width_to(line, 3, 4), 4);
+        assert_eq!(width_to(line, 3, 8), 8);
+        // 全角文字の途中の幅なら、その手前の列
+        assert_eq!(col_at_width(line, 2, 4), 1);
+        assert_eq!(col_at_width(line, 3, 4), 2);
+        assert_eq!(col_at_width(line, 100, 4), 4);
+        assert_eq!(expand_tabs(line, 0, 4), "aあ b");
+        // 途中から表示するときはタブ位置までの幅が変わる
+        assert_eq!(expand_tabs("\tb", 1, 4), "   b");
+    }
+}
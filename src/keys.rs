@@ -0,0 +1,75 @@
+// キー入力の列を文字列で書く表記 (-s で読むファイルとテストで使う)
+// vim と同じく <Esc> <CR> <BS> <C-w> のように書くか、制御文字をそのまま書く
+// (改行は Enter、ESC は Esc になる。< そのものは <lt> と書く)
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+fn named_key(name: &str) -> Option<KeyEvent> {
+    let code = match name.to_lowercase().as_str() {
+        "esc" => KeyCode::Esc,
+        "cr" | "enter" | "return" => KeyCode::Enter,
+        "bs" => KeyCode::Backspace,
+        "del" => KeyCode::Delete,
+        "tab" => KeyCode::Tab,
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "home" => KeyCode::Home,
+        "end" => KeyCode::End,
+        "pageup" => KeyCode::PageUp,
+        "pagedown" => KeyCode::PageDown,
+        "space" => KeyCode::Char(' '),
+        "lt" => KeyCode::Char('<'),
+        _ => {
+            let key = name
+                .strip_prefix("C-")
+                .or_else(|| name.strip_prefix("c-"))?;
+            let mut chars = key.chars();
+            let c = chars.next()?;
+            if chars.next().is_some() {
+                return None;
+            }
+            return Some(KeyEvent::new(
+                KeyCode::Char(c.to_ascii_lowercase()),
+                KeyModifiers::CONTROL,
+            ));
+        }
+    };
+    Some(KeyEvent::from(code))
+}
+
+fn control_key(c: char) -> KeyEvent {
+    match c {
+        '\x1b' => KeyEvent::from(KeyCode::Esc),
+        '\r' | '\n' => KeyEvent::from(KeyCode::Enter),
+        '\t' => KeyEvent::from(KeyCode::Tab),
+        '\x08' | '\x7f' => KeyEvent::from(KeyCode::Backspace),
+        // Ctrl-A から Ctrl-Z
+        '\x01'..='\x1a' => KeyEvent::new(
+            KeyCode::Char((c as u8 - 1 + b'a') as char),
+            KeyModifiers::CONTROL,
+        ),
+        _ => KeyEvent::from(KeyCode::Char(c)),
+    }
+}
+
+pub fn parse(input: &str) -> Vec<KeyEvent> {
+    let mut keys = Vec::new();
+    let mut rest = input;
+    while let Some(c) = rest.chars().next() {
+        // 知らない名前なら < をそのまま入力する
+        if c == '<' {
+            let key = rest
+                .find('>')
+                .and_then(|end| Some((end, named_key(&rest[1..end])?)));
+            if let Some((end, key)) = key {
+                rest = &rest[end + 1..];
+                keys.push(key);
+                continue;
+            }
+        }
+        keys.push(control_key(c));
+        rest = &rest[c.len_utf8()..];
+    }
+    keys
+}
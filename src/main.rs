@@ -1,95 +1,135 @@
-use arboard::Clipboard;
+use colors::Colorscheme;
 use crossterm::{
-    cursor::{position, MoveTo},
-    event::{self, KeyCode},
-    execute,
-    style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor},
+    event, execute,
     terminal::{self, ClearType, EnterAlternateScreen, LeaveAlternateScreen},
     ExecutableCommand,
 };
-use std::collections::VecDeque;
+use editor::Editor;
+use register::Register;
+use screen::Screen;
+use settings::Settings;
 use std::env;
 use std::fs;
-use std::fs::File;
-use std::io::{self, Write};
-use std::path::PathBuf;
+use std::io;
+use std::panic;
+use std::process;
+mod buffer;
+mod cmdline;
+mod colors;
+mod editor;
+mod ex;
+mod fileio;
+mod grapheme;
+mod keys;
+mod motion;
+mod region;
+mod register;
+mod render;
+mod screen;
+mod search;
+mod settings;
+mod substitute;
+mod swap;
+mod syntax;
+mod textobject;
+mod undo;
+mod window;
+// 描画処理を無効にしているので、インタプリタは今のところ使われていない
+#[allow(dead_code)]
 mod script;
 
-type Text = Vec<String>;
-
-const MAX_UNDO: usize = 100;
-
-struct UndoRedo {
-    undo_stack: VecDeque<Text>,
-    redo_stack: Vec<Text>,
-}
-
-impl UndoRedo {
-    fn new() -> Self {
-        Self {
-            undo_stack: VecDeque::new(),
-            redo_stack: Vec::new(),
-        }
-    }
-
-    fn perform_action(&mut self, action: Text) {
-        if self.undo_stack.len() == MAX_UNDO {
-            self.undo_stack.pop_front();
-        }
-        self.undo_stack.push_back(action);
-        self.redo_stack.clear();
-    }
-
-    fn undo(&mut self) -> Option<Text> {
-        if let Some(last_action) = self.undo_stack.pop_back() {
-            self.redo_stack.push(last_action.clone());
-            return Some(last_action);
-        }
-        None
-    }
-
-    fn redo(&mut self) -> Option<Text> {
-        if let Some(last_redo) = self.redo_stack.pop() {
-            self.undo_stack.push_back(last_redo.clone());
-            return Some(last_redo);
-        }
-        None
-    }
+// 画面を開かずに実行する操作 (起動オプションで指定した順に実行する)
+enum Batch {
+    Keys(String), // -s {file}: ファイルに書いたキー入力
+    Exec(String), // --exec {cmd}: ex コマンド
 }
 
-enum Mode {
-    Normal,
-    Insert,
+// エラーのメッセージか ("E37: ..." のように番号で始まる)
+fn is_error(message: &str) -> bool {
+    message.strip_prefix('E').is_some_and(|rest| {
+        rest.split_once(':')
+            .is_some_and(|(n, _)| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+    })
 }
 
-fn is_identifier_char(c: char) -> bool {
-    match c {
-        'a'..='z' => true,
-        '_' => true,
-        _ => false,
+// 画面を開かずにキー入力や ex コマンドを順に実行する
+// エラーは標準エラー出力に表示して、1つでもあれば終了コードを 1 にする
+fn run_batch(editor: &mut Editor, batch: &[Batch]) -> i32 {
+    let mut status = 0;
+    for step in batch {
+        match step {
+            Batch::Keys(path) => {
+                let input = match fs::read_to_string(path) {
+                    Ok(input) => input,
+                    Err(e) => {
+                        eprintln!("Cannot open script file \"{}\": {}", path, e);
+                        return 1;
+                    }
+                };
+                // ファイルの最後の改行は Enter として入力しない (Enter で終えたいときは <CR> と書く)
+                let input = input.strip_suffix('\n').unwrap_or(&input);
+                let input = input.strip_suffix('\r').unwrap_or(input);
+                for key in keys::parse(input) {
+                    editor.handle_key(key);
+                    if is_error(&editor.message) {
+                        eprintln!("{}", editor.message);
+                        status = 1;
+                    }
+                    if editor.quit {
+                        return status;
+                    }
+                }
+            }
+            Batch::Exec(cmd) => {
+                if let Err(msg) = editor.run_command(cmd) {
+                    eprintln!("{}", msg);
+                    status = 1;
+                }
+                if editor.quit {
+                    return status;
+                }
+            }
+        }
     }
+    status
 }
 
-fn read_file(filename: &str) -> Vec<String> {
-    match fs::read_to_string(filename) {
-        Ok(contents) => contents.lines().map(String::from).collect(),
-        Err(_) => vec![format!("ファイルを読み込めませんでした:{}", filename)],
+fn main() -> crossterm::Result<()> {
+    let mut settings = Settings::new();
+    let mut files = Vec::new();
+    let mut batch = Vec::new();
+    let mut colorscheme = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            // undo の履歴をファイルに保存し、次に開いたときに引き継ぐ
+            "--undofile" => settings.undofile = true,
+            "-s" | "--exec" | "--colorscheme" => match args.next() {
+                Some(value) if arg == "-s" => batch.push(Batch::Keys(value)),
+                Some(value) if arg == "--exec" => batch.push(Batch::Exec(value)),
+                Some(value) => colorscheme = Some(value),
+                None => {
+                    eprintln!("Argument missing after: \"{}\"", arg);
+                    process::exit(2);
+                }
+            },
+            _ if arg.starts_with('-') && arg != "-" => {
+                eprintln!("Unknown option argument: \"{}\"", arg);
+                process::exit(2);
+            }
+            _ => files.push(arg),
+        }
     }
-}
 
-fn write_file(filename: &str, buf: &Vec<String>) -> io::Result<()> {
-    let mut file = File::create(filename)?; // ファイルを作成
-    for line in buf {
-        writeln!(file, "{}", line)?; // 各行を書き込み（改行付き）
+    // ファイル名が無ければ名前の無いバッファを開く (2つ目からのファイルは裏のバッファに開く)
+    let mut editor = match files.first() {
+        Some(filename) => Editor::open(filename, settings),
+        None => Editor::new(settings),
+    };
+    editor.add_buffers(files.get(1..).unwrap_or_default());
+    if !batch.is_empty() {
+        process::exit(run_batch(&mut editor, &batch));
     }
-    Ok(())
-}
-
-fn main() -> crossterm::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let filename = &args[1];
-    let mut filepath = PathBuf::from(env::current_dir().unwrap());
-    filepath.push(filename);
 
     //(loop (!= i 100) [(set i (+ i 1)) (paint 10 i (* i 100) i i)])
     let mut lex: script::Lexer = script::Lexer::new(String::from(
@@ -97,7 +137,7 @@ fn main() -> crossterm::Result<()> {
     ));
     lex.lex();
     let mut parser = script::Parser::new(lex);
-    let mut interpreter: script::Interpreter = match parser.program() {
+    let _interpreter: script::Interpreter = match parser.program() {
         Ok(pro) => script::Interpreter::new(pro),
         Err(msg) => {
             eprintln!("Parsing Error: {}.", msg);
@@ -105,6 +145,36 @@ fn main() -> crossterm::Result<()> {
         }
     };
 
+    editor.register = Register::system();
+    let (width, height) = terminal::size()?;
+    editor.resize(width, height);
+    // カラースキームを読み込む (指定が無ければ ~/.kbeditor_colors があれば読む)
+    editor.colors = Colorscheme::new(colors::detect());
+    let home = env::var("HOME").unwrap_or_default();
+    let default = format!("{}/.kbeditor_colors", home);
+    let colorscheme = colorscheme.or_else(|| fs::metadata(&default).is_ok().then_some(default));
+    if let Some(path) = colorscheme {
+        if let Err(msg) = editor.colors.load_file(&path) {
+            editor.message = msg;
+        }
+    }
+    // 前回までの検索の履歴 (終了するときに書き戻す)
+    let search_history = format!("{}/.kbeditor_search_history", home);
+    if let Err(e) = editor.cmdline.load_search_history(&search_history) {
+        editor.message = format!("Cannot read {}: {}", search_history, e);
+    }
+    // 前回の編集中にスワップファイルが残っていれば復元する (起動時に指定したすべてのファイル)
+    if !editor.check_swaps(swap::ask)? {
+        return Ok(());
+    }
+    // パニックしたときも端末を元に戻す
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let _ = terminal::disable_raw_mode();
+        let _ = execute!(io::stdout(), LeaveAlternateScreen);
+        default_hook(info);
+    }));
+
     // ターミナルの初期化
     let mut stdout = io::stdout();
     terminal::enable_raw_mode()?; // 生モード（キー入力をそのまま取得）
@@ -116,329 +186,14 @@ fn main() -> crossterm::Result<()> {
     )
     .unwrap();
 
-    const CURSOR_START_POS: usize = 6;
-    let mut cursor_pos = (CURSOR_START_POS, 0); // カーソルの初期位置
-    let mut input_buffer: Text = read_file(filepath.to_str().unwrap()); // 入力された文字を保持するバッファ
-    let mut mode = Mode::Normal;
-    let mut current_num = 0;
-    let mut clipboard = Clipboard::new().unwrap();
-    let mut recorder = UndoRedo::new();
-    let (_, height) = terminal::size().unwrap();
-    let mut upper: usize = 0;
-
+    // 前に描画した画面 (大きさが 0 なので、最初は全体を描く)
+    let mut screen = Screen::new(0, 0, Default::default());
+    // 入力や端末の大きさの変更があったときだけ描き直す
+    let mut redraw = true;
     loop {
-        // ユーザーの入力を待つ
-        if event::poll(std::time::Duration::from_millis(100))? {
-            if let event::Event::Key(key_event) = event::read().unwrap() {
-                match key_event.code {
-                    KeyCode::Enter => {
-                        // Enterキーが押された場合、新しい行に移動
-                        mode = Mode::Insert;
-                        let mut spaces = String::new();
-                        for i in 0..input_buffer[cursor_pos.1 + upper].len() {
-                            if input_buffer[cursor_pos.1 + upper].chars().nth(i).unwrap() != ' ' {
-                                break;
-                            }
-                            spaces += " ";
-                        }
-                        input_buffer.insert(cursor_pos.1 + upper + 1, spaces.clone());
-                        cursor_pos.1 += 1;
-                        cursor_pos.0 = CURSOR_START_POS + spaces.len();
-                    }
-                    KeyCode::Esc => {
-                        mode = Mode::Normal;
-                    }
-                    KeyCode::Tab => {
-                        input_buffer[cursor_pos.1] += "    ";
-                        cursor_pos.0 += 4;
-                    }
-                    KeyCode::Char(c) => match mode {
-                        Mode::Normal => match c {
-                            // manage numeric
-                            '0'..='9' => {
-                                current_num = current_num * 10 + (c as i32 - '0' as i32);
-                            }
-                            // redo undo
-                            'u' => {
-                                if let Some(data) = recorder.undo() {
-                                    input_buffer = data;
-                                }
-                            }
-                            'r' => {
-                                if let Some(data) = recorder.redo() {
-                                    input_buffer = data;
-                                }
-                            }
-                            // move cursor
-                            'h' => {
-                                if cursor_pos.0 > CURSOR_START_POS {
-                                    cursor_pos.0 -= 1;
-                                }
-                            }
-                            'j' => {
-                                if input_buffer.len() != 0
-                                    && cursor_pos.1 + upper < input_buffer.len() - 1
-                                {
-                                    cursor_pos.1 += 1;
-                                    if input_buffer[cursor_pos.1 + upper].len()
-                                        < cursor_pos.0 - CURSOR_START_POS
-                                    {
-                                        cursor_pos.0 = input_buffer[cursor_pos.1 + upper].len()
-                                            + CURSOR_START_POS;
-                                    }
-                                    if cursor_pos.1 == height as usize
-                                        && input_buffer.len() >= cursor_pos.1 + upper
-                                    {
-                                        upper += 1;
-                                        cursor_pos.1 -= 1;
-                                    }
-                                }
-                            }
-                            'k' => {
-                                if cursor_pos.1 > 0 {
-                                    cursor_pos.1 -= 1;
-                                    if input_buffer[cursor_pos.1 + upper].len()
-                                        < cursor_pos.0 - CURSOR_START_POS
-                                    {
-                                        cursor_pos.0 = input_buffer[cursor_pos.1 + upper].len()
-                                            + CURSOR_START_POS;
-                                    }
-                                } else if upper > 0 {
-                                    upper -= 1;
-                                }
-                            }
-                            'l' => {
-                                if cursor_pos.0
-                                    < input_buffer[cursor_pos.1 + upper].len() + CURSOR_START_POS
-                                {
-                                    cursor_pos.0 += 1;
-                                }
-                            }
-                            // quit
-                            'q' => {
-                                break;
-                            }
-                            // change mode to insert
-                            'i' => {
-                                mode = Mode::Insert;
-                            }
-                            'o' => {
-                                mode = Mode::Insert;
-                                let mut spaces = String::new();
-                                for i in 0..input_buffer[cursor_pos.1 + upper].len() {
-                                    if input_buffer[cursor_pos.1 + upper].chars().nth(i).unwrap()
-                                        != ' '
-                                    {
-                                        break;
-                                    }
-                                    spaces += " ";
-                                }
-                                input_buffer.insert(cursor_pos.1 + upper + 1, spaces.clone());
-                                cursor_pos.1 += 1;
-                                cursor_pos.0 = CURSOR_START_POS + spaces.len();
-                            }
-                            // remove char
-                            'x' => {
-                                if cursor_pos.0 > CURSOR_START_POS
-                                    && input_buffer[cursor_pos.1 + upper].len() != 0
-                                {
-                                    input_buffer[cursor_pos.1 + upper]
-                                        .remove(cursor_pos.0 - CURSOR_START_POS - 1);
-                                    cursor_pos.0 -= 1;
-                                }
-                            }
-                            'X' => {
-                                if cursor_pos.0
-                                    < input_buffer[cursor_pos.1 + upper].len() + CURSOR_START_POS
-                                {
-                                    input_buffer[cursor_pos.1 + upper]
-                                        .remove(cursor_pos.0 - CURSOR_START_POS);
-                                }
-                            }
-                            // remove and copy to clipboard
-                            'd' => {
-                                let mut str = String::new();
-                                if current_num == 0 {
-                                    current_num = 1;
-                                }
-                                for i in 0..current_num {
-                                    if cursor_pos.1 + upper >= input_buffer.len() {
-                                        break;
-                                    }
-                                    if i != 0 {
-                                        str += "\n";
-                                    }
-                                    str += &input_buffer[cursor_pos.1 + upper];
-                                    input_buffer.remove(cursor_pos.1 as usize + upper);
-                                }
-                                clipboard.set_text(str.as_str()).unwrap();
-                                current_num = 0;
-                                if input_buffer.len() == 0 {
-                                    input_buffer.push(String::new());
-                                    cursor_pos.0 = CURSOR_START_POS;
-                                }
-                            }
-                            // write to clipboard
-                            'y' => {
-                                let mut str = String::new();
-                                if current_num == 0 {
-                                    current_num = 1;
-                                }
-                                for i in 0..current_num {
-                                    if cursor_pos.1 + upper + i as usize >= input_buffer.len() {
-                                        break;
-                                    }
-                                    if i != 0 {
-                                        str += "\n";
-                                    }
-                                    str += &input_buffer[cursor_pos.1 + i as usize + upper];
-                                }
-                                clipboard.set_text(str.as_str()).unwrap();
-                                current_num = 0;
-                            }
-                            // paste clipboard
-                            'p' => {
-                                let str = clipboard.get_text().unwrap();
-                                let cols: Vec<&str> = str.split('\n').collect();
-                                for i in 0..cols.len() {
-                                    input_buffer
-                                        .insert(cursor_pos.1 + upper + i, cols[i].to_string());
-                                }
-                            }
-                            // next or prev word
-                            'w' => {
-                                for i in (cursor_pos.0 - CURSOR_START_POS)
-                                    ..input_buffer[cursor_pos.1].len()
-                                {
-                                    if is_identifier_char(
-                                        input_buffer[cursor_pos.1].chars().nth(i).unwrap(),
-                                    ) {
-                                        cursor_pos.0 = i + CURSOR_START_POS
-                                    } else {
-                                        cursor_pos.0 += 1;
-                                        break;
-                                    }
-                                }
-                                for i in (cursor_pos.0 - CURSOR_START_POS)
-                                    ..input_buffer[cursor_pos.1].len()
-                                {
-                                    if !is_identifier_char(
-                                        input_buffer[cursor_pos.1].chars().nth(i).unwrap(),
-                                    ) {
-                                        cursor_pos.0 = i + CURSOR_START_POS;
-                                    } else {
-                                        cursor_pos.0 += 1;
-                                        break;
-                                    }
-                                }
-                            }
-                            'b' => {
-                                for i in (0..(cursor_pos.0 - CURSOR_START_POS)).rev() {
-                                    if is_identifier_char(
-                                        input_buffer[cursor_pos.1].chars().nth(i).unwrap(),
-                                    ) {
-                                        cursor_pos.0 = i + CURSOR_START_POS;
-                                    } else {
-                                        cursor_pos.0 -= 1;
-                                        break;
-                                    }
-                                }
-                                for i in (0..(cursor_pos.0 - CURSOR_START_POS)).rev() {
-                                    if !is_identifier_char(
-                                        input_buffer[cursor_pos.1].chars().nth(i).unwrap(),
-                                    ) {
-                                        cursor_pos.0 = i + CURSOR_START_POS;
-                                    } else {
-                                        cursor_pos.0 -= 1;
-                                        break;
-                                    }
-                                }
-                                for i in (0..(cursor_pos.0 - CURSOR_START_POS)).rev() {
-                                    if is_identifier_char(
-                                        input_buffer[cursor_pos.1].chars().nth(i).unwrap(),
-                                    ) {
-                                        cursor_pos.0 = i + CURSOR_START_POS;
-                                    } else {
-                                        break;
-                                    }
-                                }
-                            }
-                            '$' => {
-                                cursor_pos.0 = input_buffer[cursor_pos.1].len() + CURSOR_START_POS;
-                            }
-                            '^' => {
-                                cursor_pos.0 = CURSOR_START_POS;
-                            }
-                            'g' => {
-                                cursor_pos.1 = 0;
-                            }
-                            'G' => {
-                                if current_num == 0 {
-                                    cursor_pos.1 = input_buffer.len() - 1;
-                                } else {
-                                    if current_num as usize >= input_buffer.len() {
-                                        current_num = input_buffer.len() as i32;
-                                    }
-                                    if current_num < 5 {
-                                        upper = 0;
-                                    } else {
-                                        upper = current_num as usize - 5;
-                                    }
-                                    cursor_pos.1 = current_num as usize - upper - 1;
-                                    current_num = 0;
-                                }
-                            }
-                            _ => {}
-                        },
-                        Mode::Insert => {
-                            match c {
-                                '`' => {
-                                    mode = Mode::Normal;
-                                    continue;
-                                }
-                                ' ' => {
-                                    recorder.perform_action(input_buffer.clone());
-                                }
-                                _ => {}
-                            }
-                            // 文字が入力された場合、それをバッファに追加
-                            input_buffer[cursor_pos.1 + upper]
-                                .insert(cursor_pos.0 - CURSOR_START_POS, c);
-                            cursor_pos.0 += 1; // カーソル位置を右に移動
-                        }
-                    },
-                    _ => {}
-                }
-            }
-        }
-
-        // 入力された内容を表示
-        stdout.execute(MoveTo(0, 0))?; // カーソルを先頭に戻す
-        stdout.execute(terminal::Clear(ClearType::All))?; // 画面をクリア
-
-        // バッファを行単位で描画
-        for (line_number, line) in input_buffer.iter().enumerate() {
-            if line_number < upper || line_number >= upper + height as usize {
-                continue;
-            }
-            // 行ごとに表示
-            execute!(
-                stdout,
-                SetForegroundColor(Color::DarkYellow),
-                Print(format!("{:>5} ", line_number + 1))
-            )
-            .unwrap();
-            execute!(
-                stdout,
-                SetForegroundColor(Color::Grey),
-                Print(format!("{}\r\n", line))
-            )
-            .unwrap();
-        }
-        execute!(stdout, Print(format!("{:>5} ", input_buffer.len()))).unwrap();
-        // カーソルの位置を調整
-        if cursor_pos.1 >= input_buffer.len() {
-            cursor_pos.1 = input_buffer.len() - 1;
+        if redraw {
+            render::render(&mut stdout, &editor, &mut screen)?;
+            redraw = false;
         }
 
         /*
@@ -467,19 +222,93 @@ fn main() -> crossterm::Result<()> {
         }
         */
 
-        // カーソルを現在の位置に移動
-        stdout.execute(MoveTo(cursor_pos.0 as u16, cursor_pos.1 as u16))?;
-        stdout.flush()?; // バッファの内容を画面に反映
+        // ユーザーの入力を待つ
+        if event::poll(std::time::Duration::from_millis(100))? {
+            match event::read()? {
+                event::Event::Key(key_event) => {
+                    editor.handle_key(key_event);
+                    redraw = true;
+                }
+                event::Event::Resize(width, height) => {
+                    editor.resize(width, height);
+                    redraw = true;
+                }
+                _ => {}
+            }
+        }
+        if editor.quit {
+            break;
+        }
+        // スワップファイルを書けなかったときはメッセージが変わる
+        let message = editor.message.clone();
+        editor.tick();
+        redraw |= editor.message != message;
     }
 
     // 終了処理
+    editor.close();
     terminal::disable_raw_mode()?;
     execute!(stdout, LeaveAlternateScreen).unwrap();
-    match write_file(filename, input_buffer.clone().as_ref()) {
-        Ok(_) => Ok(()),
-        Err(_) => {
-            eprintln!("Could not write to file '{}'!", filename);
-            Ok(())
+    if let Err(e) = editor.cmdline.save_search_history(&search_history) {
+        eprintln!("Cannot write {}: {}", search_history, e);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // keys を書いた -s 用のファイルを作る
+    fn script(name: &str, keys: &str) -> String {
+        let path = env::temp_dir().join(format!("kbeditor-{}-{}.txt", name, process::id()));
+        fs::write(&path, keys).unwrap();
+        path.display().to_string()
+    }
+
+    #[test]
+    fn errors_start_with_error_number() {
+        assert!(is_error("E37: No write since last change"));
+        assert!(is_error("E1: x"));
+        assert!(!is_error("E: x"));
+        assert!(!is_error("Ex: x"));
+        assert!(!is_error("\"a.txt\" 3L written"));
+        assert!(!is_error("Error"));
+        assert!(!is_error(""));
+    }
+
+    #[test]
+    fn last_newline_of_script_is_not_enter() {
+        for keys in ["ifoo<Esc>\n", "ifoo<Esc>\r\n", "ifoo<Esc>"] {
+            let path = script("newline", keys);
+            let mut editor = Editor::new(Settings::new());
+            assert_eq!(run_batch(&mut editor, &[Batch::Keys(path.clone())]), 0);
+            assert_eq!(editor.buffer.to_string(), "foo");
+            fs::remove_file(path).unwrap();
         }
+        // 途中の改行は Enter になる
+        let path = script("enter", "ifoo\nbar<Esc>\n");
+        let mut editor = Editor::new(Settings::new());
+        run_batch(&mut editor, &[Batch::Keys(path.clone())]);
+        assert_eq!(editor.buffer.to_string(), "foo\nbar");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn exit_status_is_one_on_errors() {
+        let mut editor = Editor::new(Settings::new());
+        let ok = [Batch::Exec("set ts=4".to_string())];
+        assert_eq!(run_batch(&mut editor, &ok), 0);
+        // エラーがあっても残りを実行する
+        let batch = [
+            Batch::Exec("nosuchcommand".to_string()),
+            Batch::Exec("set ts=4".to_string()),
+        ];
+        assert_eq!(run_batch(&mut editor, &batch), 1);
+        let path = script("error", ":nosuchcommand<CR>\n");
+        assert_eq!(run_batch(&mut editor, &[Batch::Keys(path.clone())]), 1);
+        fs::remove_file(&path).unwrap();
+        // 読めないスクリプトファイル
+        assert_eq!(run_batch(&mut editor, &[Batch::Keys(path)]), 1);
     }
 }
@@ -0,0 +1,121 @@
+// 単語単位の移動
+// 単語は英数字と _ の並び、または記号の並び (空白で区切る)
+// 位置はバッファと同じ (行, 列) で、書記素クラスタの途中には止まらない
+use crate::buffer::Buffer;
+use unicode_segmentation::UnicodeSegmentation;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum CharClass {
+    Blank,
+    Word,
+    Punct,
+}
+
+pub fn char_class(c: char) -> CharClass {
+    if c.is_whitespace() {
+        CharClass::Blank
+    } else if c.is_alphanumeric() || c == '_' {
+        CharClass::Word
+    } else {
+        CharClass::Punct
+    }
+}
+
+// 行を書記素クラスタごとに (始まりの列, 種類) にする
+pub fn graphemes(line: &str) -> Vec<(usize, CharClass)> {
+    let mut col = 0;
+    line.graphemes(true)
+        .map(|g| {
+            let start = col;
+            col += g.chars().count();
+            (start, char_class(g.chars().next().unwrap()))
+        })
+        .collect()
+}
+
+// col から始まる (または col を含む) 書記素クラスタの番号
+fn index_at(graphemes: &[(usize, CharClass)], col: usize) -> usize {
+    graphemes
+        .iter()
+        .position(|(start, _)| *start >= col)
+        .unwrap_or(graphemes.len())
+}
+
+// w: 次の単語の先頭 (行末を越えたら次の行の最初の単語、空行も1つの単語として止まる)
+pub fn next_word_start(buffer: &Buffer, pos: (usize, usize)) -> (usize, usize) {
+    let (mut line, col) = pos;
+    let mut gs = graphemes(&buffer.line(line));
+    let mut i = index_at(&gs, col);
+    if i < gs.len() && gs[i].1 != CharClass::Blank {
+        let class = gs[i].1;
+        while i < gs.len() && gs[i].1 == class {
+            i += 1;
+        }
+    }
+    loop {
+        while i < gs.len() && gs[i].1 == CharClass::Blank {
+            i += 1;
+        }
+        if i < gs.len() {
+            return (line, gs[i].0);
+        }
+        if line + 1 >= buffer.len_lines() {
+            return (line, buffer.line_len(line));
+        }
+        line += 1;
+        gs = graphemes(&buffer.line(line));
+        i = 0;
+        if gs.is_empty() {
+            return (line, 0);
+        }
+    }
+}
+
+// b: 前の単語の先頭 (行頭を越えたら前の行の最後の単語)
+pub fn prev_word_start(buffer: &Buffer, pos: (usize, usize)) -> (usize, usize) {
+    let (mut line, col) = pos;
+    let mut gs = graphemes(&buffer.line(line));
+    let mut i = index_at(&gs, col);
+    loop {
+        while i > 0 && gs[i - 1].1 == CharClass::Blank {
+            i -= 1;
+        }
+        if i > 0 {
+            let class = gs[i - 1].1;
+            while i > 0 && gs[i - 1].1 == class {
+                i -= 1;
+            }
+            return (line, gs[i].0);
+        }
+        if line == 0 {
+            return (0, 0);
+        }
+        line -= 1;
+        gs = graphemes(&buffer.line(line));
+        i = gs.len();
+        if gs.is_empty() {
+            return (line, 0);
+        }
+    }
+}
+
+// Ctrl-W で消す範囲の始まり (カーソルの前の空白と、その前の単語)
+pub fn word_start_before(line: &str, col: usize) -> usize {
+    let gs = graphemes(line);
+    let mut i = index_at(&gs, col);
+    while i > 0 && gs[i - 1].1 == CharClass::Blank {
+        i -= 1;
+    }
+    if i > 0 {
+        let class = gs[i - 1].1;
+        while i > 0 && gs[i - 1].1 == class {
+            i -= 1;
+        }
+    }
+    gs.get(i).map_or(col, |(start, _)| *start)
+}
+
+// 行の最初の空白でない文字の列
+pub fn first_non_blank(line: &str) -> usize {
+    line.chars().take_while(|c| c.is_whitespace()).count()
+}
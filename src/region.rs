@@ -0,0 +1,156 @@
+// 選択範囲や、コマンドが操作する範囲
+use crate::buffer::Buffer;
+use crate::grapheme;
+use crate::register::Kind;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Region {
+    // 文字単位 (始まり, 終わり) 終わりの位置は含まない。(行+1, 0) なら行末の改行まで含む
+    Char((usize, usize), (usize, usize)),
+    // 行単位 (最初の行, 最後の行)
+    Line(usize, usize),
+    // 矩形 (最初の行, 最後の行, 左端, 右端) 左端と右端は表示上の列で、右端は含まない
+    Block(usize, usize, usize, usize),
+}
+
+impl Region {
+    // 2つの位置 (カーソルと選択を始めた位置) を含む範囲 (どちらの位置の文字も含む)
+    pub fn between(
+        kind: Kind,
+        a: (usize, usize),
+        b: (usize, usize),
+        buffer: &Buffer,
+        tabstop: usize,
+    ) -> Self {
+        let (start, end) = if a <= b { (a, b) } else { (b, a) };
+        match kind {
+            Kind::Char => {
+                let line = buffer.line(end.0);
+                let end = if end.1 < grapheme::next_boundary(&line, end.1) {
+                    (end.0, grapheme::next_boundary(&line, end.1))
+                } else if end.0 + 1 < buffer.len_lines() {
+                    // 行末にいるときは改行も含める
+                    (end.0 + 1, 0)
+                } else {
+                    end
+                };
+                Region::Char(start, end)
+            }
+            Kind::Line => Region::Line(start.0, end.0),
+            Kind::Block => {
+                let (la, lb) = (buffer.line(a.0), buffer.line(b.0));
+                let xa = grapheme::width_to(&la, a.1, tabstop);
+                let xb = grapheme::width_to(&lb, b.1, tabstop);
+                let right = (xa + grapheme::width_at(&la, a.1, tabstop))
+                    .max(xb + grapheme::width_at(&lb, b.1, tabstop));
+                Region::Block(start.0, end.0, xa.min(xb), right)
+            }
+        }
+    }
+
+    pub fn kind(&self) -> Kind {
+        match self {
+            Region::Char(..) => Kind::Char,
+            Region::Line(..) => Kind::Line,
+            Region::Block(..) => Kind::Block,
+        }
+    }
+
+    // (最初の行, 最後の行)
+    pub fn lines(&self) -> (usize, usize) {
+        match *self {
+            Region::Char(start, end) => {
+                // 改行だけを含む最後の行は数えない
+                if end.1 == 0 && end.0 > start.0 {
+                    (start.0, end.0 - 1)
+                } else {
+                    (start.0, end.0)
+                }
+            }
+            Region::Line(first, last) | Region::Block(first, last, _, _) => (first, last),
+        }
+    }
+
+    // 範囲の始まりの位置 (操作した後のカーソルの位置)
+    pub fn start(&self, buffer: &Buffer, tabstop: usize) -> (usize, usize) {
+        match *self {
+            Region::Char(start, _) => start,
+            Region::Line(first, _) => (first, 0),
+            Region::Block(first, _, left, _) => (
+                first,
+                grapheme::col_at_width(&buffer.line(first), left, tabstop),
+            ),
+        }
+    }
+
+    // line 行目の中で範囲に含まれる列 (始まり, 終わり)
+    // 終わりが行の長さより大きければ、行末の改行も含む
+    pub fn cols(&self, line: usize, text: &str, tabstop: usize) -> Option<(usize, usize)> {
+        let len = text.chars().count();
+        match *self {
+            Region::Char(start, end) => {
+                if line < start.0 || line > end.0 || (line == end.0 && end.1 == 0 && line > start.0)
+                {
+                    return None;
+                }
+                let s = if line == start.0 { start.1 } else { 0 };
+                let e = if line == end.0 { end.1 } else { len + 1 };
+                Some((s, e))
+            }
+            Region::Line(first, last) => (first..=last).contains(&line).then_some((0, len + 1)),
+            Region::Block(first, last, left, right) => {
+                if line < first || line > last {
+                    return None;
+                }
+                let s = grapheme::col_at_width(text, left, tabstop);
+                let e = grapheme::col_at_width_ceil(text, right, tabstop);
+                (s < e).then_some((s, e))
+            }
+        }
+    }
+
+    // 行ごとの (始まり, 終わり) (改行は含まない)
+    pub fn spans(&self, buffer: &Buffer, tabstop: usize) -> Vec<((usize, usize), (usize, usize))> {
+        let (first, last) = self.lines();
+        (first..=last)
+            .filter_map(|line| {
+                let text = buffer.line(line);
+                let len = text.chars().count();
+                self.cols(line, &text, tabstop)
+                    .map(|(s, e)| ((line, s.min(len)), (line, e.min(len))))
+            })
+            .collect()
+    }
+
+    // 範囲の内容 (行単位と矩形は各行を改行でつなげる)
+    pub fn text(&self, buffer: &Buffer, tabstop: usize) -> String {
+        match *self {
+            Region::Char(start, end) => buffer.slice(start, end),
+            Region::Line(first, last) => buffer.slice((first, 0), (last, buffer.line_len(last))),
+            Region::Block(..) => self
+                .spans(buffer, tabstop)
+                .iter()
+                .map(|(s, e)| buffer.slice(*s, *e))
+                .collect::<Vec<String>>()
+                .join("\n"),
+        }
+    }
+
+    // 範囲を削除して、削除した内容を返す
+    pub fn delete(&self, buffer: &mut Buffer, tabstop: usize) -> String {
+        match *self {
+            Region::Char(start, end) => buffer.delete(start, end),
+            Region::Line(first, last) => buffer.remove_lines(first, last - first + 1),
+            Region::Block(first, last, _, _) => {
+                let text = self.text(buffer, tabstop);
+                for line in first..=last {
+                    let cols = self.cols(line, &buffer.line(line), tabstop);
+                    if let Some((s, e)) = cols {
+                        buffer.delete((line, s), (line, e.min(buffer.line_len(line))));
+                    }
+                }
+                text
+            }
+        }
+    }
+}
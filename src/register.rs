@@ -0,0 +1,65 @@
+use arboard::Clipboard;
+
+// 保存した内容が文字単位か、行単位か、矩形か (p で貼り付けるときの形が変わる)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Kind {
+    Char,
+    Line,
+    Block,
+}
+
+// d / y で保存し、p で貼り付ける内容
+// システムのクリップボードが使えれば共有し、使えなければ (端末だけの環境やテストでは) 自分で保持する
+pub struct Register {
+    text: String,
+    kind: Kind,
+    clipboard: Option<Clipboard>,
+    copied: String, // 最後にクリップボードに書いた内容 (ほかのアプリでコピーされたかを調べる)
+}
+
+impl Register {
+    pub fn new() -> Self {
+        Self {
+            text: String::new(),
+            kind: Kind::Line,
+            clipboard: None,
+            copied: String::new(),
+        }
+    }
+
+    // システムのクリップボードと共有する
+    pub fn system() -> Self {
+        Self {
+            clipboard: Clipboard::new().ok(),
+            ..Self::new()
+        }
+    }
+
+    pub fn set(&mut self, text: String, kind: Kind) {
+        if let Some(clipboard) = &mut self.clipboard {
+            // 行単位のときはほかのアプリでも行として貼り付けられるように改行を付ける
+            self.copied = match kind {
+                Kind::Line => format!("{}\n", text),
+                _ => text.clone(),
+            };
+            let _ = clipboard.set_text(self.copied.as_str());
+        }
+        self.text = text;
+        self.kind = kind;
+    }
+
+    pub fn get(&mut self) -> (String, Kind) {
+        let copied = self
+            .clipboard
+            .as_mut()
+            .and_then(|clipboard| clipboard.get_text().ok());
+        match copied {
+            // ほかのアプリでコピーした内容は、改行で終わっていれば行単位として扱う
+            Some(text) if text != self.copied => match text.strip_suffix('\n') {
+                Some(lines) => (lines.to_string(), Kind::Line),
+                None => (text, Kind::Char),
+            },
+            _ => (self.text.clone(), self.kind),
+        }
+    }
+}
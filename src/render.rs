@@ -0,0 +1,281 @@
+// エディタの状態を端末に描画する
+use crate::colors::Highlight;
+use crate::editor::{Editor, Mode};
+use crate::grapheme;
+use crate::register::Kind;
+use crate::screen::Screen;
+use crate::search;
+use crate::syntax::Group;
+use crate::window::Window;
+use std::io::{self, Write};
+use std::ops::Range;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+// 行番号を表示する幅 (テキストはこの列から始まる)
+pub const CURSOR_START_POS: usize = 6;
+
+// 文字ごとの表示の種類
+#[derive(Clone, Copy, PartialEq)]
+enum Mark {
+    Plain,
+    Match,    // 検索パターンに一致した部分
+    Selected, // ビジュアルモードの選択範囲
+}
+
+// 1行のうち表示上の列が columns の範囲に入る部分を (x, y) から描画する
+// marks は文字ごとの表示の種類で、最後の要素は行末の改行 (選択していれば1文字分反転する)
+// groups は文字ごとのシンタックスハイライトの種類 (検索に一致した部分や選択範囲では使わない)
+fn print_line(
+    screen: &mut Screen,
+    editor: &Editor,
+    (x, y): (usize, usize),
+    text: &str,
+    marks: &[Mark],
+    groups: &[Group],
+    columns: Range<usize>,
+) {
+    let colors = &editor.colors;
+    let mut col = 0;
+    let mut offset = 0;
+    for g in text.graphemes(true) {
+        let highlight = match marks[col] {
+            Mark::Plain => Highlight::from(groups[col]),
+            Mark::Match => Highlight::Search,
+            Mark::Selected => Highlight::Visual,
+        };
+        let expanded = grapheme::expand_tabs(g, offset, editor.settings.tabstop);
+        let end = offset + expanded.width();
+        if end > columns.end {
+            return;
+        }
+        if offset >= columns.start {
+            screen.print(
+                x + offset - columns.start,
+                y,
+                &expanded,
+                colors.get(highlight),
+            );
+        } else if end > columns.start {
+            // 左端で半分だけ見える全角文字やタブは空白にする
+            let visible = " ".repeat(end - columns.start);
+            screen.print(x, y, &visible, colors.get(highlight));
+        }
+        offset = end;
+        col += g.chars().count();
+    }
+    if marks[col] == Mark::Selected && columns.contains(&offset) {
+        screen.print(
+            x + offset - columns.start,
+            y,
+            " ",
+            colors.get(Highlight::Visual),
+        );
+    }
+}
+
+// 行の文字ごとの表示の種類 (検索に一致した部分の上に、今のウィンドウなら選択範囲を重ねる)
+fn line_marks(editor: &Editor, line: usize, text: &str, current: bool) -> Vec<Mark> {
+    let len = text.chars().count();
+    let mut marks = vec![Mark::Plain; len + 1];
+    if let Some(regex) = editor.highlight() {
+        for (start, end) in search::matches(regex, text) {
+            marks[start..end].fill(Mark::Match);
+        }
+    }
+    let selected = editor
+        .selection()
+        .filter(|_| current)
+        .and_then(|region| region.cols(line, text, editor.settings.tabstop));
+    if let Some((start, end)) = selected {
+        marks[start..end.min(len + 1)].fill(Mark::Selected);
+    }
+    marks
+}
+
+// ウィンドウを1つ描画する (同じバッファを表示しているウィンドウには同じ内容が見える)
+fn render_window(screen: &mut Screen, editor: &Editor, window: &Window) {
+    let colors = &editor.colors;
+    let current = window.id == editor.window;
+    let (number, upper, leftcol) = if current {
+        (editor.number, editor.upper, editor.leftcol)
+    } else {
+        (window.number, window.upper, window.leftcol)
+    };
+    let buffer = editor.buffer_of(number);
+    let syntax = editor.syntax_of(number);
+    let rect = window.rect;
+    // ほかのウィンドウで行が消されていれば、残っている行まで戻す
+    let upper = upper.min(buffer.len_lines() - 1);
+    let last = buffer.len_lines().min(upper + rect.height);
+    let text_width = rect.width.saturating_sub(CURSOR_START_POS);
+    let gutter = |n: usize| -> String { format!("{:>5} ", n).chars().take(rect.width).collect() };
+    // バッファを行単位で描画
+    for line_number in upper..last {
+        // 行ごとに表示
+        let y = rect.y + line_number - upper;
+        let gutter = gutter(line_number + 1);
+        screen.print(rect.x, y, &gutter, colors.get(Highlight::LineNr));
+        let text = buffer.line(line_number);
+        let marks = line_marks(editor, line_number, &text, current);
+        let groups = syntax.line(buffer, line_number);
+        print_line(
+            screen,
+            editor,
+            (rect.x + CURSOR_START_POS, y),
+            &text,
+            &marks,
+            &groups,
+            leftcol..leftcol + text_width,
+        );
+    }
+    if last - upper < rect.height {
+        let gutter = gutter(buffer.len_lines());
+        screen.print(
+            rect.x,
+            rect.y + last - upper,
+            &gutter,
+            colors.get(Highlight::LineNr),
+        );
+    }
+    if editor.windows.len() == 1 {
+        return;
+    }
+    // 右に別のウィンドウがあれば区切りの縦線を引く
+    if rect.x + rect.width < editor.screen.0 {
+        for y in rect.y..=rect.y + rect.height {
+            screen.print(
+                rect.x + rect.width,
+                y,
+                "│",
+                colors.get(Highlight::VertSplit),
+            );
+        }
+    }
+    // 下のステータス行にバッファの名前と、未保存の変更があれば [+] を表示 (今のウィンドウは StatusLine の色にする)
+    let (name, modified) = editor.buffer_status(number);
+    let status = format!(" {}{}", name, if modified { " [+]" } else { "" });
+    let status: String = format!("{:<1$}", status, rect.width)
+        .graphemes(true)
+        .scan(0, |x, g| {
+            *x += g.width();
+            (*x <= rect.width).then_some(g)
+        })
+        .collect();
+    let highlight = if current {
+        Highlight::StatusLine
+    } else {
+        Highlight::StatusLineNC
+    };
+    screen.print(rect.x, rect.y + rect.height, &status, colors.get(highlight));
+}
+
+// 一番上の行にタブページの一覧を表示する (それぞれ今のウィンドウのバッファの名前と、未保存の変更があれば [+])
+fn render_tabline(screen: &mut Screen, editor: &Editor) {
+    let mut x = 0;
+    for (i, tab) in editor.tabs.iter().enumerate() {
+        let number = match tab {
+            Some(tab) => tab.current().number,
+            None => editor.number,
+        };
+        let (name, modified) = editor.buffer_status(number);
+        let label = format!(" {} {}{} ", i + 1, name, if modified { " [+]" } else { "" });
+        if x + label.width() > editor.screen.0 {
+            break;
+        }
+        let highlight = if tab.is_none() {
+            Highlight::TabLineSel
+        } else {
+            Highlight::TabLine
+        };
+        x = screen.print(x, 0, &label, editor.colors.get(highlight));
+    }
+    // 残りの部分も塗って、一覧の行だとわかるようにする
+    let fill = " ".repeat(editor.screen.0 - x);
+    screen.print(x, 0, &fill, editor.colors.get(Highlight::TabLineFill));
+}
+
+// 今のエディタの状態を描いた画面 (端末にはまだ書かない)
+fn frame(editor: &Editor) -> Screen {
+    let tabstop = editor.settings.tabstop;
+    let height = editor.screen.1.saturating_sub(1);
+    let normal = editor.colors.get(Highlight::Normal);
+    let mut screen = Screen::new(editor.screen.0, editor.screen.1, normal);
+    // 入力された内容を表示
+    if editor.tabs.len() > 1 {
+        render_tabline(&mut screen, editor);
+    }
+    for window in &editor.windows {
+        render_window(&mut screen, editor, window);
+    }
+
+    // 最下行にコマンドラインかメッセージを表示
+    if let Mode::Command = editor.mode {
+        let text = editor.cmdline.text();
+        let line = format!("{}{}", editor.cmdline.prompt, text);
+        screen.print(0, height, &line, normal);
+        let typed: String = text.chars().take(editor.cmdline.cursor).collect();
+        screen.cursor = (typed.width() + 1, height);
+        return screen;
+    }
+    // メッセージが無ければ今のモードを表示する
+    let message = match (editor.message.is_empty(), editor.mode) {
+        (true, Mode::Insert) => "-- INSERT --",
+        (true, Mode::Visual(Kind::Char)) => "-- VISUAL --",
+        (true, Mode::Visual(Kind::Line)) => "-- VISUAL LINE --",
+        (true, Mode::Visual(Kind::Block)) => "-- VISUAL BLOCK --",
+        _ => &editor.message,
+    };
+    // :ls のような複数行のメッセージは、下の行から上に重ねて表示する (右端の表示は省く)
+    if message.contains('\n') {
+        let lines: Vec<&str> = message.lines().collect();
+        let top = (height + 1).saturating_sub(lines.len());
+        for (i, line) in lines.iter().enumerate() {
+            screen.clear_line(top + i);
+            let x = screen.print(0, top + i, line, normal);
+            screen.cursor = (x, top + i);
+        }
+        return screen;
+    }
+    screen.print(0, height, message, normal);
+    // 右端にファイル名と標準と違う形式、読み込み専用なら [RO]、未保存の変更があれば [+] を表示
+    // (ウィンドウが複数あれば、名前はそれぞれのステータス行に表示している)
+    let described = editor.format.describe();
+    let status = format!(
+        "{}{}{}{}{}",
+        if editor.filename.is_empty() {
+            "[No Name]"
+        } else {
+            &editor.filename
+        },
+        if described.is_empty() { "" } else { " " },
+        described,
+        if editor.readonly { " [RO]" } else { "" },
+        if editor.is_modified() { " [+]" } else { "" }
+    );
+    if editor.windows.len() == 1 && message.width() + status.width() < editor.screen.0 {
+        screen.print(editor.screen.0 - status.width(), height, &status, normal);
+    }
+
+    // カーソルを今のウィンドウの現在の位置に移動 (全角文字は2列分として数える)
+    let (line, col) = editor.cursor;
+    let rect = editor
+        .windows
+        .iter()
+        .find(|w| w.id == editor.window)
+        .unwrap()
+        .rect;
+    let x =
+        grapheme::width_to(&editor.buffer.line(line), col, tabstop).saturating_sub(editor.leftcol);
+    let x = (x + CURSOR_START_POS).min(rect.width.saturating_sub(1));
+    screen.cursor = (rect.x + x, rect.y + line - editor.upper);
+    screen
+}
+
+// 画面を描画する (previous は前に描画した画面で、変わったところだけを書き直してから今の画面に置き換える)
+pub fn render(stdout: &mut impl Write, editor: &Editor, previous: &mut Screen) -> io::Result<()> {
+    let screen = frame(editor);
+    screen.draw(stdout, previous)?;
+    *previous = screen;
+    Ok(())
+}
@@ -0,0 +1,206 @@
+// 端末の画面の内容 (セルごとの文字と色) と、前に描画した内容と比べて変わったセルだけを書く処理
+use crate::colors::Style;
+use crossterm::{
+    cursor::{Hide, MoveTo, Show},
+    queue,
+    style::{Attribute, Color, Print, SetAttribute, SetBackgroundColor, SetForegroundColor},
+    terminal::{Clear, ClearType},
+};
+use std::io::{self, Write};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+// 画面の1文字分 (全角文字の右半分は text を空にする)
+#[derive(Clone, PartialEq)]
+struct Cell {
+    text: String,
+    style: Style,
+}
+
+pub struct Screen {
+    width: usize,
+    height: usize,
+    cells: Vec<Cell>,           // 上の行から順に width 個ずつ
+    blank: Style,               // 何も書いていないところの色 (Normal)
+    pub cursor: (usize, usize), // 描画した後にカーソルを置く位置 (x, y)
+}
+
+// 色と属性を変える (前の属性は消す)
+fn set_style(stdout: &mut impl Write, style: Style) -> io::Result<()> {
+    queue!(
+        stdout,
+        SetAttribute(Attribute::Reset),
+        SetForegroundColor(style.fg.unwrap_or(Color::Reset)),
+        SetBackgroundColor(style.bg.unwrap_or(Color::Reset))
+    )?;
+    for (on, attribute) in [
+        (style.bold, Attribute::Bold),
+        (style.underline, Attribute::Underlined),
+        (style.reverse, Attribute::Reverse),
+    ] {
+        if on {
+            queue!(stdout, SetAttribute(attribute))?;
+        }
+    }
+    Ok(())
+}
+
+impl Screen {
+    // すべてを空白で塗った画面
+    pub fn new(width: usize, height: usize, blank: Style) -> Self {
+        let cell = Cell {
+            text: " ".to_string(),
+            style: blank,
+        };
+        Self {
+            width,
+            height,
+            cells: vec![cell; width * height],
+            blank,
+            cursor: (0, 0),
+        }
+    }
+
+    // (x, y) のセルを置き換える (全角文字の半分だけを上書きしたら、残りの半分は空白にする)
+    fn set(&mut self, x: usize, y: usize, text: &str, style: Style) {
+        let i = y * self.width + x;
+        if self.cells[i].text.is_empty() && x > 0 {
+            self.cells[i - 1].text = " ".to_string();
+        }
+        if x + 1 < self.width && self.cells[i + 1].text.is_empty() {
+            self.cells[i + 1].text = " ".to_string();
+        }
+        self.cells[i] = Cell {
+            text: text.to_string(),
+            style,
+        };
+    }
+
+    // (x, y) から text を書いて、書き終わった位置の x を返す (画面の右端を超える文字は書かない)
+    pub fn print(&mut self, mut x: usize, y: usize, text: &str, style: Style) -> usize {
+        if y >= self.height {
+            return x;
+        }
+        for g in text.graphemes(true) {
+            let width = g.width();
+            // 幅の無い制御文字などは表示しない
+            if width == 0 {
+                continue;
+            }
+            if x + width > self.width {
+                break;
+            }
+            self.set(x, y, g, style);
+            if width == 2 {
+                self.set(x + 1, y, "", style);
+            }
+            x += width;
+        }
+        x
+    }
+
+    // y 行目を空白にする
+    pub fn clear_line(&mut self, y: usize) {
+        for x in 0..self.width {
+            self.set(x, y, " ", self.blank);
+        }
+    }
+
+    // previous (前に描画した内容) と違うセルだけを書いて、1回で端末に送る
+    // 大きさが違えば全体を消してから書き直す
+    pub fn draw(&self, stdout: &mut impl Write, previous: &Screen) -> io::Result<()> {
+        let full = (previous.width, previous.height) != (self.width, self.height);
+        queue!(stdout, Hide)?;
+        if full {
+            queue!(
+                stdout,
+                SetAttribute(Attribute::Reset),
+                Clear(ClearType::All)
+            )?;
+        }
+        let mut style = None;
+        let mut position = None; // 端末のカーソルの位置 (書いた文字の後ろ)
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let i = y * self.width + x;
+                let cell = &self.cells[i];
+                if cell.text.is_empty() || (!full && previous.cells[i] == *cell) {
+                    continue;
+                }
+                if position != Some((x, y)) {
+                    queue!(stdout, MoveTo(x as u16, y as u16))?;
+                }
+                if style != Some(cell.style) {
+                    set_style(stdout, cell.style)?;
+                    style = Some(cell.style);
+                }
+                queue!(stdout, Print(&cell.text))?;
+                position = Some((x + cell.text.width(), y));
+            }
+        }
+        let (x, y) = self.cursor;
+        queue!(
+            stdout,
+            SetAttribute(Attribute::Reset),
+            MoveTo(x as u16, y as u16),
+            Show
+        )?;
+        stdout.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // previous から screen に描き直すときに端末に送る内容
+    fn draw(screen: &Screen, previous: &Screen) -> String {
+        let mut out = Vec::new();
+        screen.draw(&mut out, previous).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn screen(lines: &[&str]) -> Screen {
+        let mut screen = Screen::new(10, 3, Style::default());
+        for (y, line) in lines.iter().enumerate() {
+            screen.print(0, y, line, Style::default());
+        }
+        screen
+    }
+
+    #[test]
+    fn render_writes_only_changed_cells() {
+        let empty = Screen::new(0, 0, Style::default());
+        let first = screen(&["hello", "world"]);
+        let out = draw(&first, &empty);
+        assert!(out.contains("hello") && out.contains("world"));
+        // 何も変わっていなければ文字は書かない
+        let out = draw(&screen(&["hello", "world"]), &first);
+        assert!(!out.contains("hello") && !out.contains("world"));
+        // 変わったセルだけを書く
+        let out = draw(&screen(&["hello", "worlXd"]), &first);
+        assert!(out.contains("Xd"));
+        assert!(!out.contains("worl") && !out.contains("hello"));
+        // 色が変わったセルも書き直す
+        let mut colored = screen(&["hello", "world"]);
+        let red = Style {
+            fg: Some(Color::Red),
+            ..Style::default()
+        };
+        colored.print(1, 0, "e", red);
+        let out = draw(&colored, &first);
+        assert!(out.contains('e') && !out.contains("hello"));
+    }
+
+    #[test]
+    fn wide_characters_take_two_cells() {
+        let mut screen = screen(&["あいう"]);
+        // 全角文字の右半分に書くと、左半分は空白になる
+        assert_eq!(screen.print(3, 0, "x", Style::default()), 4);
+        let cells: Vec<&str> = screen.cells[..6].iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(cells, ["あ", "", " ", "x", "う", ""]);
+        // 右端からはみ出す文字は書かない
+        assert_eq!(screen.print(9, 1, "え", Style::default()), 9);
+        assert_eq!(screen.cells[19].text, " ");
+    }
+}
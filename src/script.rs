@@ -8,7 +8,7 @@ pub enum Token {
     Number(String),
     StringLiteral(String),
     Identifier(String),
-    EOF,
+    Eof,
 }
 impl Token {
     pub fn str(&self) -> String {
@@ -16,7 +16,7 @@ impl Token {
             Token::Number(num) => num.clone(),
             Token::StringLiteral(lit) => lit.clone(),
             Token::Identifier(id) => id.clone(),
-            Token::EOF => "EOF".to_string(),
+            Token::Eof => "EOF".to_string(),
         }
     }
 }
@@ -36,7 +36,7 @@ impl Lexer {
         self.que.pop_front()
     }
     pub fn peek(&self) -> Option<Token> {
-        self.que.front().map(|t| t.clone())
+        self.que.front().cloned()
     }
     pub fn lex(&mut self) {
         let operator_regex = Regex::new(r#"(?P<num>\d+(\.\d+)?([eE][+-]?\d+)?)|(?P<id>[a-zA-Z][a-zA-Z0-9_]*)|(?P<literal>"(?:\\.|[^"\\])*?")|(?P<op>(==|!=|<=|>=|<|>|[-+*/%&|^=!]=?|<<=?|>>=?|&&|\|\||[\(\)\{\}\[\]]))"#).unwrap();
@@ -55,7 +55,7 @@ impl Lexer {
             }
         }
 
-        self.que.push_back(Token::EOF);
+        self.que.push_back(Token::Eof);
     }
 }
 
@@ -87,7 +87,7 @@ impl AstNode {
             AstNode::IdList(li) => {
                 let mut res = String::from("[ ");
                 for co in li {
-                    res += &co;
+                    res += co;
                     res += " ";
                 }
                 res += "]";
@@ -118,43 +118,31 @@ impl Parser {
         let token = self.lexer.read().unwrap();
         if let Token::Identifier(identifier) = token {
             if id == identifier.clone() {
-                return Ok(());
+                Ok(())
             } else {
-                return Err(format!(
+                Err(format!(
                     "invalid token '{}', correct token is '{}'.",
                     identifier, id
-                ));
+                ))
             }
         } else {
-            return Err(format!(
+            Err(format!(
                 "invalid token '{}', correct token is '{}'.",
                 token.str(),
                 id
-            ));
+            ))
         }
     }
     fn istoken(&mut self, t: &str) -> bool {
         match self.lexer.peek().unwrap() {
-            Token::Identifier(op) => {
-                return op == t;
-            }
-            Token::StringLiteral(_) => {
-                return false;
-            }
-            Token::Number(_) => {
-                return false;
-            }
-            Token::EOF => {
-                return false;
-            }
+            Token::Identifier(op) => op == t,
+            Token::StringLiteral(_) => false,
+            Token::Number(_) => false,
+            Token::Eof => false,
         }
     }
     pub fn is_end(&self) -> bool {
-        if self.lexer.peek().unwrap().str() == "EOF".to_string() {
-            true
-        } else {
-            false
-        }
+        self.lexer.peek().unwrap().str() == "EOF"
     }
 
     fn get_id(&mut self) -> Result<String, String> {
@@ -162,7 +150,7 @@ impl Parser {
             Token::Identifier(id) => Ok(id),
             Token::StringLiteral(s) => Err(format!("String Literal \"{}\" is not identifier.", s)),
             Token::Number(n) => Err(format!("Number '{}' is not identifier.", n)),
-            Token::EOF => Err(format!("'EOF' is not identifier.")),
+            Token::Eof => Err("'EOF' is not identifier.".to_string()),
         }
     }
     pub fn parse(&mut self) -> Result<AstNode, String> {
@@ -197,7 +185,7 @@ impl Parser {
                 Token::Number(n) => Ok(AstNode::Number(n.parse::<f64>().unwrap())),
                 Token::StringLiteral(str) => Ok(AstNode::Str(str)),
                 Token::Identifier(id) => Ok(AstNode::Identifier(id)),
-                Token::EOF => Err(String::from("already EOF.")),
+                Token::Eof => Err(String::from("already EOF.")),
             }
         }
     }
@@ -316,31 +304,25 @@ impl Interpreter {
         }
     }
 
-    fn to_number(&mut self, astnode: Value) -> Result<f64, String> {
+    fn to_number(&self, astnode: Value) -> Result<f64, String> {
         if let Value::Num(num) = astnode {
             Ok(num)
         } else {
-            Err(format!(
-                "the value was expected to be a number, but it is of another type."
-            ))
+            Err("the value was expected to be a number, but it is of another type.".to_string())
         }
     }
-    fn to_string(&mut self, astnode: Value) -> Result<String, String> {
+    fn to_string(&self, astnode: Value) -> Result<String, String> {
         if let Value::Str(str) = astnode {
             Ok(str)
         } else {
-            Err(format!(
-                "the value was expected to be a string, but it is of another type."
-            ))
+            Err("the value was expected to be a string, but it is of another type.".to_string())
         }
     }
-    fn to_vector(&mut self, astnode: Value) -> Result<Vec<Value>, String> {
+    fn to_vector(&self, astnode: Value) -> Result<Vec<Value>, String> {
         if let Value::Vector(vec) = astnode {
             Ok(vec)
         } else {
-            Err(format!(
-                "the value was expected to be a vector, but it is of another type."
-            ))
+            Err("the value was expected to be a vector, but it is of another type.".to_string())
         }
     }
 
@@ -490,7 +472,7 @@ impl Interpreter {
                         self.environment.add(id.clone(), value.clone())?;
                         Ok(value)
                     } else {
-                        Err(format!("the given must be an identifier."))
+                        Err("the given must be an identifier.".to_string())
                     }
                 }
                 "const" => {
@@ -500,19 +482,16 @@ impl Interpreter {
                         self.environment.add_const(id.clone(), value.clone())?;
                         Ok(value)
                     } else {
-                        Err(format!("the given must be an identifier."))
+                        Err("the given must be an identifier.".to_string())
                     }
                 }
                 "func" => {
                     self.check_children_num(children.clone(), 2)?;
                     if let AstNode::IdList(li) = &children[0] {
-                        let mut vec: Vec<AstNode> = vec![];
-                        for i in 1..children.len() {
-                            vec.push(children[i].clone());
-                        }
+                        let vec: Vec<AstNode> = children[1..].to_vec();
                         Ok(Value::Func(li.clone(), vec))
                     } else {
-                        Err(format!("you must provide a list of arguments."))
+                        Err("you must provide a list of arguments.".to_string())
                     }
                 }
                 "if" => {
@@ -548,7 +527,7 @@ impl Interpreter {
                     self.check_children_num(children.clone(), 2)?;
                     let v: Value = self.eval(children[0].clone())?;
                     let i: Value = self.eval(children[1].clone())?;
-                    Ok(self.to_vector(v)?.clone()[self.to_number(i)?.clone() as usize].clone())
+                    Ok(self.to_vector(v)?.clone()[self.to_number(i)? as usize].clone())
                 }
                 /*
                 "setat" => {
@@ -558,7 +537,7 @@ impl Interpreter {
                         let vec: Value = self.environment.find(id.clone())?;
                         Ok(value)
                     } else {
-                        Err(format!("the given must be an identifier."))
+                        Err("the given must be an identifier.".to_string())
                     }
                 }
                 */
@@ -611,7 +590,7 @@ impl Interpreter {
                     }
                 }
             },
-            _ => Err(format!("invalid astnode.")),
+            _ => Err("invalid astnode.".to_string()),
         }
     }
 
@@ -629,7 +608,7 @@ impl Interpreter {
                     res.push(str.clone());
                 }
                 Value::Func(_, _) => {
-                    res.push(format!("func"));
+                    res.push("func".to_string());
                 }
                 */
                 Value::Vector(vec) => {
@@ -0,0 +1,69 @@
+// / と ? の検索 (パターンは正規表現で、行ごとに探す)
+use crate::buffer::Buffer;
+use regex::Regex;
+
+pub fn compile(pattern: &str) -> Result<Regex, String> {
+    Regex::new(pattern).map_err(|_| format!("E383: Invalid search string: {}", pattern))
+}
+
+// line の中で一致する部分の (始まり, 終わり) の列
+pub fn matches(regex: &Regex, line: &str) -> Vec<(usize, usize)> {
+    let col = |byte: usize| line[..byte].chars().count();
+    regex
+        .find_iter(line)
+        .map(|(start, end)| (col(start), col(end)))
+        .collect()
+}
+
+// from より後 (forward) か前で一致する位置と、端を越えて反対の端から探したか
+// 一致する位置が from しか無ければ from を返す
+pub fn find(
+    buffer: &Buffer,
+    regex: &Regex,
+    from: (usize, usize),
+    forward: bool,
+) -> Option<((usize, usize), bool)> {
+    let len = buffer.len_lines();
+    for i in 0..=len {
+        let line = if forward {
+            (from.0 + i) % len
+        } else {
+            (from.0 + len * 2 - i) % len
+        };
+        let mut cols = matches(regex, &buffer.line(line))
+            .into_iter()
+            .map(|(s, _)| s);
+        // 最初の行は from の後 (前) だけ、1周して戻った行は from より前 (後) も探す
+        let found = match (forward, i) {
+            (true, 0) => cols.find(|&s| s > from.1),
+            (true, i) if i == len => cols.find(|&s| s <= from.1),
+            (true, _) => cols.next(),
+            (false, 0) => cols.rfind(|&s| s < from.1),
+            (false, i) if i == len => cols.rfind(|&s| s >= from.1),
+            (false, _) => cols.next_back(),
+        };
+        if let Some(col) = found {
+            let wrapped = if forward {
+                from.0 + i >= len
+            } else {
+                i > from.0
+            };
+            return Some(((line, col), wrapped));
+        }
+    }
+    None
+}
+
+// * / # で探す、カーソルの位置 (無ければその後) の単語の (始まりの列, 単語)
+pub fn word_at(line: &str, col: usize) -> Option<(usize, String)> {
+    let chars: Vec<char> = line.chars().collect();
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut start = (col..chars.len()).find(|&i| is_word(chars[i]))?;
+    while start > 0 && is_word(chars[start - 1]) {
+        start -= 1;
+    }
+    let end = (start..chars.len())
+        .find(|&i| !is_word(chars[i]))
+        .unwrap_or(chars.len());
+    Some((start, chars[start..end].iter().collect()))
+}
@@ -0,0 +1,112 @@
+use crate::fileio::{Encoding, FileFormat, LineEnding};
+
+// :set で変更できるオプション
+// fileformat, fileencoding, bomb, eol は開いているファイルごとの FileFormat を変更する
+pub struct Settings {
+    pub undofile: bool, // undo の履歴をファイルに保存する
+    pub backup: bool,   // 保存するときに元のファイルを 名前~ に残す
+    pub tabstop: usize, // Tab キーで入力する空白の数
+}
+
+// :set で一覧を表示する順番
+const NAMES: [&str; 7] = [
+    "undofile",
+    "backup",
+    "tabstop",
+    "fileformat",
+    "fileencoding",
+    "bomb",
+    "eol",
+];
+
+impl Settings {
+    pub fn new() -> Self {
+        Self {
+            undofile: false,
+            backup: false,
+            tabstop: 4,
+        }
+    }
+
+    // on/off を切り替えるオプション (正式な名前, 値)
+    fn flag<'a>(
+        &'a mut self,
+        format: &'a mut FileFormat,
+        name: &str,
+    ) -> Option<(&'static str, &'a mut bool)> {
+        match name {
+            "undofile" | "udf" => Some(("undofile", &mut self.undofile)),
+            "backup" | "bk" => Some(("backup", &mut self.backup)),
+            "bomb" => Some(("bomb", &mut format.bom)),
+            "eol" => Some(("eol", &mut format.final_newline)),
+            _ => None,
+        }
+    }
+
+    fn show(&mut self, format: &mut FileFormat, name: &str) -> Result<String, String> {
+        if let Some((full, value)) = self.flag(format, name) {
+            return Ok(format!("{}{}", if *value { "" } else { "no" }, full));
+        }
+        match name {
+            "tabstop" | "ts" => Ok(format!("tabstop={}", self.tabstop)),
+            "fileformat" | "ff" => Ok(format!("fileformat={}", format.line_ending.name())),
+            "fileencoding" | "fenc" => Ok(format!("fileencoding={}", format.encoding.name())),
+            _ => Err(format!("E518: Unknown option: {}", name)),
+        }
+    }
+
+    fn show_all(&mut self, format: &mut FileFormat) -> String {
+        NAMES
+            .iter()
+            .map(|name| self.show(format, name).unwrap())
+            .collect::<Vec<String>>()
+            .join("  ")
+    }
+
+    // :set の引数を1つずつ処理する
+    // 表示するメッセージがあれば返す
+    pub fn set(&mut self, format: &mut FileFormat, args: &str) -> Result<Option<String>, String> {
+        if args.is_empty() || args == "all" {
+            return Ok(Some(self.show_all(format)));
+        }
+        let mut shown = Vec::new();
+        for arg in args.split_whitespace() {
+            if let Some(name) = arg.strip_suffix('?') {
+                shown.push(self.show(format, name)?);
+            } else if let Some((name, value)) = arg.split_once('=') {
+                let invalid = || format!("E474: Invalid argument: {}", arg);
+                match name {
+                    "tabstop" | "ts" => match value.parse() {
+                        Ok(n) if n > 0 => self.tabstop = n,
+                        _ => return Err(format!("E521: Number required after =: {}", arg)),
+                    },
+                    "fileformat" | "ff" => {
+                        format.line_ending = LineEnding::from_name(value).ok_or_else(invalid)?
+                    }
+                    "fileencoding" | "fenc" => {
+                        format.encoding = Encoding::from_name(value).ok_or_else(invalid)?
+                    }
+                    _ => {
+                        self.show(format, name)?;
+                        return Err(invalid());
+                    }
+                }
+            } else if let Some((_, flag)) = self.flag(format, arg) {
+                *flag = true;
+            } else if let Some((_, flag)) = arg
+                .strip_prefix("no")
+                .and_then(|name| self.flag(format, name))
+            {
+                *flag = false;
+            } else {
+                // 値を持つオプションは名前だけなら表示する
+                shown.push(self.show(format, arg)?);
+            }
+        }
+        Ok(if shown.is_empty() {
+            None
+        } else {
+            Some(shown.join("  "))
+        })
+    }
+}
@@ -0,0 +1,229 @@
+// :[範囲]s/pattern/replacement/[gic] (置換) の解析と、一致した部分の置き換え
+use crate::buffer::Buffer;
+use crate::search;
+use regex::Regex;
+use std::mem;
+
+// 置き換える文字列の一部
+#[derive(Debug, PartialEq)]
+enum Piece {
+    Text(String),
+    Group(usize), // \1 や $1 (0 なら一致した部分全体)
+}
+
+pub struct Substitute {
+    pub pattern: String,
+    pub regex: Regex,
+    replacement: Vec<Piece>,
+    global: bool,      // g: 行の中のすべての一致を置き換える
+    pub confirm: bool, // c: 1つずつ確認する
+}
+
+// 区切り文字で分ける (\ と区切り文字の組は区切り文字そのものにする)
+fn split(text: &str, delimiter: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        let full = parts.len() == 3;
+        let part = parts.last_mut().unwrap();
+        match c {
+            '\\' => match chars.next() {
+                Some(next) if next == delimiter => part.push(next),
+                Some(next) => {
+                    part.push('\\');
+                    part.push(next);
+                }
+                None => part.push('\\'),
+            },
+            _ if c == delimiter && !full => parts.push(String::new()),
+            _ => part.push(c),
+        }
+    }
+    parts
+}
+
+// vim と同じ書き方の置き換える文字列を解析する
+// \1 〜 \9 と $1 はグループ、& と \0 は一致した部分全体、\n と \r は改行、\t はタブ
+fn parse_replacement(text: &str) -> Vec<Piece> {
+    let mut pieces = Vec::new();
+    let mut literal = String::new();
+    let mut chars = text.chars().peekable();
+    fn push(pieces: &mut Vec<Piece>, literal: &mut String, group: usize) {
+        if !literal.is_empty() {
+            pieces.push(Piece::Text(mem::take(literal)));
+        }
+        pieces.push(Piece::Group(group));
+    }
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => push(&mut pieces, &mut literal, 0),
+            '\\' => match chars.next() {
+                Some(d @ '0'..='9') => push(&mut pieces, &mut literal, d as usize - '0' as usize),
+                Some('n' | 'r') => literal.push('\n'),
+                Some('t') => literal.push('\t'),
+                Some(next) => literal.push(next),
+                None => literal.push('\\'),
+            },
+            '$' => match chars.peek() {
+                Some('$') => {
+                    chars.next();
+                    literal.push('$');
+                }
+                Some(d) if d.is_ascii_digit() => {
+                    let mut n = 0;
+                    while let Some(d) = chars.peek().and_then(|d| d.to_digit(10)) {
+                        n = n * 10 + d as usize;
+                        chars.next();
+                    }
+                    push(&mut pieces, &mut literal, n);
+                }
+                _ => literal.push('$'),
+            },
+            _ => literal.push(c),
+        }
+    }
+    if !literal.is_empty() {
+        pieces.push(Piece::Text(literal));
+    }
+    pieces
+}
+
+// "/pattern/replacement/flags" を解析する (pattern が空なら last_pattern で探す)
+pub fn parse(arg: &str, last_pattern: Option<&str>) -> Result<Substitute, String> {
+    let Some(delimiter) = arg.chars().next() else {
+        return Err("E35: No previous regular expression".to_string());
+    };
+    if delimiter.is_alphanumeric() || matches!(delimiter, '\\' | '"' | '|') {
+        return Err("E146: Regular expressions can't be delimited by letters".to_string());
+    }
+    let parts = split(&arg[delimiter.len_utf8()..], delimiter);
+    let pattern = match (parts[0].as_str(), last_pattern) {
+        ("", Some(last)) => last.to_string(),
+        ("", None) => return Err("E35: No previous regular expression".to_string()),
+        (pattern, _) => pattern.to_string(),
+    };
+    let flags = parts.get(2).map_or("", |flags| flags.trim());
+    if let Some(c) = flags.chars().find(|c| !"gic".contains(*c)) {
+        return Err(format!("E488: Trailing characters: {}", c));
+    }
+    let regex = if flags.contains('i') {
+        search::compile(&format!("(?i){}", pattern))
+    } else {
+        search::compile(&pattern)
+    }?;
+    Ok(Substitute {
+        pattern,
+        regex,
+        replacement: parse_replacement(parts.get(1).map_or("", |r| r.as_str())),
+        global: flags.contains('g'),
+        confirm: flags.contains('c'),
+    })
+}
+
+// 一致した部分 (行, 始まりの列, 終わりの列) と置き換える文字列
+pub struct Match {
+    pub line: usize,
+    pub start: usize,
+    end: usize,
+    pub text: String,
+}
+
+impl Substitute {
+    // line の from 列から後で最初に一致する部分
+    fn find(&self, line: &str, from: usize) -> Option<(usize, usize, String)> {
+        let col = |byte: usize| line[..byte].chars().count();
+        self.regex.captures_iter(line).find_map(|caps| {
+            let (start, end) = caps.pos(0)?;
+            if col(start) < from {
+                return None;
+            }
+            let text = self
+                .replacement
+                .iter()
+                .map(|piece| match piece {
+                    Piece::Text(text) => text,
+                    Piece::Group(i) => caps.at(*i).unwrap_or(""),
+                })
+                .collect();
+            Some((col(start), col(end), text))
+        })
+    }
+}
+
+// 範囲の中で一致する部分を順に置き換えていく状態 (c フラグなら1つずつ確認する)
+pub struct Substitution {
+    pub substitute: Substitute,
+    pos: (usize, usize),        // 次に探す位置
+    last: usize,                // 範囲の最後の行 (改行を入れて行が増えればずらす)
+    pub matched: usize,         // 一致した数
+    pub count: usize,           // 置き換えた数
+    pub lines: usize,           // 置き換えた行の数
+    pub changed: Option<usize>, // 最後に置き換えた行
+}
+
+impl Substitution {
+    pub fn new(substitute: Substitute, first: usize, last: usize) -> Self {
+        Self {
+            substitute,
+            pos: (first, 0),
+            last,
+            matched: 0,
+            count: 0,
+            lines: 0,
+            changed: None,
+        }
+    }
+
+    pub fn next(&mut self, buffer: &Buffer) -> Option<Match> {
+        while self.pos.0 <= self.last.min(buffer.len_lines() - 1) {
+            let (line, col) = self.pos;
+            let text = buffer.line(line);
+            if col <= text.chars().count() {
+                if let Some((start, end, text)) = self.substitute.find(&text, col) {
+                    self.matched += 1;
+                    return Some(Match {
+                        line,
+                        start,
+                        end,
+                        text,
+                    });
+                }
+            }
+            self.pos = (line + 1, 0);
+        }
+        None
+    }
+
+    pub fn replace(&mut self, buffer: &mut Buffer, m: &Match) {
+        buffer.delete((m.line, m.start), (m.line, m.end));
+        buffer.insert((m.line, m.start), &m.text);
+        self.count += 1;
+        if self.changed != Some(m.line) {
+            self.lines += 1;
+        }
+        // 置き換えた文字列の後から続けて探す
+        let added = m.text.matches('\n').count();
+        let end = match m.text.rsplit_once('\n') {
+            Some((_, tail)) => tail.chars().count(),
+            None => m.start + m.text.chars().count(),
+        };
+        self.last += added;
+        self.changed = Some(m.line + added);
+        self.advance(m.line + added, end, m.start == m.end);
+    }
+
+    pub fn skip(&mut self, m: &Match) {
+        self.advance(m.line, m.end, m.start == m.end);
+    }
+
+    // g フラグが無ければ次の行から、空の一致なら1つ後から探す
+    fn advance(&mut self, line: usize, col: usize, empty: bool) {
+        self.pos = if !self.substitute.global {
+            (line + 1, 0)
+        } else if empty {
+            (line, col + 1)
+        } else {
+            (line, col)
+        };
+    }
+}
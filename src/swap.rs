@@ -0,0 +1,217 @@
+use crate::buffer::Buffer;
+use crate::fileio::sibling_path;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+const SWAP_FILE_HEADER: &str = "KbEditor swap 1";
+
+// 編集中の内容を定期的に書き出しておくスワップファイル (同じディレクトリの .名前.swp)
+pub fn swap_file_path(file: &Path) -> PathBuf {
+    sibling_path(file, "swp")
+}
+
+pub struct Swap {
+    pub pid: u32,               // スワップファイルを書いたプロセス
+    pub cursor: (usize, usize), // (行, 列)
+    pub text: String,
+}
+
+impl Swap {
+    // 書いたプロセスがまだ動いているか (分からなければ false)
+    pub fn is_running(&self) -> bool {
+        self.pid != process::id() && Path::new(&format!("/proc/{}", self.pid)).exists()
+    }
+}
+
+pub fn write(file: &Path, buffer: &Buffer, cursor: (usize, usize)) -> io::Result<()> {
+    let data = format!(
+        "{}\n{}\n{} {}\n{}",
+        SWAP_FILE_HEADER,
+        process::id(),
+        cursor.0,
+        cursor.1,
+        buffer
+    );
+    // 書いている途中で落ちても前のスワップファイルが残るように置き換える
+    let path = swap_file_path(file);
+    let tmp = sibling_path(file, "swp.tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(tmp, path)
+}
+
+pub fn read(file: &Path) -> Option<Swap> {
+    let data = fs::read_to_string(swap_file_path(file)).ok()?;
+    let mut parts = data.splitn(4, '\n');
+    if parts.next()? != SWAP_FILE_HEADER {
+        return None;
+    }
+    let pid = parts.next()?.parse().ok()?;
+    let (line, col) = parts.next()?.split_once(' ')?;
+    Some(Swap {
+        pid,
+        cursor: (line.parse().ok()?, col.parse().ok()?),
+        text: parts.next()?.to_string(),
+    })
+}
+
+pub fn remove(file: &Path) {
+    let _ = fs::remove_file(swap_file_path(file));
+}
+
+// 2つの内容の違いを行単位で表示する ("- " はファイルだけ, "+ " はスワップファイルだけにある行)
+pub fn diff(old: &str, new: &str) -> Vec<String> {
+    let old: Vec<&str> = old.split('\n').collect();
+    let new: Vec<&str> = new.split('\n').collect();
+    // 前後の同じ部分を除く
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let a = &old[prefix..old.len() - suffix];
+    let b = &new[prefix..new.len() - suffix];
+
+    let mut out = Vec::new();
+    // 違う部分が大きすぎるときは範囲だけ表示する
+    if a.len() * b.len() > 4_000_000 {
+        out.push(format!(
+            "lines {}-{} differ ({} lines in the file, {} lines in the swap file)",
+            prefix + 1,
+            prefix + a.len().max(b.len()),
+            a.len(),
+            b.len()
+        ));
+        return out;
+    }
+    // 最長共通部分列で差分を求める
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            i += 1;
+            j += 1;
+        } else if j == b.len() || (i < a.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            out.push(format!("{:>5} - {}", prefix + i + 1, a[i]));
+            i += 1;
+        } else {
+            out.push(format!("{:>5} + {}", prefix + j + 1, b[j]));
+            j += 1;
+        }
+    }
+    out
+}
+
+pub enum Recovery {
+    Recover(Swap),
+    Ignore,
+    Quit,
+}
+
+// 起動時にスワップファイルが残っていたら、どうするかを尋ねる
+// (生モードにする前に、普通の入出力で尋ねる)
+// current はファイルの今の内容
+pub fn ask(file: &Path, current: &str) -> io::Result<Recovery> {
+    let Some(swap) = read(file) else {
+        return Ok(Recovery::Ignore);
+    };
+    if swap.text == current && !swap.is_running() {
+        // 保存していない変更は無かった
+        remove(file);
+        return Ok(Recovery::Ignore);
+    }
+    let mut stdout = io::stdout();
+    writeln!(
+        stdout,
+        "Found a swap file \"{}\".",
+        swap_file_path(file).display()
+    )?;
+    if swap.is_running() {
+        writeln!(
+            stdout,
+            "\"{}\" is being edited by another process (pid {}).",
+            file.display(),
+            swap.pid
+        )?;
+    } else {
+        writeln!(
+            stdout,
+            "The editor may have crashed while editing \"{}\".",
+            file.display()
+        )?;
+    }
+    loop {
+        write!(
+            stdout,
+            "[r]ecover, [c]ompare, [d]elete swap file, [e]dit anyway, [q]uit: "
+        )?;
+        stdout.flush()?;
+        let mut answer = String::new();
+        if io::stdin().lock().read_line(&mut answer)? == 0 {
+            return Ok(Recovery::Quit);
+        }
+        match answer.trim() {
+            "r" => return Ok(Recovery::Recover(swap)),
+            "c" => {
+                for line in diff(current, &swap.text) {
+                    writeln!(stdout, "{}", line)?;
+                }
+            }
+            "d" => {
+                remove(file);
+                return Ok(Recovery::Ignore);
+            }
+            "e" => return Ok(Recovery::Ignore),
+            "q" => return Ok(Recovery::Quit),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn swap_file_round_trips() {
+        let dir = env::temp_dir().join(format!("kbeditor-swap-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("a.txt");
+        assert!(read(&file).is_none());
+        write(&file, &Buffer::from("one\ntwo\n"), (1, 2)).unwrap();
+        assert!(swap_file_path(&file).ends_with(".a.txt.swp"));
+        let swap = read(&file).unwrap();
+        assert_eq!(swap.text, "one\ntwo\n");
+        assert_eq!((swap.pid, swap.cursor), (process::id(), (1, 2)));
+        // 自分が書いたスワップファイルは、動いているほかのプロセスのものではない
+        assert!(!swap.is_running());
+        remove(&file);
+        assert!(read(&file).is_none());
+        // 知らない形式のファイルは読まない
+        fs::write(swap_file_path(&file), "something else\n").unwrap();
+        assert!(read(&file).is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn diff_shows_changed_lines() {
+        assert_eq!(
+            diff("a\nb\nc\nd", "a\nB\nc\nd\ne"),
+            ["    2 - b", "    2 + B", "    5 + e"]
+        );
+        assert!(diff("same", "same").is_empty());
+    }
+}
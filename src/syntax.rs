@@ -0,0 +1,643 @@
+// シンタックスハイライト (言語ごとの定義で行を字句に分け、強調表示の種類を決める)
+// 行の始まりの状態 (前の行から続くコメントや文字列) を覚えておき、変わった行から後だけを調べ直す
+use crate::buffer::Buffer;
+use std::cell::RefCell;
+use std::path::Path;
+
+// 強調表示の種類 (色は描画するときに決める)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Group {
+    Normal,
+    Comment,
+    String,
+    Number,
+    Keyword,
+    Type,
+    PreProc,    // プリプロセッサの命令やマクロ
+    Function,   // 関数の呼び出し
+    Special,    // Rust のライフタイム, Markdown のリストの記号や強調
+    Title,      // Markdown の見出し
+    Underlined, // Markdown のリンク
+}
+
+// 行の始まりの状態
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum State {
+    Normal,
+    Comment(usize),   // ブロックコメントの中 (入れ子の深さ)
+    String(char),     // 引用符で囲まれた文字列の中
+    RawString(usize), // Rust の r#"..."# の中 (# の数)
+    CodeBlock,        // Markdown の ``` で囲まれた部分の中
+}
+
+// 関数の呼び出しの見分け方
+#[derive(Clone, Copy, PartialEq)]
+enum Call {
+    BeforeParen, // foo(...) のように名前の後に ( が続く
+    AfterParen,  // (foo ...) のように ( の直後に名前がある
+}
+
+struct Language {
+    extensions: &'static [&'static str],
+    lexer: fn(&Language, &[char], State, &mut [Group]) -> State,
+    line_comment: Option<&'static str>,
+    block_comment: Option<(&'static str, &'static str)>,
+    nested_comments: bool,   // ブロックコメントを入れ子にできる
+    quotes: &'static [char], // 文字列を囲む引用符
+    multiline_strings: bool, // 文字列が改行をまたげる (そうでなければ \ で終わる行だけ続く)
+    rust_literals: bool,     // r#"..."#, 'a' と 'a (ライフタイム), name! (マクロ), 大文字で始まる型
+    preprocessor: bool,      // # で始まる行は C のプリプロセッサの命令
+    call: Call,
+    keywords: &'static [&'static str],
+    types: &'static [&'static str],
+}
+
+const RUST: Language = Language {
+    extensions: &["rs"],
+    lexer: lex_code,
+    line_comment: Some("//"),
+    block_comment: Some(("/*", "*/")),
+    nested_comments: true,
+    quotes: &['"'],
+    multiline_strings: true,
+    rust_literals: true,
+    preprocessor: false,
+    call: Call::BeforeParen,
+    keywords: &[
+        "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+        "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move",
+        "mut", "pub", "ref", "return", "self", "Self", "static", "struct", "super", "trait",
+        "true", "type", "unsafe", "use", "where", "while",
+    ],
+    types: &[
+        "bool", "char", "str", "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32",
+        "i64", "i128", "isize", "f32", "f64",
+    ],
+};
+
+const CPP: Language = Language {
+    extensions: &["c", "h", "cpp", "cc", "cxx", "hpp", "hh", "hxx"],
+    lexer: lex_code,
+    line_comment: Some("//"),
+    block_comment: Some(("/*", "*/")),
+    nested_comments: false,
+    quotes: &['"', '\''],
+    multiline_strings: false,
+    rust_literals: false,
+    preprocessor: true,
+    call: Call::BeforeParen,
+    keywords: &[
+        "auto",
+        "break",
+        "case",
+        "catch",
+        "class",
+        "const",
+        "constexpr",
+        "continue",
+        "decltype",
+        "default",
+        "delete",
+        "do",
+        "else",
+        "enum",
+        "explicit",
+        "extern",
+        "false",
+        "for",
+        "friend",
+        "goto",
+        "if",
+        "inline",
+        "namespace",
+        "new",
+        "noexcept",
+        "nullptr",
+        "operator",
+        "private",
+        "protected",
+        "public",
+        "return",
+        "sizeof",
+        "static",
+        "static_cast",
+        "struct",
+        "switch",
+        "template",
+        "this",
+        "throw",
+        "true",
+        "try",
+        "typedef",
+        "typename",
+        "union",
+        "using",
+        "virtual",
+        "volatile",
+        "while",
+    ],
+    types: &[
+        "bool",
+        "char",
+        "double",
+        "float",
+        "int",
+        "long",
+        "short",
+        "signed",
+        "unsigned",
+        "void",
+        "size_t",
+        "string",
+        "vector",
+        "map",
+        "set",
+        "pair",
+        "queue",
+        "deque",
+        "priority_queue",
+        "array",
+    ],
+};
+
+const MARKDOWN: Language = Language {
+    extensions: &["md", "markdown"],
+    lexer: lex_markdown,
+    line_comment: None,
+    block_comment: Some(("<!--", "-->")),
+    nested_comments: false,
+    quotes: &[],
+    multiline_strings: false,
+    rust_literals: false,
+    preprocessor: false,
+    call: Call::BeforeParen,
+    keywords: &[],
+    types: &[],
+};
+
+// エディタに組み込みのスクリプト言語 ((set i 0) (loop (< i 3) [...]) のような S 式)
+const SCRIPT: Language = Language {
+    extensions: &["kbs"],
+    lexer: lex_code,
+    line_comment: None,
+    block_comment: None,
+    nested_comments: false,
+    quotes: &['"'],
+    multiline_strings: true,
+    rust_literals: false,
+    preprocessor: false,
+    call: Call::AfterParen,
+    keywords: &[
+        "set", "const", "func", "if", "loop", "vec", "at", "setat", "paint",
+    ],
+    types: &[],
+};
+
+const LANGUAGES: [&Language; 4] = [&RUST, &CPP, &MARKDOWN, &SCRIPT];
+
+// ファイル名の拡張子 (無ければ最初の行) から言語を決める
+fn detect(filename: &str, first_line: &str) -> Option<&'static Language> {
+    let extension = Path::new(filename).extension().and_then(|e| e.to_str());
+    if let Some(extension) = extension {
+        return LANGUAGES
+            .into_iter()
+            .find(|language| language.extensions.contains(&extension));
+    }
+    // 拡張子の無い C++ のファイル (#include で始まる)
+    first_line.starts_with("#include").then_some(&CPP)
+}
+
+// バッファごとのハイライトの状態 (Default は言語の無いバッファ)
+#[derive(Default)]
+pub struct Highlighter {
+    language: Option<&'static Language>,
+    states: RefCell<Vec<State>>, // 調べた行の始まりの状態 (states[i] が i 行目)
+}
+
+impl Highlighter {
+    pub fn new(filename: &str, buffer: &Buffer) -> Self {
+        Self {
+            language: detect(filename, &buffer.line(0)),
+            states: RefCell::new(vec![State::Normal]),
+        }
+    }
+
+    // line 行目が変わったので、その次の行から後の状態を調べ直すようにする
+    pub fn invalidate(&mut self, line: usize) {
+        self.states.get_mut().truncate(line + 1);
+    }
+
+    // line 行目の文字ごとの強調表示の種類 (まだ調べていない前の行は、状態だけを調べる)
+    pub fn line(&self, buffer: &Buffer, line: usize) -> Vec<Group> {
+        let text: Vec<char> = buffer.line(line).chars().collect();
+        let mut groups = vec![Group::Normal; text.len()];
+        let Some(language) = self.language else {
+            return groups;
+        };
+        let mut states = self.states.borrow_mut();
+        while states.len() <= line {
+            let l = states.len() - 1;
+            let chars: Vec<char> = buffer.line(l).chars().collect();
+            let mut scratch = vec![Group::Normal; chars.len()];
+            let next = (language.lexer)(language, &chars, states[l], &mut scratch);
+            states.push(next);
+        }
+        let next = (language.lexer)(language, &text, states[line], &mut groups);
+        // 次の行の状態も分かったので覚えておく
+        if states.len() == line + 1 {
+            states.push(next);
+        }
+        groups
+    }
+}
+
+fn starts_with(chars: &[char], i: usize, s: &str) -> bool {
+    (i..).zip(s.chars()).all(|(j, c)| chars.get(j) == Some(&c))
+}
+
+fn is_ident(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// ブロックコメントの中を閉じるところまで進める (閉じなければ行末まで)
+fn lex_comment(language: &Language, chars: &[char], i: &mut usize, mut depth: usize) -> State {
+    let Some((open, close)) = language.block_comment else {
+        return State::Normal;
+    };
+    while *i < chars.len() {
+        if starts_with(chars, *i, close) {
+            *i += close.chars().count();
+            depth -= 1;
+            if depth == 0 {
+                return State::Normal;
+            }
+        } else if language.nested_comments && starts_with(chars, *i, open) {
+            *i += open.chars().count();
+            depth += 1;
+        } else {
+            *i += 1;
+        }
+    }
+    State::Comment(depth)
+}
+
+// 文字列の中を閉じる引用符まで進める (閉じなければ行末まで)
+fn lex_string(chars: &[char], i: &mut usize, quote: char) -> State {
+    while *i < chars.len() {
+        let c = chars[*i];
+        *i += if c == '\\' { 2 } else { 1 };
+        if c == quote {
+            return State::Normal;
+        }
+    }
+    *i = chars.len();
+    State::String(quote)
+}
+
+// r#"..."# の中を " と同じ数の # まで進める
+fn lex_raw_string(chars: &[char], i: &mut usize, hashes: usize) -> State {
+    while *i < chars.len() {
+        if chars[*i] == '"' && (1..=hashes).all(|k| chars.get(*i + k) == Some(&'#')) {
+            *i += 1 + hashes;
+            return State::Normal;
+        }
+        *i += 1;
+    }
+    State::RawString(hashes)
+}
+
+// Rust, C/C++, スクリプト言語の1行
+fn lex_code(language: &Language, chars: &[char], state: State, groups: &mut [Group]) -> State {
+    let mut i = 0;
+    let mut state = state;
+    let mut after_paren = false;
+    let first = chars.iter().position(|c| !c.is_whitespace());
+    // 空の行では前の行の状態がそのまま続く
+    while i < chars.len() {
+        let start = i;
+        let group;
+        match state {
+            State::Comment(depth) => {
+                state = lex_comment(language, chars, &mut i, depth);
+                group = Group::Comment;
+            }
+            State::String(quote) => {
+                state = lex_string(chars, &mut i, quote);
+                group = Group::String;
+            }
+            State::RawString(hashes) => {
+                state = lex_raw_string(chars, &mut i, hashes);
+                group = Group::String;
+            }
+            State::Normal | State::CodeBlock => {
+                let c = chars[i];
+                let word_end = |from: usize| {
+                    (from..chars.len())
+                        .find(|&j| !is_ident(chars[j]))
+                        .unwrap_or(chars.len())
+                };
+                if language
+                    .line_comment
+                    .is_some_and(|s| starts_with(chars, i, s))
+                {
+                    i = chars.len();
+                    group = Group::Comment;
+                } else if language
+                    .block_comment
+                    .is_some_and(|(s, _)| starts_with(chars, i, s))
+                {
+                    i += language.block_comment.unwrap().0.chars().count();
+                    state = lex_comment(language, chars, &mut i, 1);
+                    group = Group::Comment;
+                } else if language.preprocessor && Some(i) == first && c == '#' {
+                    // #include <iostream> の <iostream> は文字列として扱う
+                    let mut j = i + 1;
+                    while j < chars.len() && chars[j].is_whitespace() {
+                        j += 1;
+                    }
+                    let end = word_end(j);
+                    let directive: String = chars[j..end].iter().collect();
+                    groups[i..end].fill(Group::PreProc);
+                    i = end;
+                    if directive == "include" {
+                        while i < chars.len() && chars[i].is_whitespace() {
+                            i += 1;
+                        }
+                        if chars.get(i) == Some(&'<') {
+                            let close = (i..chars.len()).find(|&j| chars[j] == '>');
+                            let end = close.map_or(chars.len(), |j| j + 1);
+                            groups[i..end].fill(Group::String);
+                            i = end;
+                        }
+                    }
+                    continue;
+                } else if language.rust_literals && c == 'r' && raw_string_start(chars, i).is_some()
+                {
+                    let hashes = raw_string_start(chars, i).unwrap();
+                    i += 2 + hashes;
+                    state = lex_raw_string(chars, &mut i, hashes);
+                    group = Group::String;
+                } else if language.rust_literals && c == '\'' {
+                    // 'a' や '\n' は文字、'a のように閉じなければライフタイム
+                    let literal = match chars.get(i + 1) {
+                        Some('\\') => (i + 3..chars.len()).find(|&j| chars[j] == '\''),
+                        Some(_) if chars.get(i + 2) == Some(&'\'') => Some(i + 2),
+                        _ => None,
+                    };
+                    match literal {
+                        Some(end) => {
+                            i = end + 1;
+                            group = Group::String;
+                        }
+                        None => {
+                            i = word_end(i + 1).max(i + 1);
+                            group = Group::Special;
+                        }
+                    }
+                } else if language.quotes.contains(&c) {
+                    i += 1;
+                    state = lex_string(chars, &mut i, c);
+                    group = Group::String;
+                } else if c.is_ascii_digit() {
+                    // 1.5e3 や 0xff, 10u32 (1..2 の .. は含めない)
+                    i += 1;
+                    while i < chars.len()
+                        && (is_ident(chars[i]) || chars[i] == '.' && chars.get(i + 1) != Some(&'.'))
+                    {
+                        i += 1;
+                    }
+                    group = Group::Number;
+                } else if is_ident(c) {
+                    i = word_end(i);
+                    let word: String = chars[start..i].iter().collect();
+                    let next = chars[i..].iter().find(|c| !c.is_whitespace());
+                    group = if language.keywords.contains(&word.as_str()) {
+                        Group::Keyword
+                    } else if language.types.contains(&word.as_str()) {
+                        Group::Type
+                    } else if language.rust_literals && chars.get(i) == Some(&'!') {
+                        i += 1;
+                        Group::PreProc
+                    } else if language.call == Call::BeforeParen && next == Some(&'(')
+                        || language.call == Call::AfterParen && after_paren
+                    {
+                        Group::Function
+                    } else if language.rust_literals && c.is_uppercase() {
+                        Group::Type
+                    } else {
+                        Group::Normal
+                    };
+                } else if language.call == Call::AfterParen && after_paren && !c.is_whitespace() {
+                    // (+ i 1) の + のような演算子も関数として扱う
+                    i = (i..chars.len())
+                        .find(|&j| chars[j].is_whitespace() || "()[]\"".contains(chars[j]))
+                        .unwrap_or(chars.len())
+                        .max(i + 1);
+                    group = Group::Function;
+                } else {
+                    i += 1;
+                    group = Group::Normal;
+                }
+                if !c.is_whitespace() {
+                    after_paren = c == '(' && group == Group::Normal;
+                }
+            }
+        }
+        groups[start..i.min(chars.len())].fill(group);
+    }
+    // 改行をまたげない文字列は、\ で終わる行だけ次の行に続ける
+    match state {
+        State::String(_) if !language.multiline_strings && chars.last() != Some(&'\\') => {
+            State::Normal
+        }
+        _ => state,
+    }
+}
+
+// r"..." や r#"..."# の始まりなら # の数
+fn raw_string_start(chars: &[char], i: usize) -> Option<usize> {
+    if i > 0 && is_ident(chars[i - 1]) {
+        return None;
+    }
+    let hashes = chars[i + 1..].iter().take_while(|&&c| c == '#').count();
+    (chars.get(i + 1 + hashes) == Some(&'"')).then_some(hashes)
+}
+
+// Markdown の1行 (``` で囲まれたコードと <!-- --> のコメントは行をまたげる)
+fn lex_markdown(language: &Language, chars: &[char], state: State, groups: &mut [Group]) -> State {
+    let text: String = chars.iter().collect();
+    let trimmed = text.trim_start();
+    let indent = chars.len() - trimmed.chars().count();
+    if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+        groups.fill(Group::String);
+        return if state == State::CodeBlock {
+            State::Normal
+        } else {
+            State::CodeBlock
+        };
+    }
+    let mut i = 0;
+    match state {
+        State::CodeBlock => {
+            groups.fill(Group::String);
+            return State::CodeBlock;
+        }
+        State::Comment(depth) => {
+            let state = lex_comment(language, chars, &mut i, depth);
+            groups[..i].fill(Group::Comment);
+            if state != State::Normal {
+                return state;
+            }
+        }
+        _ => {}
+    }
+    if i == 0 {
+        let hashes = trimmed.chars().take_while(|&c| c == '#').count();
+        let marker = trimmed.chars().next();
+        if (1..=6).contains(&hashes) && trimmed[hashes..].chars().next().is_none_or(|c| c == ' ') {
+            groups.fill(Group::Title);
+            return State::Normal;
+        }
+        if marker == Some('>') {
+            groups.fill(Group::Comment);
+            return State::Normal;
+        }
+        // --- や *** は区切り線
+        if trimmed.chars().count() >= 3
+            && trimmed
+                .chars()
+                .all(|c| c == ' ' || Some(c) == marker && "-*_".contains(c))
+        {
+            groups.fill(Group::Special);
+            return State::Normal;
+        }
+        // リストの記号 (- * + や 1. 1))
+        let digits = trimmed.chars().take_while(char::is_ascii_digit).count();
+        let rest: Vec<char> = trimmed.chars().skip(digits).take(2).collect();
+        let len = match (digits, rest.as_slice()) {
+            (0, ['-' | '*' | '+', ' ']) => 1,
+            (n, ['.' | ')', ' ']) if n > 0 => n + 1,
+            _ => 0,
+        };
+        groups[indent..indent + len].fill(Group::Special);
+        i = indent + len;
+    }
+    lex_inline(language, chars, i, groups)
+}
+
+// Markdown の行の中の `code`, **強調**, [リンク](url), <!-- コメント -->
+fn lex_inline(language: &Language, chars: &[char], mut i: usize, groups: &mut [Group]) -> State {
+    let find = |from: usize, c: char| (from..chars.len()).find(|&j| chars[j] == c);
+    while i < chars.len() {
+        let c = chars[i];
+        let end = match c {
+            '`' => find(i + 1, '`').map(|j| (j + 1, Group::String)),
+            '*' | '_' => {
+                let run = chars[i..].iter().take_while(|&&d| d == c).count();
+                let close: String = std::iter::repeat_n(c, run).collect();
+                (i + run..chars.len())
+                    .find(|&j| starts_with(chars, j, &close) && j > i + run)
+                    .map(|j| (j + run, Group::Special))
+            }
+            '[' => find(i + 1, ']').and_then(|j| match chars.get(j + 1) {
+                Some('(') => find(j + 2, ')').map(|k| (k + 1, Group::Underlined)),
+                _ => None,
+            }),
+            '<' if starts_with(chars, i, "<!--") => {
+                let start = i;
+                i += 4;
+                let state = lex_comment(language, chars, &mut i, 1);
+                groups[start..i].fill(Group::Comment);
+                if state != State::Normal {
+                    return state;
+                }
+                continue;
+            }
+            _ => None,
+        };
+        match end {
+            Some((end, group)) => {
+                groups[i..end].fill(group);
+                i = end;
+            }
+            None => i += 1,
+        }
+    }
+    State::Normal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // バッファを変えたあと、エディタと同じように変わった行から調べ直す
+    fn sync(syntax: &mut Highlighter, buffer: &mut Buffer) {
+        if let Some(line) = buffer.take_changed() {
+            syntax.invalidate(line);
+        }
+    }
+
+    #[test]
+    fn line_states_carry_block_comments() {
+        let mut buffer = Buffer::from("let a = 1; /* x\ny */ \"s\"\nfn f() {}");
+        let mut syntax = Highlighter::new("a.rs", &buffer);
+        let groups = syntax.line(&buffer, 0);
+        assert_eq!(groups[0], Group::Keyword);
+        assert_eq!(groups[8], Group::Number);
+        assert_eq!(groups[11], Group::Comment);
+        let groups = syntax.line(&buffer, 1);
+        assert_eq!(groups[0], Group::Comment);
+        assert_eq!(groups[5], Group::String);
+        // コメントを閉じると、次の行からの状態を調べ直す
+        buffer.insert((0, 15), " */");
+        sync(&mut syntax, &mut buffer);
+        assert_eq!(syntax.line(&buffer, 1)[0], Group::Normal);
+        assert_eq!(syntax.line(&buffer, 2)[0], Group::Keyword);
+    }
+
+    #[test]
+    fn opening_a_comment_relexes_the_lines_below() {
+        let mut buffer = Buffer::from("let a = 1;\nfn f() {}\nlet b = 2; */\nfn g() {}");
+        let mut syntax = Highlighter::new("a.rs", &buffer);
+        // 最後の行まで調べて状態を覚えておく
+        assert_eq!(syntax.line(&buffer, 3)[0], Group::Keyword);
+        buffer.insert((0, 10), " /*");
+        // 調べ直さなければ、覚えている状態のまま
+        assert_eq!(syntax.line(&buffer, 1)[0], Group::Keyword);
+        sync(&mut syntax, &mut buffer);
+        assert_eq!(syntax.line(&buffer, 0)[11], Group::Comment);
+        assert_eq!(syntax.line(&buffer, 1)[0], Group::Comment);
+        assert_eq!(syntax.line(&buffer, 2)[0], Group::Comment);
+        // コメントが閉じた後は元に戻る
+        assert_eq!(syntax.line(&buffer, 3)[0], Group::Keyword);
+    }
+
+    #[test]
+    fn markdown_titles_outside_code_blocks() {
+        let text = Buffer::from("# Title\n```\n# not title\n```\ntext");
+        let syntax = Highlighter::new("README.md", &text);
+        assert_eq!(syntax.line(&text, 0)[2], Group::Title);
+        assert_ne!(syntax.line(&text, 2)[2], Group::Title);
+        assert_eq!(syntax.line(&text, 4)[0], Group::Normal);
+    }
+
+    #[test]
+    fn language_is_detected_from_name_or_first_line() {
+        // 拡張子の無い C++ のファイルは最初の行で見分ける
+        let text = Buffer::from("#include <cstdio>\nint main() { return 0; }");
+        let syntax = Highlighter::new("asd", &text);
+        assert_eq!(syntax.line(&text, 0)[0], Group::PreProc);
+        assert_eq!(syntax.line(&text, 1)[0], Group::Type);
+        assert_eq!(syntax.line(&text, 1)[13], Group::Keyword);
+        // 見分けられなければ色を付けない
+        let text = Buffer::from("int x;");
+        let syntax = Highlighter::new("notes", &text);
+        assert_eq!(syntax.line(&text, 0)[0], Group::Normal);
+        // エディタのスクリプト
+        let text = Buffer::from("(set x 10) # comment");
+        let syntax = Highlighter::new("a.kbs", &text);
+        let groups = syntax.line(&text, 0);
+        assert_eq!(groups[1], Group::Keyword);
+        assert_eq!(groups[7], Group::Number);
+    }
+}
@@ -0,0 +1,442 @@
+// テキストオブジェクト (演算子の後の iw / a( / i" / it / ap など)
+// i は中身だけ、a は区切り (括弧、引用符、タグ、後ろの空白や空行) まで含む
+use crate::buffer::Buffer;
+use crate::motion::{self, CharClass};
+use crate::region::Region;
+use std::collections::{HashMap, VecDeque};
+
+// iw / aw: 単語 (空白の上なら空白の並び)
+// aw は単語の後の空白も含め、後ろに空白が無ければ前の空白を含める
+pub fn word(buffer: &Buffer, pos: (usize, usize), around: bool, count: usize) -> Option<Region> {
+    let (line, col) = pos;
+    let text = buffer.line(line);
+    let gs = motion::graphemes(&text);
+    if gs.is_empty() {
+        return None;
+    }
+    let len = text.chars().count();
+    let start_of = |i: usize| gs.get(i).map_or(len, |(start, _)| *start);
+    // 行末にいるときは最後の文字を対象にする
+    let i = gs.iter().rposition(|(start, _)| *start <= col).unwrap_or(0);
+    // i を含む同じ種類の並び [s, e)
+    let run = |i: usize| {
+        let class = gs[i].1;
+        let mut s = i;
+        while s > 0 && gs[s - 1].1 == class {
+            s -= 1;
+        }
+        let mut e = i;
+        while e < gs.len() && gs[e].1 == class {
+            e += 1;
+        }
+        (s, e)
+    };
+    let (mut s, mut e) = run(i);
+    let on_blank = gs[i].1 == CharClass::Blank;
+    for n in 0..count {
+        if n > 0 {
+            if e >= gs.len() {
+                break;
+            }
+            e = run(e).1;
+        }
+        // aw は並びの後の空白 (空白の上なら後の単語) も含める
+        if around && e < gs.len() && (on_blank || gs[e].1 == CharClass::Blank) {
+            e = run(e).1;
+        }
+    }
+    if around && !on_blank && gs[e - 1].1 != CharClass::Blank {
+        // 後ろに空白が無ければ前の空白を含める
+        while s > 0 && gs[s - 1].1 == CharClass::Blank {
+            s -= 1;
+        }
+    }
+    Some(Region::Char((line, start_of(s)), (line, start_of(e))))
+}
+
+// 行の中で quote で囲まれた文字列を調べて、文字列の中の文字にはその文字列の始まりの列を付ける
+// (\ の後の文字は数えず、閉じていない文字列は行末で終わりにする)
+fn strings(chars: &[char], quote: char) -> Vec<Option<usize>> {
+    let mut strings = vec![None; chars.len()];
+    let mut start = None;
+    let mut escaped = false;
+    for (i, &c) in chars.iter().enumerate() {
+        match start {
+            Some(s) => {
+                strings[i] = Some(s);
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == quote || c == '\n' {
+                    start = None;
+                }
+            }
+            None if c == quote => {
+                strings[i] = Some(i);
+                start = Some(i);
+            }
+            None => {}
+        }
+    }
+    strings
+}
+
+// line 行目の文字 (改行を含む) を (オフセット, 文字, 文字列の中ならその始まりのオフセット) で返す
+fn cells(buffer: &Buffer, line: usize, quote: char) -> Vec<(usize, char, Option<usize>)> {
+    let base = buffer.to_offset((line, 0));
+    let chars: Vec<char> = buffer
+        .chars_after(base)
+        .take(buffer.line_len(line) + 1)
+        .map(|(_, c)| c)
+        .collect();
+    let strings = strings(&chars, quote);
+    chars
+        .into_iter()
+        .zip(strings)
+        .enumerate()
+        .map(|(i, (c, s))| (base + i, c, s.map(|s| base + s)))
+        .collect()
+}
+
+// offset から前の文字を近い順に1行ずつ読む
+fn cells_before(
+    buffer: &Buffer,
+    offset: usize,
+    quote: char,
+) -> impl Iterator<Item = (usize, char, Option<usize>)> + '_ {
+    let line = buffer.to_pos(offset).0;
+    (0..=line)
+        .rev()
+        .flat_map(move |l| cells(buffer, l, quote).into_iter().rev())
+        .skip_while(move |&(i, _, _)| i > offset)
+}
+
+// offset から後ろの文字を1行ずつ読む
+fn cells_after(
+    buffer: &Buffer,
+    offset: usize,
+    quote: char,
+) -> impl Iterator<Item = (usize, char, Option<usize>)> + '_ {
+    let line = buffer.to_pos(offset).0;
+    (line..buffer.len_lines())
+        .flat_map(move |l| cells(buffer, l, quote))
+        .skip_while(move |&(i, _, _)| i < offset)
+}
+
+// i( / a( など: pos を囲む count 番目の括弧 (入れ子と " で囲んだ文字列の中の括弧を考慮する)
+// 括弧の直後と直前が改行なら、i は括弧の行を除いた行だけにする
+pub fn bracket(
+    buffer: &Buffer,
+    pos: (usize, usize),
+    open: char,
+    close: char,
+    around: bool,
+    count: usize,
+) -> Option<Region> {
+    let cursor = buffer.to_offset(pos);
+    // カーソルが文字列の中にあれば、まずその文字列の中の括弧を探す
+    let string = cells_before(buffer, cursor, '"')
+        .next()
+        .filter(|&(i, _, _)| i == cursor)
+        .and_then(|(_, _, s)| s);
+    let (start, end) = string
+        .and_then(|_| enclosing(buffer, cursor, (open, close), string, count))
+        .or_else(|| enclosing(buffer, cursor, (open, close), None, count))?;
+    if around {
+        return Some(Region::Char(buffer.to_pos(start), buffer.to_pos(end + 1)));
+    }
+    let mut from = buffer.to_pos(start + 1);
+    let mut to = buffer.to_pos(end);
+    if from.1 == buffer.line_len(from.0) && to.0 > from.0 + 1 {
+        let before = buffer.line(to.0);
+        if before.chars().take(to.1).all(char::is_whitespace) {
+            from = (from.0 + 1, 0);
+            to = (to.0, 0);
+        }
+    }
+    Some(Region::Char(from, to))
+}
+
+// cursor から外側に向かって count 番目の括弧の組を探す
+// (string の文字列の中か、None なら文字列の外の括弧だけを数える)
+// 前と後ろを1回ずつ読むだけで、内側から順に閉じていない開き括弧と閉じ括弧を組にする
+// (閉じていない括弧があれば、その外側の括弧も閉じないので、組になるのは内側から同じ数だけ)
+fn enclosing(
+    buffer: &Buffer,
+    cursor: usize,
+    (open, close): (char, char),
+    string: Option<usize>,
+    count: usize,
+) -> Option<(usize, usize)> {
+    let count = count.max(1);
+    let mut depth = 0;
+    let mut opens = 0;
+    let mut start = None;
+    for (i, c, s) in cells_before(buffer, cursor, '"') {
+        if s != string {
+            // 文字列の中を探しているなら、文字列の始まりより前は見ない
+            if string.is_some() {
+                break;
+            }
+            continue;
+        }
+        if c == close && i != cursor {
+            depth += 1;
+        } else if c == open && depth > 0 {
+            depth -= 1;
+        } else if c == open {
+            opens += 1;
+            if opens == count {
+                start = Some(i);
+                break;
+            }
+        }
+    }
+    let start = start?;
+    let mut depth = 0;
+    let mut closes = 0;
+    for (i, c, s) in cells_after(buffer, cursor, '"') {
+        if s != string {
+            if string.is_some() {
+                break;
+            }
+            continue;
+        }
+        if c == open && i != cursor {
+            depth += 1;
+        } else if c == close && depth > 0 {
+            depth -= 1;
+        } else if c == close {
+            closes += 1;
+            if closes == count {
+                return Some((start, i));
+            }
+        }
+    }
+    None
+}
+
+// i" / a" / i' / i` など: 行の中で pos を囲む (無ければ pos より後の) q で囲まれた文字列
+// a" は後ろの空白も含め、後ろに空白が無ければ前の空白を含める
+pub fn quote(buffer: &Buffer, pos: (usize, usize), q: char, around: bool) -> Option<Region> {
+    let (line, col) = pos;
+    let chars: Vec<char> = buffer.line(line).chars().collect();
+    let strings = strings(&chars, q);
+    // 閉じている文字列の (始まりの引用符, 終わりの引用符)
+    let (start, end) = (0..chars.len())
+        .filter(|&i| strings[i] == Some(i))
+        .filter_map(|s| {
+            let e = (s + 1..chars.len())
+                .take_while(|&i| strings[i] == Some(s))
+                .last()?;
+            (chars[e] == q).then_some((s, e))
+        })
+        .find(|&(_, end)| col <= end)?;
+    if !around {
+        return Some(Region::Char((line, start + 1), (line, end)));
+    }
+    let mut s = start;
+    let mut e = end + 1;
+    while e < chars.len() && chars[e].is_whitespace() {
+        e += 1;
+    }
+    if e == end + 1 {
+        while s > 0 && chars[s - 1].is_whitespace() {
+            s -= 1;
+        }
+    }
+    Some(Region::Char((line, s), (line, e)))
+}
+
+enum Tag {
+    Open(String),
+    Close(String),
+}
+
+// < と > の間の文字列をタグにする (<!-- --> や <?xml ?>、<br/> は組にしないので None)
+fn parse_tag(inner: &str) -> Option<Tag> {
+    if inner.starts_with(['!', '?']) || inner.ends_with('/') {
+        return None;
+    }
+    match inner.strip_prefix('/') {
+        Some(name) => Some(Tag::Close(name.trim().to_string())),
+        None => {
+            let name: String = inner.chars().take_while(|c| !c.is_whitespace()).collect();
+            (!name.is_empty()).then_some(Tag::Open(name))
+        }
+    }
+}
+
+// from から後ろのタグを順に (タグ, 始まり, 終わり) で返す (< から、次の < より前の > までを1つのタグにする)
+fn tags_after(buffer: &Buffer, from: usize) -> impl Iterator<Item = (Tag, usize, usize)> + '_ {
+    let mut start = None;
+    let mut inner = String::new();
+    buffer.chars_after(from).filter_map(move |(i, c)| match c {
+        '<' => {
+            start = Some(i);
+            inner.clear();
+            None
+        }
+        '>' => {
+            let s = start.take()?;
+            Some((parse_tag(&inner)?, s, i + 1))
+        }
+        _ => {
+            if start.is_some() {
+                inner.push(c);
+            }
+            None
+        }
+    })
+}
+
+// cursor から前に始まるタグを近い順に返す (カーソルの上のタグも含む)
+fn tags_before(buffer: &Buffer, cursor: usize) -> impl Iterator<Item = (Tag, usize, usize)> + '_ {
+    // カーソルより後ろで、タグの終わりになる >
+    let mut end = None;
+    let mut inner = Vec::new(); // 後ろから読んだ文字
+    let mut after = Vec::new();
+    for (i, c) in buffer.chars_after(cursor + 1) {
+        match c {
+            '>' => {
+                end = Some(i + 1);
+                inner = after.into_iter().rev().collect();
+                break;
+            }
+            '<' => break,
+            _ => after.push(c),
+        }
+    }
+    buffer
+        .chars_before(cursor + 1)
+        .filter_map(move |(i, c)| match c {
+            '>' => {
+                end = Some(i + 1);
+                inner.clear();
+                None
+            }
+            '<' => {
+                let e = end.take()?;
+                let text: String = inner.iter().rev().collect();
+                Some((parse_tag(&text)?, i, e))
+            }
+            _ => {
+                if end.is_some() {
+                    inner.push(c);
+                }
+                None
+            }
+        })
+}
+
+// カーソルより後ろの、組になる開きタグが無い閉じタグ (必要になったところまでだけ読む)
+struct CloseTags<I> {
+    tags: I,
+    open: Vec<String>, // 読んだが閉じていない開きタグ
+    unused: HashMap<String, VecDeque<(usize, usize)>>, // まだ使っていない閉じタグ
+}
+
+impl<I: Iterator<Item = (Tag, usize, usize)>> CloseTags<I> {
+    fn push(&mut self, name: String, start: usize, end: usize) {
+        self.unused.entry(name).or_default().push_back((start, end));
+    }
+
+    // name の閉じタグのうち、まだ使っていない最初のものの (始まり, 終わり)
+    fn next(&mut self, name: &str) -> Option<(usize, usize)> {
+        if let Some(tag) = self.unused.get_mut(name).and_then(|q| q.pop_front()) {
+            return Some(tag);
+        }
+        loop {
+            match self.tags.next()? {
+                (Tag::Open(open), _, _) => self.open.push(open),
+                (Tag::Close(close), start, end) => {
+                    if let Some(at) = self.open.iter().rposition(|open| *open == close) {
+                        self.open.remove(at);
+                    } else if close == name {
+                        return Some((start, end));
+                    } else {
+                        self.push(close, start, end);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// it / at: pos を囲む count 番目の <tag> ... </tag>
+// 前のタグを近い順に読み、閉じていない開きタグを、後ろの閉じタグと内側から順に組にする
+pub fn tag(buffer: &Buffer, pos: (usize, usize), around: bool, count: usize) -> Option<Region> {
+    let cursor = buffer.to_offset(pos);
+    let mut count = count.max(1);
+    let mut closes = CloseTags {
+        tags: tags_after(buffer, cursor + 1),
+        open: Vec::new(),
+        unused: HashMap::new(),
+    };
+    // カーソルより前で閉じたタグの名前 (その開きタグはカーソルを囲まない)
+    let mut closed: Vec<String> = Vec::new();
+    for (tag, start, open_end) in tags_before(buffer, cursor) {
+        match tag {
+            Tag::Close(name) if open_end <= cursor => closed.push(name),
+            // カーソルが閉じタグの上にある
+            Tag::Close(name) => closes.push(name, start, open_end),
+            Tag::Open(name) => {
+                if let Some(at) = closed.iter().rposition(|close| *close == name) {
+                    closed.truncate(at);
+                    continue;
+                }
+                let Some((close_start, end)) = closes.next(&name) else {
+                    continue;
+                };
+                count -= 1;
+                if count == 0 {
+                    let (s, e) = if around {
+                        (start, end)
+                    } else {
+                        (open_end, close_start)
+                    };
+                    return Some(Region::Char(buffer.to_pos(s), buffer.to_pos(e)));
+                }
+            }
+        }
+    }
+    None
+}
+
+// ip / ap: 空行で区切られた段落 (空行の上なら空行の並び)
+// ap は段落の後の空行も含め、後ろに空行が無ければ前の空行を含める
+pub fn paragraph(buffer: &Buffer, line: usize, around: bool, count: usize) -> Option<Region> {
+    let last = buffer.len_lines() - 1;
+    let blank = |l: usize| buffer.line(l).trim().is_empty();
+    // l を含む同じ種類 (空行かどうか) の並びの最後の行
+    let run_end = |l: usize| {
+        let mut e = l;
+        while e < last && blank(e + 1) == blank(l) {
+            e += 1;
+        }
+        e
+    };
+    let mut first = line;
+    while first > 0 && blank(first - 1) == blank(line) {
+        first -= 1;
+    }
+    let mut end = run_end(line);
+    for n in 0..count.max(1) {
+        if n > 0 {
+            if end >= last {
+                break;
+            }
+            end = run_end(end + 1);
+        }
+        if around && end < last {
+            end = run_end(end + 1);
+        }
+    }
+    if around && !blank(line) && !blank(end) {
+        while first > 0 && blank(first - 1) {
+            first -= 1;
+        }
+    }
+    Some(Region::Line(first, end))
+}
@@ -0,0 +1,603 @@
+use crate::buffer::{Buffer, Edit};
+use crate::fileio::sibling_path;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+const UNDO_FILE_HEADER: &str = "KbEditor undo 1";
+
+// undo ツリーの1ノード (親の状態に edits を適用した状態)
+struct Change {
+    parent: usize,
+    cur_child: Option<usize>, // redo で進む子 (最後にいた枝)
+    edits: Vec<Edit>,
+    cursor: (usize, usize), // 変更前のカーソル位置 (行, 列)
+    time: SystemTime,
+}
+
+// 変更を差分で記録する undo ツリー
+// ノードは作られた順に並んでいるので、添字がそのまま通し番号になる
+pub struct UndoTree {
+    changes: Vec<Change>,
+    current: usize,
+    saved: Option<usize>, // ファイルに保存したときの状態 (どの状態とも違えば None)
+}
+
+impl UndoTree {
+    pub fn new() -> Self {
+        Self {
+            changes: vec![Change {
+                parent: 0,
+                cur_child: None,
+                edits: Vec::new(),
+                cursor: (0, 0),
+                time: SystemTime::now(),
+            }],
+            current: 0,
+            saved: Some(0),
+        }
+    }
+
+    // ファイルに保存した状態から変わっているか
+    pub fn is_modified(&self) -> bool {
+        self.saved != Some(self.current)
+    }
+
+    pub fn mark_saved(&mut self) {
+        self.saved = Some(self.current);
+    }
+
+    // バッファに溜まっている編集を1つの変更として記録する
+    pub fn commit(&mut self, buffer: &mut Buffer, cursor: (usize, usize)) {
+        let edits = buffer.take_edits();
+        if edits.is_empty() {
+            return;
+        }
+        let seq = self.changes.len();
+        self.changes.push(Change {
+            parent: self.current,
+            cur_child: None,
+            edits,
+            cursor,
+            time: SystemTime::now(),
+        });
+        self.changes[self.current].cur_child = Some(seq);
+        self.current = seq;
+    }
+
+    pub fn undo(&mut self, buffer: &mut Buffer) -> Option<(usize, usize)> {
+        if self.current == 0 {
+            return None;
+        }
+        let change = &self.changes[self.current];
+        for edit in change.edits.iter().rev() {
+            buffer.revert(edit);
+        }
+        let (parent, cursor) = (change.parent, change.cursor);
+        self.changes[parent].cur_child = Some(self.current);
+        self.current = parent;
+        Some(cursor)
+    }
+
+    pub fn redo(&mut self, buffer: &mut Buffer) -> Option<(usize, usize)> {
+        let child = self.changes[self.current].cur_child?;
+        for edit in &self.changes[child].edits {
+            buffer.apply(edit);
+        }
+        self.current = child;
+        Some(self.changes[child].cursor)
+    }
+
+    // 通し番号 seq の状態まで、ツリーをたどって移動する
+    fn travel(&mut self, seq: usize, buffer: &mut Buffer) -> Option<(usize, usize)> {
+        if seq == self.current {
+            return None;
+        }
+        // 目的のノードから根までの道
+        let mut path = vec![seq];
+        while *path.last().unwrap() != 0 {
+            path.push(self.changes[*path.last().unwrap()].parent);
+        }
+        // 共通の祖先まで undo する
+        let mut cursor = None;
+        while !path.contains(&self.current) {
+            cursor = self.undo(buffer);
+        }
+        // そこから目的のノードまで redo する
+        let start = path.iter().position(|n| *n == self.current).unwrap();
+        for i in (0..start).rev() {
+            self.changes[self.current].cur_child = Some(path[i]);
+            cursor = self.redo(buffer);
+        }
+        cursor
+    }
+
+    // 時間順に count 個前の状態に戻る (vim の g-)
+    pub fn earlier(&mut self, count: usize, buffer: &mut Buffer) -> Option<(usize, usize)> {
+        self.travel(self.current.saturating_sub(count), buffer)
+    }
+
+    // 時間順に count 個後の状態に進む (vim の g+)
+    pub fn later(&mut self, count: usize, buffer: &mut Buffer) -> Option<(usize, usize)> {
+        let seq = (self.current + count).min(self.changes.len() - 1);
+        self.travel(seq, buffer)
+    }
+
+    // 指定した時刻以前にできた最後の状態の通し番号
+    fn seq_at(&self, time: SystemTime) -> usize {
+        self.changes
+            .iter()
+            .rposition(|c| c.time <= time)
+            .unwrap_or(0)
+    }
+
+    // 現在の状態から duration だけ前の状態に戻る (vim の :earlier 5m)
+    pub fn earlier_by(
+        &mut self,
+        duration: Duration,
+        buffer: &mut Buffer,
+    ) -> Option<(usize, usize)> {
+        let time = self.changes[self.current]
+            .time
+            .checked_sub(duration)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        self.travel(self.seq_at(time), buffer)
+    }
+
+    // 現在の状態から duration だけ後の状態に進む (vim の :later 5m)
+    pub fn later_by(&mut self, duration: Duration, buffer: &mut Buffer) -> Option<(usize, usize)> {
+        let time = self.changes[self.current].time + duration;
+        self.travel(self.seq_at(time).max(self.current), buffer)
+    }
+}
+
+// 編集中のファイルに対応する undo ファイル (同じディレクトリの .名前.un~)
+pub fn undo_file_path(file: &Path) -> PathBuf {
+    sibling_path(file, "un~")
+}
+
+// バッファの内容のハッシュ (FNV-1a)
+// undo ファイルが今のファイルの内容に対応しているかを確かめるのに使う
+fn content_hash(buffer: &Buffer) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for (i, line) in buffer.lines().enumerate() {
+        let newline = if i == 0 { "" } else { "\n" };
+        for b in newline.bytes().chain(line.bytes()) {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+impl UndoTree {
+    // file の内容が buffer であるときの undo の履歴を保存する
+    pub fn save(&self, file: &Path, buffer: &Buffer) -> io::Result<()> {
+        let mut out = String::new();
+        out += &format!(
+            "{}\n{:016x}\n{}\n",
+            UNDO_FILE_HEADER,
+            content_hash(buffer),
+            file.display()
+        );
+        out += &format!("{} {}\n", self.current, self.changes.len());
+        for change in &self.changes {
+            let time = change
+                .time
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            out += &format!(
+                "{} {} {} {} {} {} {}\n",
+                change.parent,
+                change.cur_child.map_or("-".to_string(), |c| c.to_string()),
+                change.cursor.0,
+                change.cursor.1,
+                time.as_secs(),
+                time.subsec_nanos(),
+                change.edits.len()
+            );
+            for edit in &change.edits {
+                let (kind, at, text) = match edit {
+                    Edit::Insert(at, text) => ('i', at, text),
+                    Edit::Delete(at, text) => ('d', at, text),
+                };
+                out += &format!("{} {} {}\n{}\n", kind, at, text.len(), text);
+            }
+        }
+        fs::write(undo_file_path(file), out)
+    }
+
+    // file に対応する undo の履歴を読み込む
+    // undo ファイルが無い、壊れている、または別の内容のファイルのものなら None
+    pub fn load(file: &Path, buffer: &Buffer) -> Option<Self> {
+        let data = fs::read_to_string(undo_file_path(file)).ok()?;
+        let mut reader = UndoFileReader { rest: &data };
+        if reader.line()? != UNDO_FILE_HEADER
+            || u64::from_str_radix(reader.line()?, 16).ok()? != content_hash(buffer)
+            || reader.line()? != file.display().to_string()
+        {
+            return None;
+        }
+        let header = reader.numbers()?;
+        let (current, len) = (*header.first()?, *header.get(1)?);
+        let mut changes: Vec<Change> = Vec::new();
+        for seq in 0..len {
+            let mut fields = reader.line()?.split(' ');
+            let parent = fields.next()?.parse().ok()?;
+            let cur_child = match fields.next()? {
+                "-" => None,
+                n => Some(n.parse().ok()?),
+            };
+            let rest: Vec<u64> = fields.map(|f| f.parse().ok()).collect::<Option<_>>()?;
+            let [line, col, secs, nanos, edits] = rest[..] else {
+                return None;
+            };
+            if parent > seq || cur_child.is_some_and(|c| c >= len) {
+                return None;
+            }
+            let mut change = Change {
+                parent,
+                cur_child,
+                edits: Vec::new(),
+                cursor: (line as usize, col as usize),
+                time: SystemTime::UNIX_EPOCH + Duration::new(secs, nanos as u32),
+            };
+            for _ in 0..edits {
+                let mut fields = reader.line()?.split(' ');
+                let (kind, at, bytes) = (fields.next()?, fields.next()?, fields.next()?);
+                let at = at.parse().ok()?;
+                let text = reader.bytes(bytes.parse().ok()?)?.to_string();
+                change.edits.push(match kind {
+                    "i" => Edit::Insert(at, text),
+                    "d" => Edit::Delete(at, text),
+                    _ => return None,
+                });
+            }
+            changes.push(change);
+        }
+        if current >= changes.len() {
+            return None;
+        }
+        let tree = Self {
+            changes,
+            current,
+            saved: Some(current),
+        };
+        tree.fits(buffer).then_some(tree)
+    }
+
+    // 読み込んだ履歴が buffer (current の状態) に当てはまるかを、すべての変更をたどり直して確かめる
+    // ツリーのつながり、編集の位置と削除する文字列、変更前のカーソル位置がどれか1つでもおかしければ false
+    fn fits(&self, buffer: &Buffer) -> bool {
+        let mut children = vec![Vec::new(); self.changes.len()];
+        for (seq, change) in self.changes.iter().enumerate() {
+            if seq == 0 {
+                if change.parent != 0 || !change.edits.is_empty() {
+                    return false;
+                }
+            } else if change.parent >= seq {
+                return false;
+            } else {
+                children[change.parent].push(seq);
+            }
+            if change
+                .cur_child
+                .is_some_and(|c| c == 0 || self.changes[c].parent != seq)
+            {
+                return false;
+            }
+        }
+        // current から根の状態まで戻す
+        let mut state = buffer.clone();
+        let mut seq = self.current;
+        while seq != 0 {
+            for edit in self.changes[seq].edits.iter().rev() {
+                if !state.can_revert(edit) {
+                    return false;
+                }
+                state.revert(edit);
+            }
+            seq = self.changes[seq].parent;
+        }
+        // 根からすべての枝をたどる (true は子をたどり終えて親の状態に戻すところ)
+        let mut stack: Vec<(usize, bool)> = children[0].iter().rev().map(|&c| (c, false)).collect();
+        while let Some((seq, done)) = stack.pop() {
+            let change = &self.changes[seq];
+            if done {
+                for edit in change.edits.iter().rev() {
+                    state.revert(edit);
+                }
+                continue;
+            }
+            let (line, col) = change.cursor;
+            if line >= state.len_lines() || col > state.line_len(line) {
+                return false;
+            }
+            for edit in &change.edits {
+                if !state.can_apply(edit) {
+                    return false;
+                }
+                state.apply(edit);
+            }
+            stack.push((seq, true));
+            stack.extend(children[seq].iter().rev().map(|&c| (c, false)));
+        }
+        true
+    }
+}
+
+struct UndoFileReader<'a> {
+    rest: &'a str,
+}
+
+impl<'a> UndoFileReader<'a> {
+    fn line(&mut self) -> Option<&'a str> {
+        let (line, rest) = self.rest.split_once('\n')?;
+        self.rest = rest;
+        Some(line)
+    }
+
+    fn numbers(&mut self) -> Option<Vec<usize>> {
+        self.line()?.split(' ').map(|n| n.parse().ok()).collect()
+    }
+
+    // 改行を含むかもしれない len バイトの文字列と、その後の改行を読む
+    fn bytes(&mut self, len: usize) -> Option<&'a str> {
+        let text = self.rest.get(..len)?;
+        self.rest = self.rest.get(len..)?.strip_prefix('\n')?;
+        Some(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    // "hello" → "hello world" と、そこから undo して "hello!\nbye" に分かれた履歴
+    fn history() -> (UndoTree, Buffer) {
+        let mut buffer = Buffer::from("");
+        let mut tree = UndoTree::new();
+        buffer.insert((0, 0), "hello");
+        tree.commit(&mut buffer, (0, 0));
+        buffer.insert((0, 5), " world");
+        tree.commit(&mut buffer, (0, 5));
+        tree.undo(&mut buffer);
+        buffer.insert((0, 5), "!\nbye");
+        tree.commit(&mut buffer, (0, 4));
+        (tree, buffer)
+    }
+
+    // history() を保存した undo ファイルの中身を edit で書き換えて読み込む
+    fn load_edited(name: &str, edit: impl Fn(String) -> String) -> Option<UndoTree> {
+        let (tree, buffer) = history();
+        save_and_load(name, &tree, &buffer, edit)
+    }
+
+    fn save_and_load(
+        name: &str,
+        tree: &UndoTree,
+        buffer: &Buffer,
+        edit: impl Fn(String) -> String,
+    ) -> Option<UndoTree> {
+        let dir = env::temp_dir().join(format!("kbeditor-undo-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("a.txt");
+        tree.save(&file, buffer).unwrap();
+        let data = fs::read_to_string(undo_file_path(&file)).unwrap();
+        fs::write(undo_file_path(&file), edit(data)).unwrap();
+        let loaded = UndoTree::load(&file, buffer);
+        fs::remove_dir_all(dir).unwrap();
+        loaded
+    }
+
+    // seq 番目の変更の行 (親, redo で進む子, カーソル, 時刻, 編集の数) の先頭の4つを置き換える
+    fn replace_change(data: String, seq: usize, fields: &str) -> String {
+        let mut lines: Vec<String> = data.lines().map(str::to_string).collect();
+        // ヘッダの4行の後に、変更ごとの行と編集ごとの2行が並ぶ
+        let mut i = 4;
+        for _ in 0..seq {
+            let edits: usize = lines[i].rsplit(' ').next().unwrap().parse().unwrap();
+            i += 1 + 2 * edits;
+        }
+        let rest: Vec<&str> = lines[i].split(' ').skip(4).collect();
+        lines[i] = format!("{} {}", fields, rest.join(" "));
+        lines.join("\n") + "\n"
+    }
+
+    #[test]
+    fn history_round_trips_through_undo_file() {
+        let (tree, mut buffer) = history();
+        let mut loaded = save_and_load("roundtrip", &tree, &buffer, |data| data).unwrap();
+        assert_eq!(loaded.current, tree.current);
+        assert!(!loaded.is_modified());
+        for (a, b) in tree.changes.iter().zip(&loaded.changes) {
+            assert_eq!(
+                (a.parent, a.cur_child, a.cursor),
+                (b.parent, b.cur_child, b.cursor)
+            );
+            assert_eq!(a.time, b.time);
+        }
+        assert_eq!(loaded.undo(&mut buffer), Some((0, 4)));
+        assert_eq!(buffer.to_string(), "hello");
+        loaded.undo(&mut buffer);
+        assert_eq!(buffer.to_string(), "");
+        loaded.redo(&mut buffer);
+        loaded.redo(&mut buffer);
+        assert_eq!(buffer.to_string(), "hello!\nbye");
+        loaded.earlier(1, &mut buffer);
+        assert_eq!(buffer.to_string(), "hello world");
+    }
+
+    #[test]
+    fn corrupt_undo_files_are_ignored() {
+        // 書き換えなければ読める
+        assert!(load_edited("same", |data| replace_change(data, 3, "1 - 0 4")).is_some());
+        // 途中で切れている
+        assert!(load_edited("truncated", |data| data[..data.len() - 4].to_string()).is_none());
+        // 編集の位置が今の内容に当てはまらない
+        assert!(load_edited("offset", |data| data.replace("i 5 5\n", "i 50 5\n")).is_none());
+        // 挿入した文字列が今の内容と違う
+        assert!(load_edited("text", |data| data.replace("!\nbye", "?\nbye")).is_none());
+        // redo で進む子が、その変更の子ではない
+        assert!(load_edited("child", |data| replace_change(data, 2, "1 3 0 5")).is_none());
+        // 親が自分より後の変更
+        assert!(load_edited("parent", |data| replace_change(data, 1, "2 3 0 0")).is_none());
+        // 変更前のカーソルが、その時の内容の外にある
+        assert!(load_edited("cursor", |data| replace_change(data, 3, "1 - 0 40")).is_none());
+        // 別の内容のファイルの履歴
+        let (tree, _) = history();
+        let dir = env::temp_dir().join(format!("kbeditor-undo-other-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("a.txt");
+        tree.save(&file, &Buffer::from("other")).unwrap();
+        assert!(UndoTree::load(&file, &Buffer::from("hello!\nbye")).is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn text(buffer: &Buffer) -> String {
+        buffer.lines().collect::<Vec<_>>().join("\n")
+    }
+
+    // "a" → "ab" → "abc" と変えた後、"ab" まで戻って "abX" に分かれた履歴
+    fn branched() -> (UndoTree, Buffer) {
+        let mut buffer = Buffer::from("");
+        let mut tree = UndoTree::new();
+        for (i, s) in ["a", "b", "c"].iter().enumerate() {
+            buffer.insert((0, i), s);
+            tree.commit(&mut buffer, (0, i));
+        }
+        tree.undo(&mut buffer);
+        buffer.insert((0, 2), "X");
+        tree.commit(&mut buffer, (0, 2));
+        (tree, buffer)
+    }
+
+    #[test]
+    fn undo_and_redo_follow_the_last_branch() {
+        let (mut tree, mut buffer) = branched();
+        assert_eq!(text(&buffer), "abX");
+        assert_eq!(tree.undo(&mut buffer), Some((0, 2)));
+        assert_eq!(text(&buffer), "ab");
+        tree.undo(&mut buffer);
+        tree.undo(&mut buffer);
+        assert_eq!(text(&buffer), "");
+        // 最初の状態より前には戻れない
+        assert_eq!(tree.undo(&mut buffer), None);
+        // redo は最後にいた枝 ("abX" の方) に進む
+        tree.redo(&mut buffer);
+        tree.redo(&mut buffer);
+        assert_eq!(tree.redo(&mut buffer), Some((0, 2)));
+        assert_eq!(text(&buffer), "abX");
+        assert_eq!(tree.redo(&mut buffer), None);
+        // 何も編集していなければ変更を記録しない
+        tree.commit(&mut buffer, (0, 0));
+        assert_eq!(tree.changes.len(), 5);
+    }
+
+    #[test]
+    fn earlier_and_later_walk_across_branches() {
+        let (mut tree, mut buffer) = branched();
+        // 時間順では "abc" (3) の次が "abX" (4)
+        tree.earlier(1, &mut buffer);
+        assert_eq!(text(&buffer), "abc");
+        tree.earlier(2, &mut buffer);
+        assert_eq!(text(&buffer), "a");
+        tree.later(2, &mut buffer);
+        assert_eq!(text(&buffer), "abc");
+        tree.later(10, &mut buffer);
+        assert_eq!(text(&buffer), "abX");
+        tree.earlier(10, &mut buffer);
+        assert_eq!(text(&buffer), "");
+        // 枝を移った後の redo は、移ってきた枝に進む
+        tree.later(3, &mut buffer);
+        tree.undo(&mut buffer);
+        tree.redo(&mut buffer);
+        assert_eq!(text(&buffer), "abc");
+    }
+
+    #[test]
+    fn earlier_and_later_by_time() {
+        let (mut tree, mut buffer) = branched();
+        // 変更の時刻を 1 分ずつずらす
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        for (i, change) in tree.changes.iter_mut().enumerate() {
+            change.time = start + Duration::from_secs(60 * i as u64);
+        }
+        let minute = Duration::from_secs(60);
+        tree.earlier_by(minute, &mut buffer);
+        assert_eq!(text(&buffer), "abc");
+        tree.earlier_by(minute * 2 + minute / 2, &mut buffer);
+        assert_eq!(text(&buffer), "");
+        tree.later_by(minute, &mut buffer);
+        assert_eq!(text(&buffer), "a");
+        tree.later_by(minute * 3, &mut buffer);
+        assert_eq!(text(&buffer), "abX");
+        tree.earlier_by(minute * 100, &mut buffer);
+        assert_eq!(text(&buffer), "");
+    }
+
+    #[test]
+    fn edits_until_commit_are_one_change() {
+        // インサートモードで入力した文字は抜けるときにまとめて1回分になる
+        let mut buffer = Buffer::from("");
+        let mut tree = UndoTree::new();
+        for (i, c) in ["f", "o", "o"].iter().enumerate() {
+            buffer.insert((0, i), c);
+        }
+        tree.commit(&mut buffer, (0, 0));
+        // ノーマルモードのコマンドは1回ごとに記録する
+        buffer.delete((0, 0), (0, 1));
+        tree.commit(&mut buffer, (0, 0));
+        buffer.delete((0, 0), (0, 1));
+        tree.commit(&mut buffer, (0, 0));
+        assert_eq!(text(&buffer), "o");
+        tree.undo(&mut buffer);
+        assert_eq!(text(&buffer), "oo");
+        tree.undo(&mut buffer);
+        assert_eq!(text(&buffer), "foo");
+        tree.undo(&mut buffer);
+        assert_eq!(text(&buffer), "");
+    }
+
+    #[test]
+    fn history_is_saved_and_loaded() {
+        let dir = std::env::temp_dir().join(format!("kbeditor-undofile-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("a.txt");
+        let (tree, mut buffer) = branched();
+        tree.save(&file, &buffer).unwrap();
+        // 保存したときと違う内容のファイルには使わない
+        assert!(UndoTree::load(&file, &Buffer::from("abc")).is_none());
+        let mut loaded = UndoTree::load(&file, &buffer).unwrap();
+        assert_eq!(loaded.current, tree.current);
+        loaded.undo(&mut buffer);
+        assert_eq!(text(&buffer), "ab");
+        loaded.earlier(1, &mut buffer);
+        assert_eq!(text(&buffer), "a");
+        loaded.later(2, &mut buffer);
+        assert_eq!(text(&buffer), "abc");
+        loaded.earlier(3, &mut buffer);
+        assert_eq!(text(&buffer), "");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn modified_until_back_at_the_saved_state() {
+        let (mut tree, mut buffer) = branched();
+        assert!(tree.is_modified());
+        tree.mark_saved();
+        assert!(!tree.is_modified());
+        // 戻っても、進んで保存した状態に着けば変更は無い
+        tree.undo(&mut buffer);
+        assert!(tree.is_modified());
+        tree.redo(&mut buffer);
+        assert!(!tree.is_modified());
+        // 保存した後に変更を記録したら変更あり
+        buffer.insert((0, 0), "x");
+        tree.commit(&mut buffer, (0, 0));
+        assert!(tree.is_modified());
+        assert!(!UndoTree::new().is_modified());
+    }
+}
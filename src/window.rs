@@ -0,0 +1,218 @@
+// ウィンドウの分割 (:split / :vsplit) と、端末の中での並べ方
+
+// ウィンドウの大きさの最小値 (ステータス行と、行番号の後の1列は残す)
+const MIN_HEIGHT: usize = 2;
+const MIN_WIDTH: usize = 8;
+
+// 端末の中の矩形 (x, y は左上の位置)
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+// 1つのウィンドウ (今のウィンドウの cursor と upper は Editor のフィールドの方が新しい)
+#[derive(Clone)]
+pub struct Window {
+    pub id: usize,
+    pub number: usize, // 表示しているバッファの番号
+    pub cursor: (usize, usize),
+    pub upper: usize,
+    pub leftcol: usize, // 左端に表示している表示上の列 (長い行は横にずらして表示する)
+    pub rect: Rect, // 行番号とテキストを表示する範囲 (ウィンドウが複数あればその下にステータス行がある)
+}
+
+// タブページ (ウィンドウの並べ方と、そのウィンドウ)
+pub struct TabPage {
+    pub layout: Layout,
+    pub windows: Vec<Window>,
+    pub window: usize, // 最後にいたウィンドウの id
+}
+
+impl TabPage {
+    pub fn current(&self) -> &Window {
+        self.windows.iter().find(|w| w.id == self.window).unwrap()
+    }
+}
+
+// ウィンドウの並べ方
+// Split の子は (並べ方, 大きさ) で、大きさは上下に並べるならステータス行を含む行数、
+// 左右に並べる (vertical) なら区切りの縦線を除いた列数 (0 なら均等に分ける)
+pub enum Layout {
+    Window(usize),
+    Split {
+        vertical: bool,
+        children: Vec<(Layout, usize)>,
+    },
+}
+
+impl Layout {
+    pub fn contains(&self, id: usize) -> bool {
+        match self {
+            Layout::Window(w) => *w == id,
+            Layout::Split { children, .. } => children.iter().any(|(child, _)| child.contains(id)),
+        }
+    }
+
+    // id のウィンドウを分けて、その前 (上か左) に new のウィンドウを置く
+    pub fn split(&mut self, id: usize, new: usize, vertical: bool) {
+        match self {
+            Layout::Window(w) if *w == id => {
+                *self = Layout::Split {
+                    vertical,
+                    children: vec![(Layout::Window(new), 0), (Layout::Window(id), 0)],
+                }
+            }
+            Layout::Window(_) => {}
+            Layout::Split {
+                vertical: v,
+                children,
+            } => {
+                let Some(i) = children.iter().position(|(child, _)| child.contains(id)) else {
+                    return;
+                };
+                match &mut children[i] {
+                    // 同じ向きに並べているなら、その中で半分ずつにする
+                    (Layout::Window(_), size) if *v == vertical => {
+                        let separator = usize::from(vertical);
+                        let half = size.saturating_sub(separator) / 2;
+                        let rest = size.saturating_sub(separator + half);
+                        *size = rest;
+                        children.insert(i, (Layout::Window(new), half));
+                    }
+                    (child, _) => child.split(id, new, vertical),
+                }
+            }
+        }
+    }
+
+    // id のウィンドウを除いて、空いた分を隣 (前か、無ければ後ろ) のウィンドウに渡す
+    pub fn remove(&mut self, id: usize) {
+        let Layout::Split { vertical, children } = self else {
+            return;
+        };
+        let Some(i) = children.iter().position(|(child, _)| child.contains(id)) else {
+            return;
+        };
+        if let Layout::Window(_) = children[i].0 {
+            let (_, size) = children.remove(i);
+            let neighbor = i.saturating_sub(1).min(children.len() - 1);
+            children[neighbor].1 += size + usize::from(*vertical);
+        } else {
+            children[i].0.remove(id);
+        }
+        if children.len() == 1 {
+            let (only, _) = children.pop().unwrap();
+            *self = only;
+        }
+    }
+
+    // id を含む、向きが vertical の一番内側の分割で、id の側を delta だけ大きく (負なら小さく) する
+    // 大きくするときは後ろ (足りなければ前) のウィンドウから取り、小さくするときは後ろ (無ければ前) に渡す
+    pub fn resize(&mut self, id: usize, vertical: bool, delta: isize) -> bool {
+        let Layout::Split {
+            vertical: v,
+            children,
+        } = self
+        else {
+            return false;
+        };
+        let Some(i) = children.iter().position(|(child, _)| child.contains(id)) else {
+            return false;
+        };
+        if children[i].0.resize(id, vertical, delta) {
+            return true;
+        }
+        if *v != vertical {
+            return false;
+        }
+        let min = if vertical { MIN_WIDTH } else { MIN_HEIGHT };
+        if delta < 0 {
+            let shrink = (delta.unsigned_abs()).min(children[i].1.saturating_sub(min));
+            children[i].1 -= shrink;
+            let neighbor = if i + 1 < children.len() { i + 1 } else { i - 1 };
+            children[neighbor].1 += shrink;
+            return true;
+        }
+        let mut rest = delta as usize;
+        let others = (i + 1..children.len())
+            .chain((0..i).rev())
+            .collect::<Vec<_>>();
+        for j in others {
+            let take = rest.min(children[j].1.saturating_sub(min));
+            children[j].1 -= take;
+            children[i].1 += take;
+            rest -= take;
+        }
+        true
+    }
+
+    // すべての分割を均等にする
+    pub fn equalize(&mut self) {
+        if let Layout::Split { children, .. } = self {
+            for (child, size) in children {
+                *size = 0;
+                child.equalize();
+            }
+        }
+    }
+
+    // area の中に並べたときの、ウィンドウごとの矩形 (上から、左から順に並べる)
+    // 子の大きさの合計が area に合わなければ、後ろの子から合わせる
+    pub fn arrange(&mut self, area: Rect, rects: &mut Vec<(usize, Rect)>) {
+        let (vertical, children) = match self {
+            Layout::Window(id) => {
+                rects.push((*id, area));
+                return;
+            }
+            Layout::Split { vertical, children } => (*vertical, children),
+        };
+        let total = if vertical {
+            area.width.saturating_sub(children.len() - 1)
+        } else {
+            area.height
+        };
+        fit(children, total);
+        let mut offset = 0;
+        for (child, size) in children.iter_mut() {
+            let rect = if vertical {
+                Rect {
+                    x: area.x + offset,
+                    width: *size,
+                    ..area
+                }
+            } else {
+                Rect {
+                    y: area.y + offset,
+                    height: *size,
+                    ..area
+                }
+            };
+            child.arrange(rect, rects);
+            offset += *size + usize::from(vertical);
+        }
+    }
+}
+
+// 子の大きさの合計を total にする (0 の子があれば全部を均等に分け直す)
+fn fit(children: &mut [(Layout, usize)], total: usize) {
+    let n = children.len();
+    if children.iter().any(|(_, size)| *size == 0) {
+        for (i, (_, size)) in children.iter_mut().enumerate() {
+            *size = total / n + usize::from(i < total % n);
+        }
+        return;
+    }
+    let sum: usize = children.iter().map(|(_, size)| size).sum();
+    if sum < total {
+        children[n - 1].1 += total - sum;
+    }
+    let mut over = sum.saturating_sub(total);
+    for (_, size) in children.iter_mut().rev() {
+        let take = over.min(size.saturating_sub(1));
+        *size -= take;
+        over -= take;
+    }
+}